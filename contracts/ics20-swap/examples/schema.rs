@@ -4,7 +4,8 @@ use std::fs::create_dir_all;
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
 use cw_ics20_swap::msg::{
-    ChannelResponse, ExecuteMsg, InitMsg, ListChannelsResponse, QueryMsg, TransferMsg,
+    ChannelResponse, ExecuteMsg, InitMsg, ListChannelsResponse, MigrateMsg, QueryMsg, SudoMsg,
+    TransferMsg,
 };
 
 fn main() {
@@ -15,6 +16,8 @@ fn main() {
 
     export_schema(&schema_for!(InitMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
+    export_schema(&schema_for!(SudoMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(TransferMsg), &out_dir);
     export_schema(&schema_for!(ChannelResponse), &out_dir);
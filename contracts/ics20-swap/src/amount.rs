@@ -2,16 +2,31 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use cosmwasm_std::{Coin, Uint128};
+use cw20::Cw20Coin;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Amount {
     Native(Coin),
+    Cw20(Cw20Coin),
 }
 
 impl Amount {
     pub fn from_parts(denom: String, amount: Uint128) -> Self {
-        Amount::Native(Coin { denom, amount })
+        match denom.strip_prefix("cw20:") {
+            Some(address) => Amount::Cw20(Cw20Coin {
+                address: address.into(),
+                amount,
+            }),
+            None => Amount::Native(Coin { denom, amount }),
+        }
+    }
+
+    pub fn cw20(amount: u128, addr: &str) -> Self {
+        Amount::Cw20(Cw20Coin {
+            address: addr.into(),
+            amount: Uint128::new(amount),
+        })
     }
 
     pub fn native(amount: u128, denom: &str) -> Self {
@@ -26,22 +41,29 @@ impl Amount {
     pub fn denom(&self) -> String {
         match self {
             Amount::Native(c) => c.denom.clone(),
+            Amount::Cw20(c) => get_cw20_denom(c.address.as_str()),
         }
     }
 
     pub fn amount(&self) -> Uint128 {
         match self {
             Amount::Native(c) => c.amount,
+            Amount::Cw20(c) => c.amount,
         }
     }
 
     pub fn is_empty(&self) -> bool {
         match self {
             Amount::Native(c) => c.amount.is_zero(),
+            Amount::Cw20(c) => c.amount.is_zero(),
         }
     }
 }
 
+pub fn get_cw20_denom(contract: &str) -> String {
+    format!("cw20:{contract}")
+}
+
 #[cfg(test)]
 mod test {
     use crate::amount::Amount;
@@ -54,5 +76,12 @@ mod test {
 
         assert_eq!("ucosm", res.denom());
         assert_eq!(Uint128::new(1), res.amount());
+
+        // cw20 token
+        let res = Amount::from_parts("cw20:my-token".to_string(), 1u8.into());
+
+        assert_eq!(Amount::cw20(1, "my-token"), res);
+        assert_eq!("cw20:my-token", res.denom());
+        assert_eq!(Uint128::new(1), res.amount());
     }
 }
@@ -1,20 +1,40 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, Addr, Binary, Deps, DepsMut, Env, IbcMsg, MessageInfo, Order, Response, StdResult,
+    coins, from_binary, to_binary, Addr, BankMsg, Binary, Deps, DepsMut, Empty, Env, IbcMsg,
+    MessageInfo, Order, Response, StdError, StdResult, Storage, SubMsg, Uint128, Uint64,
 };
 
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
+use cw20::{Cw20Coin, Cw20ReceiveMsg};
+use semver::Version;
 
 use crate::amount::Amount;
 use crate::error::ContractError;
+use crate::ibc::{check_gas_limit, execute_split_swap, send_amount, ICS20_VERSION};
 use crate::ibc_msg::Ics20Packet;
+use crate::migrations::v1;
 use crate::msg::{
-    ChannelResponse, ConfigResponse, ExecuteMsg, InitMsg, ListChannelsResponse, ListLockupResponse,
-    LockupResponse, QueryMsg, TransferMsg,
+    AllowMsg, AllowPoolMsg, AllowedPoolResponse, AllowedResponse, ChannelResponse,
+    ChannelStatusResponse, ConfigResponse, ConnectionMsg, ExecuteMsg, FeesResponse,
+    ForwardResponse, IbcLifecycleComplete, InitMsg, ListAllowedPoolsResponse, ListAllowedResponse,
+    ListChannelStatusResponse, ListChannelsResponse, ListConnectionsResponse, ListForwardsResponse,
+    ListLockupResponse, ListPacketsResponse, ListPendingOpsResponse, ListPendingRefundsResponse,
+    ListRateLimitsResponse, LockupOwnerResponse, LockupResponse, MigrateMsg, PacketResponse,
+    PendingOpResponse, PendingRefundResponse, QueryMsg, RateLimitMsg, RateLimitResponse, SudoMsg,
+    TransferMsg, UserBalancesResponse,
 };
-use crate::state::{increase_channel_balance, Config, CHANNEL_INFO, CHANNEL_STATE, CONFIG, LOCKUP};
-use cw_utils::one_coin;
+use crate::state::{
+    balance_channel, consume_inflow, ensure_channel_open, increase_channel_balance,
+    increase_user_balance, load_channel_status, move_channel_entries, reduce_collected_fee,
+    AllowInfo, AllowPoolInfo, ChannelStatus, Config, ForwardRecord, RateLimit, ADMIN,
+    ALLOWED_CONNECTIONS, ALLOW_LIST, CHANNEL_ACTION_PORT, CHANNEL_INFO, CHANNEL_MAX_SLIPPAGE,
+    CHANNEL_STATE, CHANNEL_STATUS, COLLECTED_FEES, CONFIG, DEFAULT_HISTORY_LIMIT, FEES, FORWARDS,
+    FORWARD_PACKETS, LOCKUP, LOCKUP_OWNER, PACKET_HISTORY, PENDING_OPS, PENDING_REFUNDS,
+    POOL_ALLOW_LIST, RATE_LIMITS, USER_BALANCE,
+};
+use cw_storage_plus::{Bound, PrefixBound};
+use cw_utils::{maybe_addr, nonpayable, one_coin};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:cw-ics20-swap";
@@ -22,7 +42,7 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
-    deps: DepsMut,
+    mut deps: DepsMut,
     _env: Env,
     _info: MessageInfo,
     msg: InitMsg,
@@ -31,9 +51,28 @@ pub fn instantiate(
     let cfg = Config {
         default_timeout: msg.default_timeout,
         lockup_id: msg.lockup_id,
+        history_limit: DEFAULT_HISTORY_LIMIT,
     };
     CONFIG.save(deps.storage, &cfg)?;
 
+    let admin = deps.api.addr_validate(&msg.admin)?;
+    ADMIN.set(deps.branch(), Some(admin))?;
+
+    // add all allows
+    for allowed in msg.allowlist {
+        add_allow_pool(deps.storage, allowed)?;
+    }
+    for allowed in msg.cw20_allowlist {
+        add_allow_token(deps.branch(), allowed)?;
+    }
+    for connection in msg.connections {
+        let key = (
+            connection.connection_id.as_str(),
+            connection.counterparty_port_id.as_str(),
+        );
+        ALLOWED_CONNECTIONS.save(deps.storage, key, &Empty {})?;
+    }
+
     Ok(Response::default())
 }
 
@@ -45,214 +84,1365 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
+        ExecuteMsg::Receive(msg) => execute_receive(deps, env, info, msg),
+        ExecuteMsg::ClaimRefund {} => execute_claim_refund(deps, info),
         ExecuteMsg::Transfer(msg) => {
             let coin = one_coin(&info)?;
             execute_transfer(deps, env, msg, Amount::Native(coin), info.sender)
         }
+        ExecuteMsg::UpdateConfig {
+            default_timeout,
+            lockup_id,
+            history_limit,
+        } => execute_update_config(deps, info, default_timeout, lockup_id, history_limit),
+        ExecuteMsg::UpdateAdmin { admin } => {
+            let admin = deps.api.addr_validate(&admin)?;
+            Ok(ADMIN.execute_update_admin(deps, info, Some(admin))?)
+        }
+        ExecuteMsg::Allow(allow) => execute_allow(deps, info, allow),
+        ExecuteMsg::ApproveConnection(connection) => {
+            execute_approve_connection(deps, info, connection)
+        }
+        ExecuteMsg::RevokeConnection(connection) => {
+            execute_revoke_connection(deps, info, connection)
+        }
+        ExecuteMsg::AllowPool(allow) => execute_allow_pool(deps, info, allow),
+        ExecuteMsg::DisallowPool { pool_id } => execute_disallow_pool(deps, info, pool_id),
+        ExecuteMsg::SetChannelMaxSlippage {
+            channel,
+            max_slippage_bps,
+        } => execute_set_channel_max_slippage(deps, info, channel, max_slippage_bps),
+        ExecuteMsg::SetChannelActionPort { channel, port_id } => {
+            execute_set_channel_action_port(deps, info, channel, port_id)
+        }
+        ExecuteMsg::UpdateFees {
+            swap_bps,
+            join_pool_bps,
+            exit_pool_bps,
+            lock_bps,
+        } => execute_update_fees(deps, info, swap_bps, join_pool_bps, exit_pool_bps, lock_bps),
+        ExecuteMsg::WithdrawFees {
+            denom,
+            amount,
+            recipient,
+        } => execute_withdraw_fees(deps, info, denom, amount, recipient),
+        ExecuteMsg::AddRateLimit(limit) => execute_add_rate_limit(deps, info, limit),
+        ExecuteMsg::ResetRateLimit { channel, denom } => {
+            execute_reset_rate_limit(deps, info, channel, denom)
+        }
+        ExecuteMsg::RemoveRateLimit { channel, denom } => {
+            execute_remove_rate_limit(deps, info, channel, denom)
+        }
+        ExecuteMsg::MigrateChannel {
+            channel,
+            new_channel,
+            limit,
+        } => execute_migrate_channel(deps, info, channel, new_channel, limit),
+        ExecuteMsg::MigrateState { limit } => execute_migrate_state(deps, info, limit),
+        ExecuteMsg::ResolveForward { id, refund } => {
+            execute_resolve_forward(deps, env, info, id, refund)
+        }
+        ExecuteMsg::SplitSwap {
+            op_id,
+            token_in,
+            split,
+        } => execute_split_swap(deps, env, info, op_id, token_in, split),
     }
 }
 
-pub fn execute_transfer(
+/// The admin can allow new cw20 contracts, or increase the gas limit on existing contracts.
+/// It cannot block or reduce the limit to avoid forcible sticking tokens in the channel.
+pub fn execute_allow(
     deps: DepsMut,
-    env: Env,
-    msg: TransferMsg,
-    amount: Amount,
-    sender: Addr,
+    info: MessageInfo,
+    allow: AllowMsg,
 ) -> Result<Response, ContractError> {
-    if amount.is_empty() {
-        return Err(ContractError::NoFunds {});
-    }
-    // ensure the requested channel is registered
-    if !CHANNEL_INFO.has(deps.storage, &msg.channel) {
-        return Err(ContractError::NoSuchChannel { id: msg.channel });
-    }
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-    // delta from user is in seconds
-    let timeout_delta = match msg.timeout {
-        Some(t) => t,
-        None => CONFIG.load(deps.storage)?.default_timeout,
+    add_allow_token(deps, allow.clone())?;
+    let gas = if let Some(gas) = allow.gas_limit {
+        gas.to_string()
+    } else {
+        "None".to_string()
     };
-    // timeout is in nanoseconds
-    let timeout = env.block.time.plus_seconds(timeout_delta);
 
-    // build ics20 packet
-    let packet = Ics20Packet::new(
-        amount.amount(),
-        amount.denom(),
-        sender.as_ref(),
-        &msg.remote_address,
+    let res = Response::new()
+        .add_attribute("action", "allow")
+        .add_attribute("contract", allow.contract)
+        .add_attribute("gas_limit", gas);
+    Ok(res)
+}
+
+fn add_allow_token(deps: DepsMut, allow: AllowMsg) -> Result<(), ContractError> {
+    let contract = deps.api.addr_validate(&allow.contract)?;
+    let set = AllowInfo {
+        gas_limit: allow.gas_limit,
+    };
+    ALLOW_LIST.update(deps.storage, &contract, |old| {
+        if let Some(old) = old {
+            // we must ensure it increases the limit
+            match (old.gas_limit, set.gas_limit) {
+                (None, Some(_)) => return Err(ContractError::CannotLowerGas),
+                (Some(old), Some(new)) if new < old => return Err(ContractError::CannotLowerGas),
+                _ => {}
+            };
+        }
+        Ok(set)
+    })?;
+
+    Ok(())
+}
+
+/// The admin can allow the counterparty port of a connection to open channels.
+pub fn execute_approve_connection(
+    deps: DepsMut,
+    info: MessageInfo,
+    connection: ConnectionMsg,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let key = (
+        connection.connection_id.as_str(),
+        connection.counterparty_port_id.as_str(),
     );
+    ALLOWED_CONNECTIONS.save(deps.storage, key, &Empty {})?;
 
-    increase_channel_balance(deps.storage, &msg.channel, &amount.denom(), amount.amount())?;
+    let res = Response::new()
+        .add_attribute("action", "approve_connection")
+        .add_attribute("connection_id", connection.connection_id)
+        .add_attribute("counterparty_port_id", connection.counterparty_port_id);
+    Ok(res)
+}
 
-    // prepare ibc message
-    let msg = IbcMsg::SendPacket {
-        channel_id: msg.channel,
-        data: to_binary(&packet)?,
-        timeout: timeout.into(),
-    };
+/// Channels already open on the connection keep working, only new handshakes are rejected.
+pub fn execute_revoke_connection(
+    deps: DepsMut,
+    info: MessageInfo,
+    connection: ConnectionMsg,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let key = (
+        connection.connection_id.as_str(),
+        connection.counterparty_port_id.as_str(),
+    );
+    ALLOWED_CONNECTIONS.remove(deps.storage, key);
 
-    // send response
     let res = Response::new()
-        .add_message(msg)
-        .add_attribute("action", "transfer")
-        .add_attribute("sender", &packet.sender)
-        .add_attribute("receiver", &packet.receiver)
-        .add_attribute("denom", &packet.denom)
-        .add_attribute("amount", packet.amount.to_string());
+        .add_attribute("action", "revoke_connection")
+        .add_attribute("connection_id", connection.connection_id)
+        .add_attribute("counterparty_port_id", connection.counterparty_port_id);
+    Ok(res)
+}
+
+/// The admin can allow new pools, or change the max input amount and slippage on existing pools.
+pub fn execute_allow_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    allow: AllowPoolMsg,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    add_allow_pool(deps.storage, allow.clone())?;
+    let max_amount = if let Some(max) = allow.max_amount {
+        max.to_string()
+    } else {
+        "None".to_string()
+    };
+    let max_slippage = if let Some(max) = allow.max_slippage_bps {
+        max.to_string()
+    } else {
+        "None".to_string()
+    };
 
+    let res = Response::new()
+        .add_attribute("action", "allow_pool")
+        .add_attribute("pool_id", allow.pool_id)
+        .add_attribute("max_amount", max_amount)
+        .add_attribute("max_slippage_bps", max_slippage);
     Ok(res)
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::ListChannels {} => to_binary(&query_list(deps)?),
-        QueryMsg::Channel { id } => to_binary(&query_channel(deps, id)?),
-        QueryMsg::Config {} => to_binary(&query_config(deps)?),
-        QueryMsg::Lockup { channel, owner } => to_binary(&query_lockup(deps, channel, owner)?),
-        QueryMsg::AllLockups { channel } => to_binary(&query_all_lockup(deps, channel)?),
+fn add_allow_pool(storage: &mut dyn Storage, allow: AllowPoolMsg) -> Result<(), ContractError> {
+    if let Some(max_slippage) = allow.max_slippage_bps {
+        validate_max_slippage(max_slippage)?;
     }
+    let info = AllowPoolInfo {
+        max_amount: allow.max_amount,
+        max_slippage_bps: allow.max_slippage_bps,
+    };
+    POOL_ALLOW_LIST.save(storage, allow.pool_id.u64(), &info)?;
+    Ok(())
 }
 
-fn query_list(deps: Deps) -> StdResult<ListChannelsResponse> {
-    let channels = CHANNEL_INFO
-        .range_raw(deps.storage, None, None, Order::Ascending)
-        .map(|r| r.map(|(_, v)| v))
-        .collect::<StdResult<_>>()?;
-    Ok(ListChannelsResponse { channels })
+fn validate_max_slippage(max_slippage_bps: u64) -> Result<(), ContractError> {
+    if max_slippage_bps > 10_000 {
+        return Err(ContractError::InvalidMaxSlippage {});
+    }
+    Ok(())
 }
 
-// make public for ibc tests
-pub fn query_channel(deps: Deps, id: String) -> StdResult<ChannelResponse> {
-    let info = CHANNEL_INFO.load(deps.storage, &id)?;
-    // this returns Vec<(outstanding, total)>
-    let state = CHANNEL_STATE
-        .prefix(&id)
-        .range(deps.storage, None, None, Order::Ascending)
-        .map(|r| {
-            r.map(|(denom, v)| {
-                let outstanding = Amount::from_parts(denom.clone(), v.outstanding);
-                let total = Amount::from_parts(denom, v.total_sent);
-                (outstanding, total)
-            })
-        })
-        .collect::<StdResult<Vec<_>>>()?;
-    // we want (Vec<outstanding>, Vec<total>)
-    let (balances, total_sent) = state.into_iter().unzip();
+/// Pending actions on the pool are not affected, only new packets are rejected.
+pub fn execute_disallow_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    pool_id: Uint64,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-    Ok(ChannelResponse {
-        info,
-        balances,
-        total_sent,
-    })
+    POOL_ALLOW_LIST.remove(deps.storage, pool_id.u64());
+
+    let res = Response::new()
+        .add_attribute("action", "disallow_pool")
+        .add_attribute("pool_id", pool_id);
+    Ok(res)
 }
 
-fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
-    let cfg = CONFIG.load(deps.storage)?;
-    let res = ConfigResponse {
-        default_timeout: cfg.default_timeout,
+/// Swaps received on the channel are rejected if their price impact is above the limit.
+/// The pool limit still applies, the lowest one is used.
+pub fn execute_set_channel_max_slippage(
+    deps: DepsMut,
+    info: MessageInfo,
+    channel: String,
+    max_slippage_bps: Option<u64>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    if !CHANNEL_INFO.has(deps.storage, &channel) {
+        return Err(ContractError::NoSuchChannel { id: channel });
+    }
+    let max_slippage = match max_slippage_bps {
+        Some(max) => {
+            validate_max_slippage(max)?;
+            CHANNEL_MAX_SLIPPAGE.save(deps.storage, &channel, &max)?;
+            max.to_string()
+        }
+        None => {
+            CHANNEL_MAX_SLIPPAGE.remove(deps.storage, &channel);
+            "None".to_string()
+        }
     };
+
+    let res = Response::new()
+        .add_attribute("action", "set_channel_max_slippage")
+        .add_attribute("channel", channel)
+        .add_attribute("max_slippage_bps", max_slippage);
     Ok(res)
 }
 
-fn query_lockup(deps: Deps, channel_id: String, owner: String) -> StdResult<LockupResponse> {
-    let lockup_key = (channel_id.as_str(), owner.as_str());
-    let lockup_address = LOCKUP.load(deps.storage, lockup_key).unwrap_or_default();
-    let res = LockupResponse {
-        owner,
-        address: lockup_address,
+/// The admin pairs a transfer channel with the remote port trusted to send its actions, the
+/// action packets set the remote sender without a transfer to prove it.
+pub fn execute_set_channel_action_port(
+    deps: DepsMut,
+    info: MessageInfo,
+    channel: String,
+    port_id: Option<String>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let transfer = CHANNEL_INFO
+        .may_load(deps.storage, &channel)?
+        .ok_or_else(|| ContractError::NoSuchChannel {
+            id: channel.clone(),
+        })?;
+    if transfer.version != ICS20_VERSION {
+        return Err(ContractError::NotTransferChannel { channel });
+    }
+    let action_port = match port_id {
+        Some(port_id) => {
+            CHANNEL_ACTION_PORT.save(deps.storage, &channel, &port_id)?;
+            port_id
+        }
+        None => {
+            CHANNEL_ACTION_PORT.remove(deps.storage, &channel);
+            "None".to_string()
+        }
     };
+
+    let res = Response::new()
+        .add_attribute("action", "set_channel_action_port")
+        .add_attribute("channel", channel)
+        .add_attribute("port_id", action_port);
     Ok(res)
 }
 
-fn query_all_lockup(deps: Deps, channel_id: String) -> StdResult<ListLockupResponse> {
-    let lockups = LOCKUP
-        .prefix(channel_id.as_str())
-        .range(deps.storage, None, None, Order::Ascending)
-        .map(|r| r.map(|(owner, address)| LockupResponse { owner, address }))
-        .collect::<StdResult<Vec<_>>>()?;
+/// The admin can change the fee of each action type.
+/// Pending actions keep the fee taken when they were received.
+pub fn execute_update_fees(
+    deps: DepsMut,
+    info: MessageInfo,
+    swap_bps: Option<u64>,
+    join_pool_bps: Option<u64>,
+    exit_pool_bps: Option<u64>,
+    lock_bps: Option<u64>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-    Ok(ListLockupResponse { lockups })
-}
+    let mut fees = FEES.may_load(deps.storage)?.unwrap_or_default();
+    if let Some(swap_bps) = swap_bps {
+        fees.swap_bps = swap_bps;
+    }
+    if let Some(join_pool_bps) = join_pool_bps {
+        fees.join_pool_bps = join_pool_bps;
+    }
+    if let Some(exit_pool_bps) = exit_pool_bps {
+        fees.exit_pool_bps = exit_pool_bps;
+    }
+    if let Some(lock_bps) = lock_bps {
+        fees.lock_bps = lock_bps;
+    }
+    let all_fees = [
+        fees.swap_bps,
+        fees.join_pool_bps,
+        fees.exit_pool_bps,
+        fees.lock_bps,
+    ];
+    if all_fees.iter().any(|fee| *fee > 10_000) {
+        return Err(ContractError::InvalidFee {});
+    }
+    FEES.save(deps.storage, &fees)?;
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::test_helpers::*;
+    let res = Response::new()
+        .add_attribute("action", "update_fees")
+        .add_attribute("swap_bps", fees.swap_bps.to_string())
+        .add_attribute("join_pool_bps", fees.join_pool_bps.to_string())
+        .add_attribute("exit_pool_bps", fees.exit_pool_bps.to_string())
+        .add_attribute("lock_bps", fees.lock_bps.to_string());
+    Ok(res)
+}
 
-    use cosmwasm_std::testing::{mock_env, mock_info};
-    use cosmwasm_std::{coin, coins, from_binary, CosmosMsg, IbcMsg, StdError, Uint128};
+/// The admin can send the collected fees to any address.
+pub fn execute_withdraw_fees(
+    deps: DepsMut,
+    info: MessageInfo,
+    denom: String,
+    amount: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-    use cw_utils::PaymentError;
+    let amount = match amount {
+        Some(amount) => amount,
+        None => COLLECTED_FEES
+            .may_load(deps.storage, &denom)?
+            .unwrap_or_default(),
+    };
+    if amount.is_zero() {
+        return Err(ContractError::NoFunds {});
+    }
+    reduce_collected_fee(deps.storage, &denom, amount)?;
 
-    #[test]
-    fn setup_and_query() {
-        let deps = setup(&["channel-3", "channel-7"]);
+    let recipient = match recipient {
+        Some(recipient) => deps.api.addr_validate(&recipient)?,
+        None => info.sender,
+    };
+    let msg = BankMsg::Send {
+        to_address: recipient.to_string(),
+        amount: coins(amount.u128(), &denom),
+    };
 
-        let raw_list = query(deps.as_ref(), mock_env(), QueryMsg::ListChannels {}).unwrap();
-        let list_res: ListChannelsResponse = from_binary(&raw_list).unwrap();
-        assert_eq!(2, list_res.channels.len());
-        assert_eq!(mock_channel_info("channel-3"), list_res.channels[0]);
-        assert_eq!(mock_channel_info("channel-7"), list_res.channels[1]);
+    let res = Response::new()
+        .add_message(msg)
+        .add_attribute("action", "withdraw_fees")
+        .add_attribute("recipient", recipient)
+        .add_attribute("denom", denom)
+        .add_attribute("amount", amount);
+    Ok(res)
+}
 
-        let raw_channel = query(
-            deps.as_ref(),
-            mock_env(),
-            QueryMsg::Channel {
-                id: "channel-3".to_string(),
-            },
-        )
-        .unwrap();
-        let chan_res: ChannelResponse = from_binary(&raw_channel).unwrap();
-        assert_eq!(chan_res.info, mock_channel_info("channel-3"));
-        assert_eq!(0, chan_res.total_sent.len());
-        assert_eq!(0, chan_res.balances.len());
+/// The admin can limit the amount of a denom received and sent over a channel in each window.
+pub fn execute_add_rate_limit(
+    deps: DepsMut,
+    info: MessageInfo,
+    msg: RateLimitMsg,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-        let err = query(
-            deps.as_ref(),
-            mock_env(),
-            QueryMsg::Channel {
-                id: "channel-10".to_string(),
-            },
-        )
-        .unwrap_err();
-        assert_eq!(
-            err,
-            StdError::not_found("cw_ics20_swap::state::ChannelInfo")
-        );
+    if !CHANNEL_INFO.has(deps.storage, &msg.channel) {
+        return Err(ContractError::NoSuchChannel { id: msg.channel });
     }
+    if msg.period == 0 {
+        return Err(ContractError::InvalidRateLimitPeriod {});
+    }
+    let limit = RateLimit {
+        period: msg.period,
+        max_outflow: msg.max_outflow,
+        max_inflow: msg.max_inflow,
+        buckets: vec![],
+    };
+    RATE_LIMITS.save(deps.storage, (&msg.channel, &msg.denom), &limit)?;
 
-    #[test]
-    fn proper_checks_on_execute_native() {
-        let send_channel = "channel-5";
-        let mut deps = setup(&[send_channel]);
+    let res = Response::new()
+        .add_attribute("action", "add_rate_limit")
+        .add_attribute("channel", msg.channel)
+        .add_attribute("denom", msg.denom)
+        .add_attribute("period", msg.period.to_string());
+    Ok(res)
+}
 
-        let mut transfer = TransferMsg {
-            channel: send_channel.to_string(),
-            remote_address: "foreign-address".to_string(),
-            timeout: None,
-        };
+/// Clears the quota used in the window.
+pub fn execute_reset_rate_limit(
+    deps: DepsMut,
+    info: MessageInfo,
+    channel: String,
+    denom: String,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
-        // works with proper funds
-        let msg = ExecuteMsg::Transfer(transfer.clone());
-        let info = mock_info("foobar", &coins(1234567, "ucosm"));
-        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
-        assert_eq!(res.messages[0].gas_limit, None);
-        assert_eq!(1, res.messages.len());
-        if let CosmosMsg::Ibc(IbcMsg::SendPacket {
-            channel_id,
-            data,
-            timeout,
-        }) = &res.messages[0].msg
-        {
-            let expected_timeout = mock_env().block.time.plus_seconds(DEFAULT_TIMEOUT);
-            assert_eq!(timeout, &expected_timeout.into());
-            assert_eq!(channel_id.as_str(), send_channel);
-            let msg: Ics20Packet = from_binary(data).unwrap();
-            assert_eq!(msg.amount, Uint128::new(1234567));
-            assert_eq!(msg.denom.as_str(), "ucosm");
-            assert_eq!(msg.sender.as_str(), "foobar");
+    RATE_LIMITS.update(deps.storage, (&channel, &denom), |limit| -> StdResult<_> {
+        let mut limit = limit.ok_or_else(|| StdError::not_found("RateLimit"))?;
+        limit.buckets.clear();
+        Ok(limit)
+    })?;
+
+    let res = Response::new()
+        .add_attribute("action", "reset_rate_limit")
+        .add_attribute("channel", channel)
+        .add_attribute("denom", denom);
+    Ok(res)
+}
+
+pub fn execute_remove_rate_limit(
+    deps: DepsMut,
+    info: MessageInfo,
+    channel: String,
+    denom: String,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    RATE_LIMITS.remove(deps.storage, (&channel, &denom));
+
+    let res = Response::new()
+        .add_attribute("action", "remove_rate_limit")
+        .add_attribute("channel", channel)
+        .add_attribute("denom", denom);
+    Ok(res)
+}
+
+/// The admin moves the balances, user balances and lockups of a closed channel to an open
+/// transfer channel, so the remote senders can use them again. Large channels are moved over
+/// several calls, the replacement can't change once started.
+pub fn execute_migrate_channel(
+    deps: DepsMut,
+    info: MessageInfo,
+    channel: String,
+    new_channel: String,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let channel_info = CHANNEL_INFO
+        .may_load(deps.storage, &channel)?
+        .ok_or_else(|| ContractError::NoSuchChannel {
+            id: channel.clone(),
+        })?;
+    if channel_info.version != ICS20_VERSION {
+        return Err(ContractError::NotTransferChannel { channel });
+    }
+    match load_channel_status(deps.storage, &channel)? {
+        ChannelStatus::Open => return Err(ContractError::ChannelNotClosed { id: channel }),
+        ChannelStatus::Closed => {}
+        ChannelStatus::Migrating { channel: to } if to == new_channel => {}
+        ChannelStatus::Migrating { channel: to } | ChannelStatus::Migrated { channel: to } => {
+            return Err(ContractError::ChannelMigrated {
+                id: channel,
+                channel: to,
+            })
+        }
+    }
+
+    let new_info = CHANNEL_INFO
+        .may_load(deps.storage, &new_channel)?
+        .ok_or_else(|| ContractError::NoSuchChannel {
+            id: new_channel.clone(),
+        })?;
+    if new_info.version != ICS20_VERSION {
+        return Err(ContractError::NotTransferChannel {
+            channel: new_channel,
+        });
+    }
+    ensure_channel_open(deps.storage, &new_channel)?;
+    // the entries only move to a channel with the same chain on the other side
+    if new_info.connection_id != channel_info.connection_id
+        || new_info.counterparty_endpoint.port_id != channel_info.counterparty_endpoint.port_id
+    {
+        return Err(ContractError::ChannelMismatch {
+            id: channel,
+            channel: new_channel,
+        });
+    }
+
+    let limit = limit
+        .unwrap_or(DEFAULT_MIGRATE_LIMIT)
+        .min(MAX_MIGRATE_LIMIT) as usize;
+    let moved = move_channel_entries(deps.storage, &channel, &new_channel, limit)?;
+    let status = if moved < limit {
+        ChannelStatus::Migrated {
+            channel: new_channel.clone(),
+        }
+    } else {
+        ChannelStatus::Migrating {
+            channel: new_channel.clone(),
+        }
+    };
+    CHANNEL_STATUS.save(deps.storage, &channel, &status)?;
+
+    let status = match status {
+        ChannelStatus::Migrated { .. } => "migrated",
+        _ => "migrating",
+    };
+    let res = Response::new()
+        .add_attribute("action", "migrate_channel")
+        .add_attribute("channel", channel)
+        .add_attribute("new_channel", new_channel)
+        .add_attribute("moved", moved.to_string())
+        .add_attribute("status", status);
+    Ok(res)
+}
+
+pub fn execute_migrate_state(
+    deps: DepsMut,
+    info: MessageInfo,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let limit = limit
+        .unwrap_or(DEFAULT_MIGRATE_LIMIT)
+        .clamp(1, MAX_MIGRATE_LIMIT) as usize;
+    let (migrated, done) = v1::migrate_entries(deps.storage, limit)?;
+
+    let res = Response::new()
+        .add_attribute("action", "migrate_state")
+        .add_attribute("migrated", migrated.to_string())
+        .add_attribute("status", migrate_status(done));
+    Ok(res)
+}
+
+fn migrate_status(done: bool) -> &'static str {
+    if done {
+        "migrated"
+    } else {
+        "migrating"
+    }
+}
+
+/// The forwards are resolved by their ibc-hooks callback, the admin only resolves the ones whose
+/// callback never came. A refund can only be credited once the transfer timed out, before that
+/// the packet may still be delivered.
+pub fn execute_resolve_forward(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    id: u64,
+    refund: bool,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let forward = FORWARDS
+        .may_load(deps.storage, id)?
+        .ok_or(ContractError::NoSuchForward { id })?;
+    if refund && env.block.time < forward.timeout {
+        return Err(ContractError::ForwardNotExpired { id });
+    }
+    remove_forward(deps.storage, id, &forward);
+
+    let res = Response::new()
+        .add_attribute("action", "resolve_forward")
+        .add_attribute("id", id.to_string())
+        .add_attribute("refund", refund.to_string());
+    if refund {
+        return refund_forward(deps.storage, forward, res);
+    }
+    Ok(res)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, _env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+            channel,
+            sequence,
+            success,
+            ..
+        }) => sudo_forward_result(deps, channel, sequence, success),
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout { channel, sequence }) => {
+            sudo_forward_result(deps, channel, sequence, false)
+        }
+    }
+}
+
+/// The transfer module refunds the contract when a forward fails or times out, the output is
+/// credited back to the remote sender.
+pub fn sudo_forward_result(
+    deps: DepsMut,
+    channel: String,
+    sequence: u64,
+    delivered: bool,
+) -> Result<Response, ContractError> {
+    // the forwards resolved by the admin are already removed
+    let id = match FORWARD_PACKETS.may_load(deps.storage, (&channel, sequence))? {
+        Some(id) => id,
+        None => return Ok(Response::new()),
+    };
+    let forward = FORWARDS.load(deps.storage, id)?;
+    remove_forward(deps.storage, id, &forward);
+
+    let res = Response::new()
+        .add_attribute("action", "forward_result")
+        .add_attribute("id", id.to_string())
+        .add_attribute("delivered", delivered.to_string());
+    if !delivered {
+        return refund_forward(deps.storage, forward, res);
+    }
+    Ok(res)
+}
+
+fn remove_forward(storage: &mut dyn Storage, id: u64, forward: &ForwardRecord) {
+    FORWARDS.remove(storage, id);
+    FORWARD_PACKETS.remove(storage, (&forward.forward_channel, forward.sequence));
+}
+
+fn refund_forward(
+    storage: &mut dyn Storage,
+    forward: ForwardRecord,
+    res: Response,
+) -> Result<Response, ContractError> {
+    let channel = balance_channel(storage, &forward.channel)?;
+    let amount = forward.amount;
+    increase_channel_balance(storage, &channel, &amount.denom, amount.amount)?;
+    increase_user_balance(
+        storage,
+        &channel,
+        &forward.sender,
+        &amount.denom,
+        amount.amount,
+    )?;
+    Ok(res
+        .add_attribute("channel", channel)
+        .add_attribute("receiver", forward.sender)
+        .add_attribute("amount", amount.to_string()))
+}
+
+/// The admin can change the default timeout of new packets and the lockup code used for new accounts.
+/// Existing lockup accounts are not migrated.
+pub fn execute_update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    default_timeout: Option<u64>,
+    lockup_id: Option<u64>,
+    history_limit: Option<u64>,
+) -> Result<Response, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> StdResult<_> {
+        if let Some(default_timeout) = default_timeout {
+            cfg.default_timeout = default_timeout;
+        }
+        if let Some(lockup_id) = lockup_id {
+            cfg.lockup_id = lockup_id;
+        }
+        if let Some(history_limit) = history_limit {
+            cfg.history_limit = history_limit;
+        }
+        Ok(cfg)
+    })?;
+
+    let res = Response::new()
+        .add_attribute("action", "update_config")
+        .add_attribute("default_timeout", cfg.default_timeout.to_string())
+        .add_attribute("lockup_id", cfg.lockup_id.to_string())
+        .add_attribute("history_limit", cfg.history_limit.to_string());
+    Ok(res)
+}
+
+/// The refunds are removed before sending them, they are kept if the send fails.
+pub fn execute_claim_refund(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    nonpayable(&info)?;
+
+    let recipient = info.sender.as_str();
+    let refunds = PENDING_REFUNDS
+        .prefix(recipient)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    if refunds.is_empty() {
+        return Err(ContractError::NoRefund {});
+    }
+
+    let mut res = Response::new()
+        .add_attribute("action", "claim_refund")
+        .add_attribute("recipient", recipient);
+    for (denom, amount) in refunds {
+        PENDING_REFUNDS.remove(deps.storage, (recipient, &denom));
+        let to_send = Amount::from_parts(denom, amount);
+        let gas_limit = check_gas_limit(deps.as_ref(), &to_send)?;
+        let mut submsg = SubMsg::new(send_amount(to_send.clone(), recipient.to_string()));
+        submsg.gas_limit = gas_limit;
+        res = res
+            .add_submessage(submsg)
+            .add_attribute("refund", format!("{}{}", amount, to_send.denom()));
+    }
+    Ok(res)
+}
+
+pub fn execute_receive(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    wrapper: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    nonpayable(&info)?;
+
+    let msg: TransferMsg = from_binary(&wrapper.msg)?;
+    let amount = Amount::Cw20(Cw20Coin {
+        address: info.sender.to_string(),
+        amount: wrapper.amount,
+    });
+    let api = deps.api;
+    execute_transfer(deps, env, msg, amount, api.addr_validate(&wrapper.sender)?)
+}
+
+pub fn execute_transfer(
+    deps: DepsMut,
+    env: Env,
+    msg: TransferMsg,
+    amount: Amount,
+    sender: Addr,
+) -> Result<Response, ContractError> {
+    if amount.is_empty() {
+        return Err(ContractError::NoFunds {});
+    }
+    // ensure the requested channel is registered
+    let channel = CHANNEL_INFO
+        .may_load(deps.storage, &msg.channel)?
+        .ok_or_else(|| ContractError::NoSuchChannel {
+            id: msg.channel.clone(),
+        })?;
+    if channel.version != ICS20_VERSION {
+        return Err(ContractError::NotTransferChannel {
+            channel: msg.channel,
+        });
+    }
+    ensure_channel_open(deps.storage, &msg.channel)?;
+    // if cw20 token, ensure it is whitelisted
+    if let Amount::Cw20(coin) = &amount {
+        let addr = deps.api.addr_validate(&coin.address)?;
+        ALLOW_LIST
+            .may_load(deps.storage, &addr)?
+            .ok_or(ContractError::NotOnAllowList)?;
+    }
+
+    // delta from user is in seconds
+    let timeout_delta = match msg.timeout {
+        Some(t) => t,
+        None => CONFIG.load(deps.storage)?.default_timeout,
+    };
+    // timeout is in nanoseconds
+    let timeout = env.block.time.plus_seconds(timeout_delta);
+
+    // build ics20 packet
+    let packet = Ics20Packet::new(
+        amount.amount(),
+        amount.denom(),
+        sender.as_ref(),
+        &msg.remote_address,
+    );
+
+    consume_inflow(
+        deps.storage,
+        env.block.time,
+        &msg.channel,
+        &amount.denom(),
+        amount.amount(),
+    )?;
+    increase_channel_balance(deps.storage, &msg.channel, &amount.denom(), amount.amount())?;
+
+    // prepare ibc message
+    let msg = IbcMsg::SendPacket {
+        channel_id: msg.channel,
+        data: to_binary(&packet)?,
+        timeout: timeout.into(),
+    };
+
+    // send response
+    let res = Response::new()
+        .add_message(msg)
+        .add_attribute("action", "transfer")
+        .add_attribute("sender", &packet.sender)
+        .add_attribute("receiver", &packet.receiver)
+        .add_attribute("denom", &packet.denom)
+        .add_attribute("amount", packet.amount.to_string());
+
+    Ok(res)
+}
+
+const MIGRATE_MIN_VERSION: &str = "0.1.0";
+const MIGRATE_VERSION_2: &str = "0.2.0";
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let version: Version = CONTRACT_VERSION.parse()?;
+    let stored = get_contract_version(deps.storage)?;
+    let storage_version: Version = stored.version.parse()?;
+
+    // ensure this is the same contract
+    if CONTRACT_NAME != stored.contract {
+        return Err(ContractError::CannotMigrate {
+            previous_contract: stored.contract,
+        });
+    }
+    // don't allow a downgrade or a version we never supported
+    if storage_version > version || storage_version < MIGRATE_MIN_VERSION.parse()? {
+        return Err(ContractError::CannotMigrateVersion {
+            previous_version: stored.version,
+        });
+    }
+
+    // run the state migrations of each version we skipped, the entries that don't fit in the
+    // first batch are migrated with MigrateState
+    let mut done = true;
+    if storage_version < MIGRATE_VERSION_2.parse()? {
+        // nobody could manage the pools and connections without an admin
+        if msg.admin.is_none() {
+            return Err(ContractError::MigrateAdminRequired {});
+        }
+        v1::migrate_state(deps.storage)?;
+        (_, done) = v1::migrate_entries(deps.storage, DEFAULT_MIGRATE_LIMIT as usize)?;
+    }
+
+    if let Some(admin) = msg.admin {
+        let admin = deps.api.addr_validate(&admin)?;
+        ADMIN.set(deps.branch(), Some(admin))?;
+    }
+    for allowed in msg.allowlist {
+        add_allow_pool(deps.storage, allowed)?;
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("status", migrate_status(done)))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::ListChannels { start_after, limit } => {
+            to_binary(&query_list(deps, start_after, limit)?)
+        }
+        QueryMsg::Channel { id } => to_binary(&query_channel(deps, id)?),
+        QueryMsg::ClosedChannels { start_after, limit } => {
+            to_binary(&list_closed_channels(deps, start_after, limit)?)
+        }
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
+        QueryMsg::Lockup { channel, owner } => to_binary(&query_lockup(deps, channel, owner)?),
+        QueryMsg::AllLockups {
+            channel,
+            start_after,
+            limit,
+        } => to_binary(&query_all_lockup(deps, channel, start_after, limit)?),
+        QueryMsg::LockupOwner { address } => to_binary(&query_lockup_owner(deps, address)?),
+        QueryMsg::UserBalances { channel, owner } => {
+            to_binary(&query_user_balances(deps, channel, owner)?)
+        }
+        QueryMsg::PendingOps { start_after, limit } => {
+            to_binary(&list_pending_ops(deps, start_after, limit)?)
+        }
+        QueryMsg::Forwards { start_after, limit } => {
+            to_binary(&list_forwards(deps, start_after, limit)?)
+        }
+        QueryMsg::Allowed { contract } => to_binary(&query_allowed(deps, contract)?),
+        QueryMsg::ListAllowed { start_after, limit } => {
+            to_binary(&list_allowed(deps, start_after, limit)?)
+        }
+        QueryMsg::PendingRefund { recipient } => to_binary(&query_pending_refund(deps, recipient)?),
+        QueryMsg::PendingRefunds { start_after, limit } => {
+            to_binary(&list_pending_refunds(deps, start_after, limit)?)
+        }
+        QueryMsg::ListConnections { start_after, limit } => {
+            to_binary(&list_connections(deps, start_after, limit)?)
+        }
+        QueryMsg::AllowedPool { pool_id } => to_binary(&query_allowed_pool(deps, pool_id)?),
+        QueryMsg::ListAllowedPools { start_after, limit } => {
+            to_binary(&list_allowed_pools(deps, start_after, limit)?)
+        }
+        QueryMsg::Fees {} => to_binary(&query_fees(deps)?),
+        QueryMsg::RateLimits { channel } => to_binary(&query_rate_limits(deps, env, channel)?),
+        QueryMsg::Packet { channel, sequence } => {
+            to_binary(&query_packet(deps, channel, sequence)?)
+        }
+        QueryMsg::PacketHistory {
+            channel,
+            sender,
+            start_after,
+            limit,
+        } => to_binary(&list_packet_history(
+            deps,
+            channel,
+            sender,
+            start_after,
+            limit,
+        )?),
+    }
+}
+
+fn query_list(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListChannelsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+
+    let channels = CHANNEL_INFO
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|r| r.map(|(_, v)| v))
+        .collect::<StdResult<_>>()?;
+    Ok(ListChannelsResponse { channels })
+}
+
+// make public for ibc tests
+pub fn query_channel(deps: Deps, id: String) -> StdResult<ChannelResponse> {
+    let info = CHANNEL_INFO.load(deps.storage, &id)?;
+    // this returns Vec<(outstanding, total)>
+    let state = CHANNEL_STATE
+        .prefix(&id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|r| {
+            r.map(|(denom, v)| {
+                let outstanding = Amount::from_parts(denom.clone(), v.outstanding);
+                let total = Amount::from_parts(denom, v.total_sent);
+                (outstanding, total)
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    // we want (Vec<outstanding>, Vec<total>)
+    let (balances, total_sent) = state.into_iter().unzip();
+    let max_slippage_bps = CHANNEL_MAX_SLIPPAGE.may_load(deps.storage, &id)?;
+    let action_port = CHANNEL_ACTION_PORT.may_load(deps.storage, &id)?;
+    let status = load_channel_status(deps.storage, &id)?;
+
+    Ok(ChannelResponse {
+        info,
+        balances,
+        total_sent,
+        max_slippage_bps,
+        action_port,
+        status,
+    })
+}
+
+fn list_closed_channels(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListChannelStatusResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+
+    let channels = CHANNEL_STATUS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|r| r.map(|(id, status)| ChannelStatusResponse { id, status }))
+        .collect::<StdResult<_>>()?;
+    Ok(ListChannelStatusResponse { channels })
+}
+
+fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let admin = ADMIN.get(deps)?.unwrap_or_else(|| Addr::unchecked(""));
+    let res = ConfigResponse {
+        default_timeout: cfg.default_timeout,
+        lockup_id: cfg.lockup_id,
+        history_limit: cfg.history_limit,
+        admin: admin.into(),
+    };
+    Ok(res)
+}
+
+fn query_fees(deps: Deps) -> StdResult<FeesResponse> {
+    let fees = FEES.may_load(deps.storage)?.unwrap_or_default();
+    let collected = COLLECTED_FEES
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|r| r.map(|(denom, amount)| Amount::from_parts(denom, amount)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(FeesResponse { fees, collected })
+}
+
+fn query_rate_limits(deps: Deps, env: Env, channel: String) -> StdResult<ListRateLimitsResponse> {
+    let rate_limits = RATE_LIMITS
+        .prefix(&channel)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|r| {
+            r.map(|(denom, limit)| {
+                // only the buckets of the last period count, the older ones are dropped by the next packet
+                let (outflow, inflow) = limit.current_flow(env.block.time);
+                RateLimitResponse {
+                    denom,
+                    period: limit.period,
+                    max_outflow: limit.max_outflow,
+                    max_inflow: limit.max_inflow,
+                    outflow,
+                    inflow,
+                }
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(ListRateLimitsResponse { rate_limits })
+}
+
+fn query_lockup(deps: Deps, channel_id: String, owner: String) -> StdResult<LockupResponse> {
+    let lockup_key = (channel_id.as_str(), owner.as_str());
+    let lockup_address = LOCKUP.load(deps.storage, lockup_key).unwrap_or_default();
+    let res = LockupResponse {
+        owner,
+        address: lockup_address,
+    };
+    Ok(res)
+}
+
+fn query_all_lockup(
+    deps: Deps,
+    channel_id: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListLockupResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+
+    let lockups = LOCKUP
+        .prefix(channel_id.as_str())
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|r| r.map(|(owner, address)| LockupResponse { owner, address }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(ListLockupResponse { lockups })
+}
+
+fn query_lockup_owner(deps: Deps, address: String) -> StdResult<LockupOwnerResponse> {
+    let (channel, owner) = LOCKUP_OWNER.load(deps.storage, &address)?;
+    Ok(LockupOwnerResponse { channel, owner })
+}
+
+// make public for ibc tests
+pub fn query_user_balances(
+    deps: Deps,
+    channel_id: String,
+    owner: String,
+) -> StdResult<UserBalancesResponse> {
+    let balances = USER_BALANCE
+        .prefix((channel_id.as_str(), owner.as_str()))
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|r| r.map(|(denom, amount)| Amount::from_parts(denom, amount)))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(UserBalancesResponse { balances })
+}
+
+// settings for pagination
+const MAX_LIMIT: u32 = 30;
+const DEFAULT_MIGRATE_LIMIT: u32 = 100;
+const MAX_MIGRATE_LIMIT: u32 = 500;
+const DEFAULT_LIMIT: u32 = 10;
+
+fn list_pending_ops(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<ListPendingOpsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let ops = PENDING_OPS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(id, args)| PendingOpResponse { id, args }))
+        .collect::<StdResult<_>>()?;
+    Ok(ListPendingOpsResponse { ops })
+}
+
+fn list_forwards(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<ListForwardsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let forwards = FORWARDS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(id, forward)| ForwardResponse { id, forward }))
+        .collect::<StdResult<_>>()?;
+    Ok(ListForwardsResponse { forwards })
+}
+
+fn query_packet(deps: Deps, channel: String, sequence: u64) -> StdResult<PacketResponse> {
+    let record = PACKET_HISTORY.load(deps.storage, (&channel, sequence))?;
+    Ok(PacketResponse { sequence, record })
+}
+
+fn list_packet_history(
+    deps: Deps,
+    channel: String,
+    sender: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<ListPacketsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let packets = PACKET_HISTORY
+        .prefix(&channel)
+        .range(deps.storage, start, None, Order::Ascending)
+        .filter(|item| match (item, &sender) {
+            (Ok((_, record)), Some(sender)) => &record.sender == sender,
+            _ => true,
+        })
+        .take(limit)
+        .map(|item| item.map(|(sequence, record)| PacketResponse { sequence, record }))
+        .collect::<StdResult<_>>()?;
+    Ok(ListPacketsResponse { packets })
+}
+
+fn query_allowed(deps: Deps, contract: String) -> StdResult<AllowedResponse> {
+    let addr = deps.api.addr_validate(&contract)?;
+    let info = ALLOW_LIST.may_load(deps.storage, &addr)?;
+    let res = match info {
+        None => AllowedResponse {
+            is_allowed: false,
+            gas_limit: None,
+        },
+        Some(a) => AllowedResponse {
+            is_allowed: true,
+            gas_limit: a.gas_limit,
+        },
+    };
+    Ok(res)
+}
+
+fn list_allowed(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListAllowedResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let addr = maybe_addr(deps.api, start_after)?;
+    let start = addr.as_ref().map(Bound::exclusive);
+
+    let allow = ALLOW_LIST
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            item.map(|(addr, allow)| AllowMsg {
+                contract: addr.into(),
+                gas_limit: allow.gas_limit,
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(ListAllowedResponse { allow })
+}
+
+fn query_pending_refund(deps: Deps, recipient: String) -> StdResult<PendingRefundResponse> {
+    let refunds = PENDING_REFUNDS
+        .prefix(&recipient)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|r| r.map(|(denom, amount)| Amount::from_parts(denom, amount)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PendingRefundResponse { recipient, refunds })
+}
+
+fn list_pending_refunds(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListPendingRefundsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(PrefixBound::exclusive);
+
+    // the limit applies to recipients, all the refunds of a recipient are returned together
+    let mut refunds: Vec<PendingRefundResponse> = vec![];
+    for item in PENDING_REFUNDS.prefix_range(deps.storage, start, None, Order::Ascending) {
+        let ((recipient, denom), amount) = item?;
+        let amount = Amount::from_parts(denom, amount);
+        if let Some(last) = refunds
+            .last_mut()
+            .filter(|last| last.recipient == recipient)
+        {
+            last.refunds.push(amount);
+        } else if refunds.len() == limit {
+            break;
+        } else {
+            refunds.push(PendingRefundResponse {
+                recipient,
+                refunds: vec![amount],
+            });
+        }
+    }
+    Ok(ListPendingRefundsResponse { refunds })
+}
+
+fn list_connections(
+    deps: Deps,
+    start_after: Option<ConnectionMsg>,
+    limit: Option<u32>,
+) -> StdResult<ListConnectionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_ref().map(|connection| {
+        Bound::exclusive((
+            connection.connection_id.as_str(),
+            connection.counterparty_port_id.as_str(),
+        ))
+    });
+
+    let connections = ALLOWED_CONNECTIONS
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            item.map(|(connection_id, counterparty_port_id)| ConnectionMsg {
+                connection_id,
+                counterparty_port_id,
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(ListConnectionsResponse { connections })
+}
+
+fn query_allowed_pool(deps: Deps, pool_id: Uint64) -> StdResult<AllowedPoolResponse> {
+    let info = POOL_ALLOW_LIST.may_load(deps.storage, pool_id.u64())?;
+    let res = match info {
+        None => AllowedPoolResponse {
+            is_allowed: false,
+            max_amount: None,
+            max_slippage_bps: None,
+        },
+        Some(a) => AllowedPoolResponse {
+            is_allowed: true,
+            max_amount: a.max_amount,
+            max_slippage_bps: a.max_slippage_bps,
+        },
+    };
+    Ok(res)
+}
+
+fn list_allowed_pools(
+    deps: Deps,
+    start_after: Option<Uint64>,
+    limit: Option<u32>,
+) -> StdResult<ListAllowedPoolsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(id.u64()));
+
+    let pools = POOL_ALLOW_LIST
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            item.map(|(pool_id, allow)| AllowPoolMsg {
+                pool_id: pool_id.into(),
+                max_amount: allow.max_amount,
+                max_slippage_bps: allow.max_slippage_bps,
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(ListAllowedPoolsResponse { pools })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{increase_collected_fee, save_lockup};
+    use crate::test_helpers::*;
+
+    use cosmwasm_std::testing::{mock_env, mock_info};
+    use cosmwasm_std::{coin, coins, from_binary, Attribute, CosmosMsg, IbcMsg, StdError, Uint128};
+
+    use cw_controllers::AdminError;
+    use cw_utils::PaymentError;
+
+    #[test]
+    fn setup_and_query() {
+        let deps = setup(&["channel-3", "channel-7"]);
+
+        let list = |start_after: Option<&str>, limit: Option<u32>| {
+            let msg = QueryMsg::ListChannels {
+                start_after: start_after.map(String::from),
+                limit,
+            };
+            let raw_list = query(deps.as_ref(), mock_env(), msg).unwrap();
+            let list_res: ListChannelsResponse = from_binary(&raw_list).unwrap();
+            list_res.channels
+        };
+        let channels = list(None, None);
+        assert_eq!(2, channels.len());
+        assert_eq!(mock_channel_info("channel-3"), channels[0]);
+        assert_eq!(mock_channel_info("channel-7"), channels[1]);
+        assert_eq!(vec![mock_channel_info("channel-3")], list(None, Some(1)));
+        assert_eq!(
+            vec![mock_channel_info("channel-7")],
+            list(Some("channel-3"), None)
+        );
+
+        let raw_channel = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Channel {
+                id: "channel-3".to_string(),
+            },
+        )
+        .unwrap();
+        let chan_res: ChannelResponse = from_binary(&raw_channel).unwrap();
+        assert_eq!(chan_res.info, mock_channel_info("channel-3"));
+        assert_eq!(0, chan_res.total_sent.len());
+        assert_eq!(0, chan_res.balances.len());
+
+        let err = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Channel {
+                id: "channel-10".to_string(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StdError::not_found("cw_ics20_swap::state::ChannelInfo")
+        );
+    }
+
+    #[test]
+    fn proper_checks_on_execute_native() {
+        let send_channel = "channel-5";
+        let mut deps = setup(&[send_channel]);
+
+        let mut transfer = TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "foreign-address".to_string(),
+            timeout: None,
+        };
+
+        // works with proper funds
+        let msg = ExecuteMsg::Transfer(transfer.clone());
+        let info = mock_info("foobar", &coins(1234567, "ucosm"));
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(res.messages[0].gas_limit, None);
+        assert_eq!(1, res.messages.len());
+        if let CosmosMsg::Ibc(IbcMsg::SendPacket {
+            channel_id,
+            data,
+            timeout,
+        }) = &res.messages[0].msg
+        {
+            let expected_timeout = mock_env().block.time.plus_seconds(DEFAULT_TIMEOUT);
+            assert_eq!(timeout, &expected_timeout.into());
+            assert_eq!(channel_id.as_str(), send_channel);
+            let msg: Ics20Packet = from_binary(data).unwrap();
+            assert_eq!(msg.amount, Uint128::new(1234567));
+            assert_eq!(msg.denom.as_str(), "ucosm");
+            assert_eq!(msg.sender.as_str(), "foobar");
             assert_eq!(msg.receiver.as_str(), "foreign-address");
         } else {
             panic!("Unexpected return message: {:?}", res.messages[0]);
@@ -270,16 +1460,225 @@ mod test {
         let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
         assert_eq!(err, ContractError::Payment(PaymentError::MultipleDenoms {}));
 
-        // reject with bad channel id
-        transfer.channel = "channel-45".to_string();
-        let msg = ExecuteMsg::Transfer(transfer);
-        let info = mock_info("foobar", &coins(1234567, "ucosm"));
-        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        // reject with bad channel id
+        transfer.channel = "channel-45".to_string();
+        let msg = ExecuteMsg::Transfer(transfer);
+        let info = mock_info("foobar", &coins(1234567, "ucosm"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::NoSuchChannel {
+                id: "channel-45".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn proper_checks_on_execute_cw20() {
+        let send_channel = "channel-15";
+        let cw20_addr = "my-token";
+        let mut deps = setup(&["channel-3", send_channel]);
+
+        let transfer = TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "foreign-address".to_string(),
+            timeout: Some(7777),
+        };
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "my-account".into(),
+            amount: Uint128::new(888777666),
+            msg: to_binary(&transfer).unwrap(),
+        });
+
+        // rejected until the contract is allowed
+        let info = mock_info(cw20_addr, &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NotOnAllowList);
+
+        let allow = ExecuteMsg::Allow(AllowMsg {
+            contract: cw20_addr.to_string(),
+            gas_limit: Some(123456),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), allow).unwrap();
+
+        // works with proper funds
+        let info = mock_info(cw20_addr, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(res.messages[0].gas_limit, None);
+        if let CosmosMsg::Ibc(IbcMsg::SendPacket {
+            channel_id,
+            data,
+            timeout,
+        }) = &res.messages[0].msg
+        {
+            let expected_timeout = mock_env().block.time.plus_seconds(7777);
+            assert_eq!(timeout, &expected_timeout.into());
+            assert_eq!(channel_id.as_str(), send_channel);
+            let msg: Ics20Packet = from_binary(data).unwrap();
+            assert_eq!(msg.amount, Uint128::new(888777666));
+            assert_eq!(msg.denom, format!("cw20:{}", cw20_addr));
+            assert_eq!(msg.sender.as_str(), "my-account");
+            assert_eq!(msg.receiver.as_str(), "foreign-address");
+        } else {
+            panic!("Unexpected return message: {:?}", res.messages[0]);
+        }
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::cw20(888777666, cw20_addr)]);
+
+        // reject with tokens funds
+        let info = mock_info(cw20_addr, &coins(1234567, "ucosm"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert_eq!(err, ContractError::Payment(PaymentError::NonPayable {}));
+    }
+
+    #[test]
+    fn allow_cw20_tokens() {
+        let mut deps = setup(&[]);
+
+        let allow = |contract: &str, gas_limit: Option<u64>| {
+            ExecuteMsg::Allow(AllowMsg {
+                contract: contract.to_string(),
+                gas_limit,
+            })
+        };
+
+        // only admin can allow tokens
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            allow("token-a", None),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+
+        let admin = mock_info(ADMIN_ADDR, &[]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            allow("token-a", None),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            allow("token-b", Some(100000)),
+        )
+        .unwrap();
+
+        // the gas limit can only increase
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            allow("token-a", Some(100000)),
+        );
+        assert_eq!(err.unwrap_err(), ContractError::CannotLowerGas);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            allow("token-b", Some(99999)),
+        );
+        assert_eq!(err.unwrap_err(), ContractError::CannotLowerGas);
+        execute(deps.as_mut(), mock_env(), admin, allow("token-b", None)).unwrap();
+
+        let allowed = |contract: &str| {
+            let msg = QueryMsg::Allowed {
+                contract: contract.to_string(),
+            };
+            let raw = query(deps.as_ref(), mock_env(), msg).unwrap();
+            from_binary::<AllowedResponse>(&raw).unwrap()
+        };
+        assert_eq!(
+            allowed("token-b"),
+            AllowedResponse {
+                is_allowed: true,
+                gas_limit: None,
+            }
+        );
+        assert!(!allowed("token-c").is_allowed);
+
+        let msg = QueryMsg::ListAllowed {
+            start_after: Some("token-a".to_string()),
+            limit: None,
+        };
+        let raw = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let list: ListAllowedResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            list.allow,
+            vec![AllowMsg {
+                contract: "token-b".to_string(),
+                gas_limit: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn approve_and_revoke_connections() {
+        let mut deps = setup(&[]);
+
+        let connection = |connection_id: &str, port_id: &str| ConnectionMsg {
+            connection_id: connection_id.to_string(),
+            counterparty_port_id: port_id.to_string(),
+        };
+        let list = |deps: Deps, start_after: Option<ConnectionMsg>| {
+            let msg = QueryMsg::ListConnections {
+                start_after,
+                limit: None,
+            };
+            let raw = query(deps, mock_env(), msg).unwrap();
+            from_binary::<ListConnectionsResponse>(&raw)
+                .unwrap()
+                .connections
+        };
+        assert_eq!(
+            list(deps.as_ref(), None),
+            vec![connection(CONNECTION_ID, COUNTERPARTY_PORT)]
+        );
+
+        // only admin can approve and revoke connections
+        let msg = ExecuteMsg::ApproveConnection(connection("connection-5", "transfer"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), msg);
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        let msg = ExecuteMsg::RevokeConnection(connection(CONNECTION_ID, COUNTERPARTY_PORT));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), msg);
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+
+        let admin = mock_info(ADMIN_ADDR, &[]);
+        let msg = ExecuteMsg::ApproveConnection(connection("connection-5", "transfer"));
+        execute(deps.as_mut(), mock_env(), admin.clone(), msg).unwrap();
+        assert_eq!(
+            list(deps.as_ref(), None),
+            vec![
+                connection(CONNECTION_ID, COUNTERPARTY_PORT),
+                connection("connection-5", "transfer")
+            ]
+        );
+        assert_eq!(
+            list(
+                deps.as_ref(),
+                Some(connection(CONNECTION_ID, COUNTERPARTY_PORT))
+            ),
+            vec![connection("connection-5", "transfer")]
+        );
+
+        let msg = ExecuteMsg::RevokeConnection(connection(CONNECTION_ID, COUNTERPARTY_PORT));
+        execute(deps.as_mut(), mock_env(), admin, msg).unwrap();
         assert_eq!(
-            err,
-            ContractError::NoSuchChannel {
-                id: "channel-45".to_string()
-            }
+            list(deps.as_ref(), None),
+            vec![connection("connection-5", "transfer")]
         );
     }
 
@@ -295,6 +1694,8 @@ mod test {
             mock_env(),
             QueryMsg::AllLockups {
                 channel: send_channel.to_string(),
+                start_after: None,
+                limit: None,
             },
         )
         .unwrap();
@@ -303,13 +1704,13 @@ mod test {
 
         // Save lockup mock
         let lockup_contract = "lockup-addr".to_string();
-        LOCKUP
-            .save(
-                deps.as_mut().storage,
-                (send_channel, sender),
-                &lockup_contract,
-            )
-            .unwrap();
+        save_lockup(
+            deps.as_mut().storage,
+            send_channel,
+            sender,
+            &lockup_contract,
+        )
+        .unwrap();
 
         // Query lockup (no found)
         let raw_lockup = query(
@@ -345,10 +1746,691 @@ mod test {
             mock_env(),
             QueryMsg::AllLockups {
                 channel: send_channel.to_string(),
+                start_after: None,
+                limit: None,
             },
         )
         .unwrap();
         let list_res: ListLockupResponse = from_binary(&raw_list).unwrap();
         assert_eq!(1, list_res.lockups.len());
+
+        // Paginate lockups
+        save_lockup(deps.as_mut().storage, send_channel, "remote-b", "lockup-b").unwrap();
+        save_lockup(deps.as_mut().storage, "channel-3", "remote-c", "lockup-c").unwrap();
+        let list = |start_after: Option<&str>, limit: Option<u32>| {
+            let msg = QueryMsg::AllLockups {
+                channel: send_channel.to_string(),
+                start_after: start_after.map(String::from),
+                limit,
+            };
+            let raw_list = query(deps.as_ref(), mock_env(), msg).unwrap();
+            let list_res: ListLockupResponse = from_binary(&raw_list).unwrap();
+            list_res
+                .lockups
+                .into_iter()
+                .map(|l| l.owner)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["remote-addr", "remote-b"], list(None, None));
+        assert_eq!(vec!["remote-addr"], list(None, Some(1)));
+        assert_eq!(vec!["remote-b"], list(Some("remote-addr"), Some(1)));
+
+        // Reverse lookup
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::LockupOwner {
+                address: "lockup-c".to_string(),
+            },
+        )
+        .unwrap();
+        let owner: LockupOwnerResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            owner,
+            LockupOwnerResponse {
+                channel: "channel-3".to_string(),
+                owner: "remote-c".to_string(),
+            }
+        );
+        let msg = QueryMsg::LockupOwner {
+            address: "unknown".to_string(),
+        };
+        query(deps.as_ref(), mock_env(), msg).unwrap_err();
+    }
+
+    #[test]
+    fn update_config_and_admin() {
+        let mut deps = setup(&[]);
+
+        let raw = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+        let cfg: ConfigResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            cfg,
+            ConfigResponse {
+                default_timeout: DEFAULT_TIMEOUT,
+                lockup_id: 1,
+                history_limit: DEFAULT_HISTORY_LIMIT,
+                admin: ADMIN_ADDR.to_string(),
+            }
+        );
+
+        // only admin can update
+        let msg = ExecuteMsg::UpdateConfig {
+            default_timeout: None,
+            lockup_id: Some(5),
+            history_limit: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            msg.clone(),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        let cfg = CONFIG.load(deps.as_ref().storage).unwrap();
+        assert_eq!(DEFAULT_TIMEOUT, cfg.default_timeout);
+        assert_eq!(5, cfg.lockup_id);
+
+        // change admin
+        let msg = ExecuteMsg::UpdateAdmin {
+            admin: "new-gov".to_string(),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            msg.clone(),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::UpdateConfig {
+            default_timeout: Some(600),
+            lockup_id: None,
+            history_limit: Some(20),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADMIN_ADDR, &[]),
+            msg.clone(),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        execute(deps.as_mut(), mock_env(), mock_info("new-gov", &[]), msg).unwrap();
+
+        let raw = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+        let cfg: ConfigResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            cfg,
+            ConfigResponse {
+                default_timeout: 600,
+                lockup_id: 5,
+                history_limit: 20,
+                admin: "new-gov".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limits() {
+        let mut deps = setup(&["channel-3"]);
+
+        let limit = |channel: &str, period: u64| {
+            ExecuteMsg::AddRateLimit(RateLimitMsg {
+                channel: channel.to_string(),
+                denom: "uosmo".to_string(),
+                period,
+                max_outflow: None,
+                max_inflow: Some(Uint128::new(1000)),
+            })
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            limit("channel-3", 3600),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        let info = mock_info(ADMIN_ADDR, &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            limit("channel-5", 3600),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::NoSuchChannel {
+                id: "channel-5".to_string()
+            }
+        );
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            limit("channel-3", 0),
+        );
+        assert_eq!(err.unwrap_err(), ContractError::InvalidRateLimitPeriod {});
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            limit("channel-3", 3600),
+        )
+        .unwrap();
+
+        let transfer = |deps: DepsMut, env: Env, amount: u128| {
+            let msg = ExecuteMsg::Transfer(TransferMsg {
+                channel: "channel-3".to_string(),
+                remote_address: "foreign-address".to_string(),
+                timeout: None,
+            });
+            execute(deps, env, mock_info("foobar", &coins(amount, "uosmo")), msg)
+        };
+        transfer(deps.as_mut(), mock_env(), 600).unwrap();
+        let err = transfer(deps.as_mut(), mock_env(), 500).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::RateLimitExceeded {
+                channel: "channel-3".to_string(),
+                denom: "uosmo".to_string(),
+            }
+        );
+
+        let msg = QueryMsg::RateLimits {
+            channel: "channel-3".to_string(),
+        };
+        let res: ListRateLimitsResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(
+            res.rate_limits,
+            vec![RateLimitResponse {
+                denom: "uosmo".to_string(),
+                period: 3600,
+                max_outflow: None,
+                max_inflow: Some(Uint128::new(1000)),
+                outflow: Uint128::zero(),
+                inflow: Uint128::new(600),
+            }]
+        );
+
+        // the window slides, the quota isn't reset at the end of the period
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(3000);
+        transfer(deps.as_mut(), env.clone(), 500).unwrap_err();
+        transfer(deps.as_mut(), env.clone(), 400).unwrap();
+        env.block.time = mock_env().block.time.plus_seconds(3600);
+        transfer(deps.as_mut(), env.clone(), 1).unwrap_err();
+
+        // the first transfer left the window one bucket after the period
+        env.block.time = mock_env().block.time.plus_seconds(3600 + 360);
+        transfer(deps.as_mut(), env.clone(), 700).unwrap_err();
+        transfer(deps.as_mut(), env.clone(), 600).unwrap();
+
+        // reset the quota
+        transfer(deps.as_mut(), env.clone(), 600).unwrap_err();
+        let msg = ExecuteMsg::ResetRateLimit {
+            channel: "channel-3".to_string(),
+            denom: "uosmo".to_string(),
+        };
+        execute(deps.as_mut(), env.clone(), info.clone(), msg).unwrap();
+        transfer(deps.as_mut(), env, 600).unwrap();
+
+        let msg = ExecuteMsg::RemoveRateLimit {
+            channel: "channel-3".to_string(),
+            denom: "uosmo".to_string(),
+        };
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        transfer(deps.as_mut(), mock_env(), 5000).unwrap();
+    }
+
+    #[test]
+    fn update_and_withdraw_fees() {
+        let mut deps = setup(&[]);
+
+        let update = |swap_bps: Option<u64>, lock_bps: Option<u64>| ExecuteMsg::UpdateFees {
+            swap_bps,
+            join_pool_bps: None,
+            exit_pool_bps: None,
+            lock_bps,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            update(Some(30), None),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+
+        let info = mock_info(ADMIN_ADDR, &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            update(None, Some(10_001)),
+        );
+        assert_eq!(err.unwrap_err(), ContractError::InvalidFee {});
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            update(Some(30), None),
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            update(None, Some(10)),
+        )
+        .unwrap();
+
+        increase_collected_fee(deps.as_mut().storage, "uosmo", Uint128::new(5000)).unwrap();
+        let raw = query(deps.as_ref(), mock_env(), QueryMsg::Fees {}).unwrap();
+        let res: FeesResponse = from_binary(&raw).unwrap();
+        assert_eq!(30, res.fees.swap_bps);
+        assert_eq!(0, res.fees.join_pool_bps);
+        assert_eq!(10, res.fees.lock_bps);
+        assert_eq!(vec![Amount::native(5000, "uosmo")], res.collected);
+
+        // withdraw part of the fees to another address
+        let withdraw = |amount: Option<u128>, recipient: Option<&str>| ExecuteMsg::WithdrawFees {
+            denom: "uosmo".to_string(),
+            amount: amount.map(Uint128::new),
+            recipient: recipient.map(String::from),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            withdraw(None, None),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            withdraw(Some(5001), None),
+        );
+        assert_eq!(err.unwrap_err(), ContractError::InsufficientFunds {});
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            withdraw(Some(2000), Some("treasury")),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "treasury".to_string(),
+                amount: coins(2000, "uosmo"),
+            })
+        );
+
+        // the rest goes to the admin
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            withdraw(None, None),
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: ADMIN_ADDR.to_string(),
+                amount: coins(3000, "uosmo"),
+            })
+        );
+        let err = execute(deps.as_mut(), mock_env(), info, withdraw(None, None));
+        assert_eq!(err.unwrap_err(), ContractError::NoFunds {});
+    }
+
+    #[test]
+    fn allow_pools() {
+        let mut deps = setup(&[]);
+
+        let allow = |pool_id: u64, max_amount: Option<u128>| {
+            ExecuteMsg::AllowPool(AllowPoolMsg {
+                pool_id: pool_id.into(),
+                max_amount: max_amount.map(Uint128::new),
+                max_slippage_bps: None,
+            })
+        };
+
+        // only admin can allow pools
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            allow(2, None),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+
+        let info = mock_info(ADMIN_ADDR, &[]);
+        execute(deps.as_mut(), mock_env(), info.clone(), allow(3, None)).unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            allow(2, Some(5000)),
+        )
+        .unwrap();
+
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::AllowedPool {
+                pool_id: 2u8.into(),
+            },
+        )
+        .unwrap();
+        let allowed: AllowedPoolResponse = from_binary(&raw).unwrap();
+        assert!(allowed.is_allowed);
+        assert_eq!(Some(Uint128::new(5000)), allowed.max_amount);
+
+        // slippage is limited to 100%
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 4u8.into(),
+            max_amount: None,
+            max_slippage_bps: Some(10_001),
+        });
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidMaxSlippage {});
+
+        // paginate the list
+        let list = |deps: Deps,
+                    start_after: Option<u64>,
+                    limit: Option<u32>|
+         -> ListAllowedPoolsResponse {
+            let msg = QueryMsg::ListAllowedPools {
+                start_after: start_after.map(Uint64::new),
+                limit,
+            };
+            from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap()
+        };
+        let ids = |res: ListAllowedPoolsResponse| -> Vec<u64> {
+            res.pools.iter().map(|p| p.pool_id.u64()).collect()
+        };
+        assert_eq!(vec![1, 2, 3], ids(list(deps.as_ref(), None, None)));
+        assert_eq!(vec![1, 2], ids(list(deps.as_ref(), None, Some(2))));
+        assert_eq!(vec![3], ids(list(deps.as_ref(), Some(2), Some(2))));
+
+        // disallow pool
+        let msg = ExecuteMsg::DisallowPool {
+            pool_id: 2u8.into(),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            msg.clone(),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::Admin(AdminError::NotAdmin {})
+        );
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::AllowedPool {
+                pool_id: 2u8.into(),
+            },
+        )
+        .unwrap();
+        let allowed: AllowedPoolResponse = from_binary(&raw).unwrap();
+        assert!(!allowed.is_allowed);
+        assert_eq!(vec![1, 3], ids(list(deps.as_ref(), None, None)));
+    }
+
+    #[test]
+    fn migrate_from_v1() {
+        let mut deps = setup(&["channel-3"]);
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.1.0").unwrap();
+        deps.as_mut().storage.set(b"reply_args", b"{}");
+        ADMIN.set(deps.as_mut(), None).unwrap();
+        ALLOWED_CONNECTIONS.remove(deps.as_mut().storage, (CONNECTION_ID, COUNTERPARTY_PORT));
+        let lockup_key = ("channel-3", "remote-addr");
+        LOCKUP
+            .save(
+                deps.as_mut().storage,
+                lockup_key,
+                &"lockup-addr".to_string(),
+            )
+            .unwrap();
+
+        // the admin must be set
+        let err = migrate(
+            deps.as_mut(),
+            mock_env(),
+            MigrateMsg {
+                admin: None,
+                allowlist: vec![],
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err, ContractError::MigrateAdminRequired {});
+        let version = get_contract_version(deps.as_ref().storage).unwrap();
+        assert_eq!("0.1.0", version.version);
+
+        let msg = MigrateMsg {
+            admin: Some("new-gov".to_string()),
+            allowlist: vec![AllowPoolMsg {
+                pool_id: 7u8.into(),
+                max_amount: Some(Uint128::new(1000)),
+                max_slippage_bps: None,
+            }],
+        };
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrated")));
+        assert_eq!(
+            None,
+            v1::MIGRATION_CURSOR
+                .may_load(deps.as_ref().storage)
+                .unwrap()
+        );
+
+        let version = get_contract_version(deps.as_ref().storage).unwrap();
+        assert_eq!(CONTRACT_VERSION, version.version);
+        assert_eq!(None, deps.as_ref().storage.get(b"reply_args"));
+        ADMIN
+            .assert_admin(deps.as_ref(), &Addr::unchecked("new-gov"))
+            .unwrap();
+
+        // config and channels are kept
+        let cfg = CONFIG.load(deps.as_ref().storage).unwrap();
+        assert_eq!(DEFAULT_TIMEOUT, cfg.default_timeout);
+        assert_eq!(1, cfg.lockup_id);
+        let channel = query_channel(deps.as_ref(), "channel-3".to_string()).unwrap();
+        assert_eq!(mock_channel_info("channel-3"), channel.info);
+
+        // lockups are indexed by address
+        let owner = LOCKUP_OWNER
+            .load(deps.as_ref().storage, "lockup-addr")
+            .unwrap();
+        assert_eq!(("channel-3".to_string(), "remote-addr".to_string()), owner);
+
+        // the connection of the open channel is allowed
+        assert!(ALLOWED_CONNECTIONS.has(deps.as_ref().storage, (CONNECTION_ID, COUNTERPARTY_PORT)));
+
+        // the pools of the migration are allowed
+        let pool = POOL_ALLOW_LIST.load(deps.as_ref().storage, 7).unwrap();
+        assert_eq!(Some(Uint128::new(1000)), pool.max_amount);
+
+        // same version can be migrated again
+        migrate(
+            deps.as_mut(),
+            mock_env(),
+            MigrateMsg {
+                admin: None,
+                allowlist: vec![],
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn migrate_from_v1_in_batches() {
+        let mut deps = setup(&["channel-3"]);
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.1.0").unwrap();
+        ALLOWED_CONNECTIONS.remove(deps.as_mut().storage, (CONNECTION_ID, COUNTERPARTY_PORT));
+        for i in 0..120 {
+            LOCKUP
+                .save(
+                    deps.as_mut().storage,
+                    ("channel-3", &format!("remote-{:03}", i)),
+                    &format!("lockup-{:03}", i),
+                )
+                .unwrap();
+        }
+        let indexed = |deps: Deps| {
+            LOCKUP_OWNER
+                .keys(deps.storage, None, None, Order::Ascending)
+                .count()
+        };
+
+        // the first batch is migrated with the contract
+        let msg = MigrateMsg {
+            admin: Some("new-gov".to_string()),
+            allowlist: vec![],
+        };
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrating")));
+        assert_eq!(100, indexed(deps.as_ref()));
+
+        let migrate_state = |limit: Option<u32>| ExecuteMsg::MigrateState { limit };
+        let info = mock_info("someone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, migrate_state(None)).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+
+        let admin = mock_info("new-gov", &[]);
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate_state(Some(15)),
+        )
+        .unwrap();
+        assert!(res.attributes.contains(&Attribute::new("migrated", "15")));
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrating")));
+        assert_eq!(115, indexed(deps.as_ref()));
+        assert!(!ALLOWED_CONNECTIONS.has(deps.as_ref().storage, (CONNECTION_ID, COUNTERPARTY_PORT)));
+
+        // the last lockups, then the channels
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate_state(None),
+        )
+        .unwrap();
+        assert!(res.attributes.contains(&Attribute::new("migrated", "6")));
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrated")));
+        assert_eq!(120, indexed(deps.as_ref()));
+        let owner = LOCKUP_OWNER
+            .load(deps.as_ref().storage, "lockup-119")
+            .unwrap();
+        assert_eq!(("channel-3".to_string(), "remote-119".to_string()), owner);
+        assert!(ALLOWED_CONNECTIONS.has(deps.as_ref().storage, (CONNECTION_ID, COUNTERPARTY_PORT)));
+
+        // nothing left
+        let res = execute(deps.as_mut(), mock_env(), admin, migrate_state(None)).unwrap();
+        assert!(res.attributes.contains(&Attribute::new("migrated", "0")));
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrated")));
+    }
+
+    #[test]
+    fn migrate_checks_name_and_version() {
+        let mut deps = setup(&[]);
+
+        set_contract_version(deps.as_mut().storage, "crates.io:cw20-ics20", "0.1.0").unwrap();
+        let err = migrate(
+            deps.as_mut(),
+            mock_env(),
+            MigrateMsg {
+                admin: None,
+                allowlist: vec![],
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::CannotMigrate {
+                previous_contract: "crates.io:cw20-ics20".to_string()
+            }
+        );
+
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "99.0.0").unwrap();
+        let err = migrate(
+            deps.as_mut(),
+            mock_env(),
+            MigrateMsg {
+                admin: None,
+                allowlist: vec![],
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::CannotMigrateVersion {
+                previous_version: "99.0.0".to_string()
+            }
+        );
+
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.0.9").unwrap();
+        let err = migrate(
+            deps.as_mut(),
+            mock_env(),
+            MigrateMsg {
+                admin: None,
+                allowlist: vec![],
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::CannotMigrateVersion {
+                previous_version: "0.0.9".to_string()
+            }
+        );
     }
 }
@@ -2,7 +2,8 @@ use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
 use thiserror::Error;
 
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
+use cw_controllers::AdminError;
 use cw_utils::{ParseReplyError, PaymentError};
 
 /// Never is a placeholder to ensure we don't return any errors
@@ -17,9 +18,15 @@ pub enum ContractError {
     #[error("{0}")]
     Payment(#[from] PaymentError),
 
+    #[error("{0}")]
+    Admin(#[from] AdminError),
+
     #[error("{0}")]
     ParseReply(#[from] ParseReplyError),
 
+    #[error("{0}")]
+    SemVer(String),
+
     #[error("Channel doesn't exist: {id}")]
     NoSuchChannel { id: String },
 
@@ -85,6 +92,184 @@ pub enum ContractError {
 
     #[error("This message does no accept funds")]
     NonPayable {},
+
+    #[error("Invalid token in max amounts")]
+    InvalidTokenInMaxs {},
+
+    #[error("Pool {pool_id} is not on the allow list")]
+    PoolNotAllowed { pool_id: u64 },
+
+    #[error("Input amount exceeds the max amount {max_amount} of pool {pool_id}")]
+    PoolMaxAmountExceeded { pool_id: u64, max_amount: Uint128 },
+
+    #[error("Max slippage cannot be greater than 10000 bps")]
+    InvalidMaxSlippage {},
+
+    #[error("Fee cannot be greater than 10000 bps")]
+    InvalidFee {},
+
+    #[error("Packet has an action in both the action field and the memo")]
+    DuplicateAction {},
+
+    #[error("Memo action is for another contract: {contract}")]
+    MemoOtherContract { contract: String },
+
+    #[error("Rate limit period must be greater than zero")]
+    InvalidRateLimitPeriod {},
+
+    #[error("Rate limit of {denom} on channel {channel} exceeded")]
+    RateLimitExceeded { channel: String, denom: String },
+
+    #[error(
+        "Price impact of {price_impact_bps} bps exceeds the max slippage of {max_slippage_bps} bps"
+    )]
+    SlippageExceeded {
+        price_impact_bps: u64,
+        max_slippage_bps: u64,
+    },
+
+    #[error("{msg}")]
+    SubMsgFailed { msg: String },
+
+    #[error("You can only send cw20 tokens that have been explicitly allowed by governance")]
+    NotOnAllowList,
+
+    #[error("You cannot lower the gas limit for a contract on the allow list")]
+    CannotLowerGas,
+
+    #[error("Actions only accept native tokens")]
+    Cw20ActionNotSupported {},
+
+    #[error("Connection {connection_id} with port {port_id} is not on the allow list")]
+    ConnectionNotAllowed {
+        connection_id: String,
+        port_id: String,
+    },
+
+    #[error("Channel {channel} is not a transfer channel")]
+    NotTransferChannel { channel: String },
+
+    #[error("Channel {channel} is on another connection")]
+    ChannelOtherConnection { channel: String },
+
+    #[error("Action {action} needs tokens, it can't be sent on an action channel")]
+    ActionRequiresTokens { action: String },
+
+    #[error("No pending refunds")]
+    NoRefund {},
+
+    #[error("Channel {id} is closed")]
+    ChannelClosed { id: String },
+
+    #[error("Channel {id} is not closed")]
+    ChannelNotClosed { id: String },
+
+    #[error("Channel {id} is migrated to {channel}")]
+    ChannelMigrated { id: String, channel: String },
+
+    #[error("Owner {owner} already has a lockup on channel {channel}")]
+    LockupExists { channel: String, owner: String },
+
+    #[error("The output can't be forwarded and returned to the sender")]
+    ForwardWithReturnTo {},
+
+    #[error("Forward {id} doesn't exist")]
+    NoSuchForward { id: u64 },
+
+    #[error(
+        "Each split route needs a part of the input, the shares must add up to 10000 basis points with at most 10 routes"
+    )]
+    InvalidSplitRoutes {},
+
+    #[error("Split routes must have the same output denom")]
+    SplitRouteDenoms {},
+
+    #[error("Split swap output {amount} is below the min {min}")]
+    SplitSwapMinOut { amount: Uint128, min: Uint128 },
+
+    #[error("Contracts older than 0.2.0 have no admin, the migration must set one")]
+    MigrateAdminRequired {},
+
+    #[error("Port {port_id} isn't paired with channel {channel} to send actions")]
+    ActionPortNotPaired { channel: String, port_id: String },
+
+    #[error("The operation has no forward destination")]
+    NoForward {},
+
+    #[error("Forward {id} hasn't timed out yet")]
+    ForwardNotExpired { id: u64 },
+
+    #[error("Channel {channel} doesn't have the connection and counterparty port of channel {id}")]
+    ChannelMismatch { id: String, channel: String },
+}
+
+impl ContractError {
+    /// Stable code of the error, sent in the error acks.
+    /// New variants get a new code, existing codes must not change.
+    pub fn code(&self) -> u32 {
+        match self {
+            ContractError::Std(_) => 1,
+            ContractError::Payment(_) => 2,
+            ContractError::Admin(_) => 3,
+            ContractError::ParseReply(_) => 4,
+            ContractError::SemVer(_) => 5,
+            ContractError::NoSuchChannel { .. } => 6,
+            ContractError::NoFunds { .. } => 7,
+            ContractError::NoReplyData { .. } => 8,
+            ContractError::AmountOverflow { .. } => 9,
+            ContractError::InvalidIbcVersion { .. } => 10,
+            ContractError::OnlyOrderedChannel { .. } => 11,
+            ContractError::InsufficientFunds { .. } => 12,
+            ContractError::NoForeignTokens { .. } => 13,
+            ContractError::FromOtherPort { .. } => 14,
+            ContractError::FromOtherChannel { .. } => 15,
+            ContractError::CannotClose { .. } => 16,
+            ContractError::CannotMigrate { .. } => 17,
+            ContractError::CannotMigrateVersion { .. } => 18,
+            ContractError::UnknownReplyId { .. } => 19,
+            ContractError::MissingReplyData { .. } => 20,
+            ContractError::Unauthorized => 21,
+            ContractError::GammResultNotFound { .. } => 22,
+            ContractError::InvalidLpToken { .. } => 23,
+            ContractError::InvalidAmountValue { .. } => 24,
+            ContractError::OnlyLockupByChannel { .. } => 25,
+            ContractError::LockupNotFound { .. } => 26,
+            ContractError::NonPayable { .. } => 27,
+            ContractError::InvalidTokenInMaxs { .. } => 28,
+            ContractError::PoolNotAllowed { .. } => 29,
+            ContractError::PoolMaxAmountExceeded { .. } => 30,
+            ContractError::InvalidMaxSlippage { .. } => 31,
+            ContractError::InvalidFee { .. } => 32,
+            ContractError::DuplicateAction { .. } => 33,
+            ContractError::MemoOtherContract { .. } => 34,
+            ContractError::InvalidRateLimitPeriod { .. } => 35,
+            ContractError::RateLimitExceeded { .. } => 36,
+            ContractError::SlippageExceeded { .. } => 37,
+            ContractError::SubMsgFailed { .. } => 38,
+            ContractError::NotOnAllowList => 39,
+            ContractError::CannotLowerGas => 40,
+            ContractError::Cw20ActionNotSupported { .. } => 41,
+            ContractError::ConnectionNotAllowed { .. } => 42,
+            ContractError::NotTransferChannel { .. } => 43,
+            ContractError::ChannelOtherConnection { .. } => 44,
+            ContractError::ActionRequiresTokens { .. } => 45,
+            ContractError::NoRefund { .. } => 46,
+            ContractError::ChannelClosed { .. } => 47,
+            ContractError::ChannelNotClosed { .. } => 48,
+            ContractError::ChannelMigrated { .. } => 49,
+            ContractError::LockupExists { .. } => 50,
+            ContractError::ForwardWithReturnTo { .. } => 51,
+            ContractError::NoSuchForward { .. } => 52,
+            ContractError::InvalidSplitRoutes { .. } => 53,
+            ContractError::SplitRouteDenoms { .. } => 54,
+            ContractError::SplitSwapMinOut { .. } => 55,
+            ContractError::MigrateAdminRequired { .. } => 56,
+            ContractError::ActionPortNotPaired { .. } => 57,
+            ContractError::NoForward { .. } => 58,
+            ContractError::ForwardNotExpired { .. } => 59,
+            ContractError::ChannelMismatch { .. } => 60,
+        }
+    }
 }
 
 impl From<FromUtf8Error> for ContractError {
@@ -93,6 +278,12 @@ impl From<FromUtf8Error> for ContractError {
     }
 }
 
+impl From<semver::Error> for ContractError {
+    fn from(err: semver::Error) -> Self {
+        Self::SemVer(err.to_string())
+    }
+}
+
 impl From<TryFromIntError> for ContractError {
     fn from(_: TryFromIntError) -> Self {
         ContractError::AmountOverflow {}
@@ -1,35 +1,62 @@
+use std::str::FromStr;
+
 use cosmwasm_std::{
-    attr, coins, entry_point, from_binary, to_binary, BankMsg, Binary, Coin, CosmosMsg, DepsMut,
-    Env, IbcBasicResponse, IbcChannel, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcChannelOpenMsg,
-    IbcEndpoint, IbcOrder, IbcPacket, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg,
-    IbcReceiveResponse, Reply, Response, SubMsg, SubMsgResult, WasmMsg,
+    attr, coins, entry_point, from_binary, from_slice, to_binary, to_vec, BankMsg, Binary, Coin,
+    CosmosMsg, Decimal, Deps, DepsMut, Env, IbcBasicResponse, IbcChannel, IbcChannelCloseMsg,
+    IbcChannelConnectMsg, IbcChannelOpenMsg, IbcEndpoint, IbcMsg, IbcOrder, IbcPacket,
+    IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcReceiveResponse, MessageInfo,
+    Reply, ReplyOn, Response, StdError, StdResult, Storage, SubMsg, SubMsgResult, Timestamp,
+    Uint128, WasmMsg,
 };
 
 use crate::amount::Amount;
 use crate::error::{ContractError, Never};
 use crate::ibc_msg::{
-    AmountResultAck, ClaimPacket, ExitPoolPacket, Ics20Ack, Ics20Packet, JoinPoolPacket,
-    LockPacket, LockupAck, OsmoPacket, SwapPacket, UnlockPacket, Voucher,
+    ActionPacket, AmountResultAck, ClaimPacket, ErrorAck, ExitPoolExactOutPacket, ExitPoolPacket,
+    ExtendLockPacket, ExtendLockResultAck, Forward, Ics20Ack, Ics20Packet, JoinPoolExactOutPacket,
+    JoinPoolPacket, LockPacket, LockResultAck, LockupAck, MultiExitPoolPacket, MultiJoinPoolPacket,
+    OsmoPacket, PoolResultAck, SplitSwapPacket, SplitSwapResultAck, SwapExactOutPacket, SwapPacket,
+    UnlockResultAck, Voucher, WithdrawPacket,
 };
-use crate::msg::{LockupExecuteMsg, LockupInitMsg};
+use crate::msg::{ExecuteMsg, LockupExecuteMsg, LockupInitMsg};
 use crate::parse::{
-    parse_gamm_result, parse_pool_id, GammResult, EXIT_POOL_ATTR, EXIT_POOL_EVENT, JOIN_POOL_ATTR,
-    JOIN_POOL_EVENT, SWAP_ATTR, SWAP_EVENT,
+    parse_exact_out_result, parse_gamm_result, parse_last_output, parse_pool_coins, parse_pool_id,
+    pool_denom, GammResult, EXIT_POOL_ATTR, EXIT_POOL_EVENT, JOIN_POOL_ATTR, JOIN_POOL_EVENT,
+    MULTI_EXIT_POOL_ATTR, MULTI_EXIT_POOL_EVENT, MULTI_JOIN_POOL_ATTR, MULTI_JOIN_POOL_EVENT,
+    SWAP_ATTR, SWAP_EVENT,
 };
 use crate::state::{
-    increase_channel_balance, reduce_channel_balance, restore_balance_reply, ChannelInfo,
-    ReplyArgs, CHANNEL_INFO, CONFIG, LOCKUP, REPLY_ARGS,
+    add_packet_output, balance_channel, consume_inflow, consume_outflow, credit_reply_output,
+    ensure_channel_open, finish_packet_record, increase_channel_balance, increase_collected_fee,
+    increase_pending_refund, increase_user_balance, reduce_channel_balance,
+    reduce_channel_balances, reduce_user_balance, reduce_user_balances, refund_reply_input,
+    restore_balance_reply, save_lockup, save_packet_record, save_pending_op, undo_inflow,
+    undo_outflow, undo_reduce_channel_balance, ChannelInfo, ChannelStatus, ForwardRecord,
+    PacketRecord, PacketStatus, ReplyArgs, SplitSwapState, ALLOWED_CONNECTIONS, ALLOW_LIST,
+    CHANNEL_ACTION_PORT, CHANNEL_INFO, CHANNEL_MAX_SLIPPAGE, CHANNEL_STATUS, CONFIG, FEES,
+    FORWARDS, FORWARD_PACKETS, LOCKUP, PENDING_OPS, POOL_ALLOW_LIST, SPLIT_SWAPS,
 };
+use cw20::Cw20ExecuteMsg;
+use cw_osmo_proto::ibc::applications::transfer::v1::{MsgTransfer, MsgTransferResponse};
 use cw_osmo_proto::osmosis::gamm::v1beta1::{
+    MsgExitSwapExternAmountOutResponse as ExitOutResponse,
     MsgExitSwapShareAmountInResponse as ExitResponse,
     MsgJoinSwapExternAmountInResponse as JoinResponse,
-    MsgSwapExactAmountInResponse as SwapResponse,
+    MsgJoinSwapShareAmountOutResponse as JoinOutResponse,
+    MsgSwapExactAmountInResponse as SwapResponse, MsgSwapExactAmountOutResponse as SwapOutResponse,
+    QuerySpotPriceRequest, QuerySpotPriceResponse, QuerySwapExactAmountInRequest,
+    QuerySwapExactAmountInResponse,
 };
-use cw_osmo_proto::proto_ext::MessageExt;
+use cw_osmo_proto::proto_ext::{proto_decode, MessageExt};
+use cw_osmo_proto::query::query_proto;
 use cw_utils::{parse_execute_response_data, parse_reply_instantiate_data};
 
 pub const ICS20_VERSION: &str = "ics20-1";
+/// version of the channels that carry actions without tokens
+pub const ACTION_VERSION: &str = "osmo-action-1";
 pub const ICS20_ORDERING: IbcOrder = IbcOrder::Unordered;
+/// port of the Osmosis transfer module, used to forward the action outputs
+pub const TRANSFER_PORT: &str = "transfer";
 
 // create a serialized success message
 fn ack_success_with_body(data: Binary) -> Binary {
@@ -43,89 +70,461 @@ fn ack_success() -> Binary {
     to_binary(&res).unwrap()
 }
 
-// create a serialized error message
-fn ack_fail(err: String) -> Binary {
-    let res = Ics20Ack::Error(err);
+// create a serialized error message, the error string is an ErrorAck in JSON
+fn ack_fail(err: ContractError, action: Option<&str>) -> Binary {
+    let res = Ics20Ack::Error(error_ack_msg(&err, action));
     to_binary(&res).unwrap()
 }
 
+// error message of a failed submessage
+fn ack_submsg_fail(err: String, action: Option<&str>) -> Binary {
+    ack_fail(ContractError::SubMsgFailed { msg: err }, action)
+}
+
+// make public for ibc tests
+pub fn error_ack_msg(err: &ContractError, action: Option<&str>) -> String {
+    let ack = ErrorAck {
+        code: err.code(),
+        message: err.to_string(),
+        action: action.map(String::from),
+    };
+    String::from_utf8(to_vec(&ack).unwrap()).unwrap()
+}
+
 const RECEIVE_ID: u64 = 1337;
 const SWAP_ID: u64 = 0xcb37;
+const SWAP_OUT_ID: u64 = 0xcb38;
 const JOIN_POOL_ID: u64 = 0xad54;
 const EXIT_POOL_ID: u64 = 0xfa61;
+const MULTI_JOIN_POOL_ID: u64 = 0xad55;
+const MULTI_EXIT_POOL_ID: u64 = 0xfa62;
+const JOIN_POOL_OUT_ID: u64 = 0xad56;
+const EXIT_POOL_OUT_ID: u64 = 0xfa63;
 const ACK_FAILURE_ID: u64 = 0xfa17;
 const LOCKUP_ID: u64 = 0xdf16;
 const LOCK_TOKEN_ID: u64 = 0xbc42;
 const CLAIM_TOKEN_ID: u64 = 0x1654;
 const UNLOCK_TOKEN_ID: u64 = 0x6f11;
+const UNLOCK_ALL_ID: u64 = 0x6f13;
+const EXTEND_LOCK_ID: u64 = 0x6f12;
+const FORWARD_ID: u64 = 0xf0a1;
+const SPLIT_SWAP_ID: u64 = 0xcb39;
+const SPLIT_ROUTE_ID: u64 = 0xcb3a;
+
+// the pending operation id is carried in the upper bits of the reply id
+const REPLY_KIND_BITS: u64 = 16;
+
+// each route of a split swap is a submessage, keep the number of them bounded
+const MAX_SPLIT_ROUTES: usize = 10;
+
+fn op_reply_id(op_id: u64, kind: u64) -> u64 {
+    (op_id << REPLY_KIND_BITS) | kind
+}
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn reply(deps: DepsMut, _env: Env, reply: Reply) -> Result<Response, ContractError> {
-    match reply.id {
-        RECEIVE_ID => reply_receive(deps, reply),
-        SWAP_ID => reply_gamm_result::<SwapResponse>(deps, reply, SWAP_EVENT, SWAP_ATTR),
-        JOIN_POOL_ID => {
-            reply_gamm_result::<JoinResponse>(deps, reply, JOIN_POOL_EVENT, JOIN_POOL_ATTR)
-        }
-        EXIT_POOL_ID => {
-            reply_gamm_result::<ExitResponse>(deps, reply, EXIT_POOL_EVENT, EXIT_POOL_ATTR)
-        }
-        LOCKUP_ID => reply_lockup_account(deps, reply),
-        LOCK_TOKEN_ID => reply_ack_from_data(deps, reply),
-        CLAIM_TOKEN_ID => reply_claim_result(deps, reply),
-        UNLOCK_TOKEN_ID => reply_ack_on_error(reply),
-        ACK_FAILURE_ID => reply_ack_on_error(reply),
+pub fn reply(mut deps: DepsMut, env: Env, reply: Reply) -> Result<Response, ContractError> {
+    let op_id = reply.id >> REPLY_KIND_BITS;
+    let kind = reply.id & ((1 << REPLY_KIND_BITS) - 1);
+    let res = match kind {
+        RECEIVE_ID => reply_receive(deps.branch(), op_id, reply),
+        SWAP_ID => reply_gamm_result::<SwapResponse>(
+            deps.branch(),
+            env,
+            op_id,
+            reply,
+            SWAP_EVENT,
+            SWAP_ATTR,
+            "swap",
+        ),
+        SWAP_OUT_ID => {
+            reply_exact_out_result::<SwapOutResponse>(deps.branch(), op_id, reply, "swap_exact_out")
+        }
+        JOIN_POOL_ID => reply_gamm_result::<JoinResponse>(
+            deps.branch(),
+            env,
+            op_id,
+            reply,
+            JOIN_POOL_EVENT,
+            JOIN_POOL_ATTR,
+            "join_pool",
+        ),
+        EXIT_POOL_ID => reply_gamm_result::<ExitResponse>(
+            deps.branch(),
+            env,
+            op_id,
+            reply,
+            EXIT_POOL_EVENT,
+            EXIT_POOL_ATTR,
+            "exit_pool",
+        ),
+        JOIN_POOL_OUT_ID => reply_exact_out_result::<JoinOutResponse>(
+            deps.branch(),
+            op_id,
+            reply,
+            "join_pool_exact_out",
+        ),
+        EXIT_POOL_OUT_ID => reply_exact_out_result::<ExitOutResponse>(
+            deps.branch(),
+            op_id,
+            reply,
+            "exit_pool_exact_out",
+        ),
+        MULTI_JOIN_POOL_ID => reply_multi_join_pool(deps.branch(), op_id, reply),
+        MULTI_EXIT_POOL_ID => reply_multi_exit_pool(deps.branch(), op_id, reply),
+        LOCKUP_ID => reply_lockup_account(deps.branch(), op_id, reply),
+        LOCK_TOKEN_ID => reply_ack_from_data(deps.branch(), op_id, reply),
+        EXTEND_LOCK_ID => reply_extend_lock(deps.branch(), op_id, reply),
+        CLAIM_TOKEN_ID => reply_claim_result(deps.branch(), op_id, reply),
+        UNLOCK_TOKEN_ID => reply_unlock(deps.branch(), op_id, reply, "unlock"),
+        UNLOCK_ALL_ID => reply_unlock(deps.branch(), op_id, reply, "unlock_all"),
+        ACK_FAILURE_ID => reply_refund(deps.branch(), op_id, reply),
+        FORWARD_ID => reply_forward(deps.branch(), env, op_id, reply),
+        SPLIT_SWAP_ID => reply_split_swap(deps.branch(), op_id, reply),
+        SPLIT_ROUTE_ID => reply_split_route(deps.branch(), op_id, reply),
         _ => Err(ContractError::UnknownReplyId { id: reply.id }),
+    }?;
+
+    // the routes are part of the split swap, the operation is finished by its reply
+    if kind == SPLIT_ROUTE_ID {
+        return Ok(res);
     }
+
+    // the operation is finished
+    if let Some(reply_args) = PENDING_OPS.may_load(deps.storage, op_id)? {
+        finish_packet_record(deps.storage, &reply_args, reply_status(&res))?;
+    }
+    PENDING_OPS.remove(deps.storage, op_id);
+    Ok(res)
 }
 
 pub fn reply_gamm_result<M: GammResult + cw_osmo_proto::Message + std::default::Default>(
     deps: DepsMut,
+    env: Env,
+    op_id: u64,
     reply: Reply,
     event: &str,
     attribute: &str,
+    action: &str,
 ) -> Result<Response, ContractError> {
     match reply.result {
         SubMsgResult::Ok(tx) => {
             let gamm_res = parse_gamm_result::<M>(tx, event, attribute);
             match gamm_res {
-                Ok(ack) => {
-                    let reply_args = REPLY_ARGS.load(deps.storage)?;
-                    // increase gamm amount out
+                Ok(mut ack) => {
+                    let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+                    // the amount is the output of the last pool, credit the denom of the packet
+                    ack.denom = reply_args
+                        .token_out_denom
+                        .clone()
+                        .ok_or(ContractError::NoReplyData {})?;
+                    ack.fee = fee_coin(&reply_args);
+                    let data = to_binary(&ack).unwrap();
+                    let res = Response::new().set_data(ack_success_with_body(data));
+                    if let Some(forward) = &reply_args.forward {
+                        add_packet_output(deps.storage, &reply_args, &ack.denom, ack.amount)?;
+                        let amount = Coin::new(ack.amount.u128(), ack.denom);
+                        let submsg = forward_output(deps.storage, &env, &reply_args, amount)?;
+                        return Ok(res
+                            .add_submessage(submsg)
+                            .add_attribute("action", "forward")
+                            .add_attribute("forward_channel", &forward.channel)
+                            .add_attribute("receiver", &forward.receiver));
+                    }
+                    if !reply_args.return_to {
+                        // increase gamm amount out
+                        credit_reply_output(deps.storage, &reply_args, &ack.denom, ack.amount)?;
+                        return Ok(res);
+                    }
+                    // over the rate limit quota the output stays in the remote sender balance
+                    if consume_inflow(
+                        deps.storage,
+                        env.block.time,
+                        &reply_args.channel,
+                        &ack.denom,
+                        ack.amount,
+                    )
+                    .is_err()
+                    {
+                        credit_reply_output(deps.storage, &reply_args, &ack.denom, ack.amount)?;
+                        return Ok(res.add_attribute("return_to", "rate_limited"));
+                    }
+
+                    // the returned amount is sent over the channel, only the channel balance is increased
                     increase_channel_balance(
                         deps.storage,
                         &reply_args.channel,
                         &ack.denom,
                         ack.amount,
                     )?;
+                    add_packet_output(deps.storage, &reply_args, &ack.denom, ack.amount)?;
+                    let amount = Amount::from_parts(ack.denom, ack.amount);
+                    let msg = return_amount(
+                        deps.as_ref(),
+                        env,
+                        &reply_args.channel,
+                        &reply_args.sender,
+                        amount,
+                    )?;
+                    Ok(res
+                        .add_message(msg)
+                        .add_attribute("action", "return_to")
+                        .add_attribute("receiver", reply_args.sender))
+                }
+                Err(err) => {
+                    restore_balance_reply(deps.storage, op_id)?;
+                    Ok(Response::new().set_data(ack_fail(err, Some(action))))
+                }
+            }
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some(action))))
+        }
+    }
+}
+
+// the outputs of the routes were added up by the route replies
+pub fn reply_split_swap(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(_) => {
+            let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+            let state = SPLIT_SWAPS.load(deps.storage, op_id)?;
+            SPLIT_SWAPS.remove(deps.storage, op_id);
+
+            let amount: Uint128 = state.outputs.iter().sum();
+            credit_reply_output(deps.storage, &reply_args, &state.denom, amount)?;
+            let ack = SplitSwapResultAck {
+                amount,
+                denom: state.denom,
+                route_amounts: state.outputs,
+                fee: fee_coin(&reply_args),
+            };
+            let data = to_binary(&ack)?;
+            Ok(Response::new().set_data(ack_success_with_body(data)))
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("split_swap"))))
+        }
+    }
+}
+
+// save the output of one route, the last one checks the min output of the split swap.
+// An error reverts all the routes
+pub fn reply_split_route(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    let tx = match reply.result {
+        SubMsgResult::Ok(tx) => tx,
+        SubMsgResult::Err(err) => return Err(StdError::generic_err(err).into()),
+    };
+    // a multi-hop route emits an event per pool, the last one has the route output
+    let output = parse_last_output(tx, SWAP_EVENT, SWAP_ATTR)?;
+
+    let mut state = SPLIT_SWAPS.load(deps.storage, op_id)?;
+    if output.denom != state.denom {
+        return Err(ContractError::SplitRouteDenoms {});
+    }
+    state.outputs.push(output.amount);
+    if state.outputs.len() as u64 == state.routes {
+        let amount: Uint128 = state.outputs.iter().sum();
+        if amount < state.token_out_min_amount {
+            return Err(ContractError::SplitSwapMinOut {
+                amount,
+                min: state.token_out_min_amount,
+            });
+        }
+    }
+    SPLIT_SWAPS.save(deps.storage, op_id, &state)?;
+
+    Ok(Response::new())
+}
+
+pub fn reply_exact_out_result<M: GammResult + cw_osmo_proto::Message + std::default::Default>(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+    action: &str,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+            let token_out = reply_args
+                .token_out
+                .clone()
+                .ok_or(ContractError::NoReplyData {})?;
+            match parse_exact_out_result::<M>(tx, &reply_args.denom, token_out) {
+                Ok(mut ack) => {
+                    // refund unspent input
+                    let refund = reply_args
+                        .amount
+                        .checked_sub(ack.token_in.amount)
+                        .map_err(StdError::overflow)?;
+                    if !refund.is_zero() {
+                        refund_reply_input(deps.storage, &reply_args, &reply_args.denom, refund)?;
+                    }
+                    credit_reply_output(
+                        deps.storage,
+                        &reply_args,
+                        &ack.token_out.denom,
+                        ack.token_out.amount,
+                    )?;
+                    ack.fee = fee_coin(&reply_args);
+                    let data = to_binary(&ack).unwrap();
+                    Ok(Response::new().set_data(ack_success_with_body(data)))
+                }
+                Err(err) => {
+                    restore_balance_reply(deps.storage, op_id)?;
+                    Ok(Response::new().set_data(ack_fail(err, Some(action))))
+                }
+            }
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some(action))))
+        }
+    }
+}
+
+pub fn reply_multi_join_pool(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let gamm_res =
+                parse_pool_coins(tx.clone(), MULTI_JOIN_POOL_EVENT, MULTI_JOIN_POOL_ATTR).and_then(
+                    |tokens_in| {
+                        let shares = parse_pool_coins(tx, JOIN_POOL_EVENT, JOIN_POOL_ATTR)?;
+                        Ok((tokens_in, shares))
+                    },
+                );
+            match gamm_res {
+                Ok((tokens_in, tokens_out)) => {
+                    let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+                    // refund unspent inputs
+                    let mut funds = coins(reply_args.amount.u128(), &reply_args.denom);
+                    funds.extend(reply_args.reserved.clone());
+                    for fund in funds {
+                        let spent = tokens_in
+                            .iter()
+                            .find(|c| c.denom == fund.denom)
+                            .map(|c| c.amount)
+                            .unwrap_or_default();
+                        let refund = fund.amount.checked_sub(spent).map_err(StdError::overflow)?;
+                        if !refund.is_zero() {
+                            refund_reply_input(deps.storage, &reply_args, &fund.denom, refund)?;
+                        }
+                    }
+                    for token in tokens_out.iter() {
+                        credit_reply_output(deps.storage, &reply_args, &token.denom, token.amount)?;
+                    }
+
+                    let ack = PoolResultAck {
+                        tokens_in,
+                        tokens_out,
+                        fee: fee_coin(&reply_args),
+                    };
+                    let data = to_binary(&ack).unwrap();
+                    Ok(Response::new().set_data(ack_success_with_body(data)))
+                }
+                Err(err) => {
+                    restore_balance_reply(deps.storage, op_id)?;
+                    Ok(Response::new().set_data(ack_fail(err, Some("multi_join_pool"))))
+                }
+            }
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("multi_join_pool"))))
+        }
+    }
+}
+
+pub fn reply_multi_exit_pool(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            match parse_pool_coins(tx, MULTI_EXIT_POOL_EVENT, MULTI_EXIT_POOL_ATTR) {
+                Ok(tokens_out) => {
+                    let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+                    for token in tokens_out.iter() {
+                        credit_reply_output(deps.storage, &reply_args, &token.denom, token.amount)?;
+                    }
+
+                    let ack = PoolResultAck {
+                        tokens_in: coins(reply_args.amount.u128(), &reply_args.denom),
+                        tokens_out,
+                        fee: fee_coin(&reply_args),
+                    };
                     let data = to_binary(&ack).unwrap();
                     Ok(Response::new().set_data(ack_success_with_body(data)))
                 }
                 Err(err) => {
-                    restore_balance_reply(deps.storage)?;
-                    Ok(Response::new().set_data(ack_fail(err.to_string())))
+                    restore_balance_reply(deps.storage, op_id)?;
+                    Ok(Response::new().set_data(ack_fail(err, Some("multi_exit_pool"))))
                 }
             }
         }
         SubMsgResult::Err(err) => {
-            restore_balance_reply(deps.storage)?;
-            Ok(Response::new().set_data(ack_fail(err)))
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("multi_exit_pool"))))
         }
     }
 }
 
-pub fn reply_lockup_account(deps: DepsMut, reply: Reply) -> Result<Response, ContractError> {
+// send the action output to the remote sender, the channel balance must be already increased
+fn return_amount(
+    deps: Deps,
+    env: Env,
+    channel: &str,
+    receiver: &str,
+    amount: Amount,
+) -> Result<IbcMsg, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let timeout = env.block.time.plus_seconds(config.default_timeout);
+
+    let packet = Ics20Packet::new(
+        amount.amount(),
+        amount.denom(),
+        env.contract.address.as_str(),
+        receiver,
+    );
+
+    Ok(IbcMsg::SendPacket {
+        channel_id: channel.to_string(),
+        data: to_binary(&packet)?,
+        timeout: timeout.into(),
+    })
+}
+
+pub fn reply_lockup_account(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
     match reply.result.clone() {
         SubMsgResult::Ok(_) => {
             let res = parse_reply_instantiate_data(reply);
 
             match res {
                 Ok(data) => {
-                    let reply_args = REPLY_ARGS.load(deps.storage)?;
+                    let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
 
-                    LOCKUP.save(
+                    save_lockup(
                         deps.storage,
-                        (&reply_args.channel, &reply_args.sender),
+                        &reply_args.channel,
+                        &reply_args.sender,
                         &data.contract_address,
                     )?;
                     let ack = LockupAck {
@@ -135,14 +534,22 @@ pub fn reply_lockup_account(deps: DepsMut, reply: Reply) -> Result<Response, Con
 
                     Ok(Response::new().set_data(ack_success_with_body(data)))
                 }
-                Err(err) => Ok(Response::new().set_data(ack_fail(err.to_string()))),
+                Err(err) => {
+                    Ok(Response::new().set_data(ack_fail(err.into(), Some("lockup_account"))))
+                }
             }
         }
-        SubMsgResult::Err(err) => Ok(Response::new().set_data(ack_fail(err))),
+        SubMsgResult::Err(err) => {
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("lockup_account"))))
+        }
     }
 }
 
-pub fn reply_claim_result(deps: DepsMut, reply: Reply) -> Result<Response, ContractError> {
+pub fn reply_claim_result(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
     match reply.result {
         SubMsgResult::Ok(tx) => {
             let data = tx.data.ok_or(ContractError::MissingReplyData {})?;
@@ -151,29 +558,86 @@ pub fn reply_claim_result(deps: DepsMut, reply: Reply) -> Result<Response, Contr
                 .ok_or(ContractError::MissingReplyData {})?;
 
             let token: Coin = from_binary(&data)?;
-            let reply_args = REPLY_ARGS.load(deps.storage)?;
-            increase_channel_balance(
-                deps.storage,
-                &reply_args.channel,
-                &token.denom,
-                token.amount,
-            )?;
+            let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+            credit_reply_output(deps.storage, &reply_args, &token.denom, token.amount)?;
 
             let ack = AmountResultAck {
                 denom: token.denom,
                 amount: token.amount,
+                fee: None,
+            };
+            let data = to_binary(&ack).unwrap();
+            Ok(Response::new().set_data(ack_success_with_body(data)))
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("claim"))))
+        }
+    }
+}
+
+pub fn reply_ack_from_data(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let data = tx.data.ok_or(ContractError::MissingReplyData {})?;
+            let data = parse_execute_response_data(data.as_slice())?
+                .data
+                .ok_or(ContractError::MissingReplyData {})?;
+
+            // add the fee to the lockup result
+            let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+            let data = match fee_coin(&reply_args) {
+                Some(fee) => {
+                    let mut ack: LockResultAck = from_binary(&data)?;
+                    ack.fee = Some(fee);
+                    to_binary(&ack)?
+                }
+                None => data,
             };
+
+            Ok(Response::new().set_data(ack_success_with_body(data)))
+        }
+        SubMsgResult::Err(err) => {
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("lock"))))
+        }
+    }
+}
+
+pub fn reply_extend_lock(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let data = tx.data.ok_or(ContractError::MissingReplyData {})?;
+            let data = parse_execute_response_data(data.as_slice())?
+                .data
+                .ok_or(ContractError::MissingReplyData {})?;
+
+            // the lockup contract confirms the new duration
+            let ack: ExtendLockResultAck = from_binary(&data)?;
             let data = to_binary(&ack).unwrap();
             Ok(Response::new().set_data(ack_success_with_body(data)))
         }
         SubMsgResult::Err(err) => {
-            restore_balance_reply(deps.storage)?;
-            Ok(Response::new().set_data(ack_fail(err)))
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some("extend_lock"))))
         }
     }
 }
 
-pub fn reply_ack_from_data(deps: DepsMut, reply: Reply) -> Result<Response, ContractError> {
+pub fn reply_unlock(
+    deps: DepsMut,
+    op_id: u64,
+    reply: Reply,
+    action: &str,
+) -> Result<Response, ContractError> {
     match reply.result {
         SubMsgResult::Ok(tx) => {
             let data = tx.data.ok_or(ContractError::MissingReplyData {})?;
@@ -181,40 +645,173 @@ pub fn reply_ack_from_data(deps: DepsMut, reply: Reply) -> Result<Response, Cont
                 .data
                 .ok_or(ContractError::MissingReplyData {})?;
 
+            // the lockup contract reports the lock ids and end times
+            let ack: UnlockResultAck = from_binary(&data)?;
+            let data = to_binary(&ack).unwrap();
             Ok(Response::new().set_data(ack_success_with_body(data)))
         }
         SubMsgResult::Err(err) => {
-            restore_balance_reply(deps.storage)?;
-            Ok(Response::new().set_data(ack_fail(err)))
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, Some(action))))
+        }
+    }
+}
+
+// send the action output with the transfer module, the reply credits it back to the remote
+// sender if the transfer can't be sent
+fn forward_output(
+    storage: &mut dyn Storage,
+    env: &Env,
+    reply_args: &ReplyArgs,
+    amount: Coin,
+) -> Result<SubMsg, ContractError> {
+    let forward = reply_args
+        .forward
+        .clone()
+        .ok_or(ContractError::NoForward {})?;
+    let timeout = forward_timeout(storage, env, &forward)?;
+
+    let forward_args = ReplyArgs {
+        channel: reply_args.channel.clone(),
+        denom: amount.denom.clone(),
+        amount: amount.amount,
+        sender: reply_args.sender.clone(),
+        return_to: false,
+        reserved: vec![],
+        fee: Uint128::zero(),
+        sequence: None,
+        packet_channel: None,
+        forward: Some(forward.clone()),
+        token_out: None,
+        token_out_denom: None,
+    };
+    let op_id = save_pending_op(storage, &forward_args)?;
+
+    // ibc-hooks calls sudo with the ack or the timeout of the transfer
+    let msg = MsgTransfer {
+        source_port: TRANSFER_PORT.to_string(),
+        source_channel: forward.channel,
+        token: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+            denom: amount.denom,
+            amount: amount.amount.to_string(),
+        }),
+        sender: env.contract.address.to_string(),
+        receiver: forward.receiver,
+        timeout_height: None,
+        timeout_timestamp: timeout.nanos(),
+        memo: format!(r#"{{"ibc_callback":"{}"}}"#, env.contract.address),
+    };
+    Ok(SubMsg::reply_always(
+        msg.to_msg()?,
+        op_reply_id(op_id, FORWARD_ID),
+    ))
+}
+
+// the reply is in the same block as the transfer, it gets the same timeout
+fn forward_timeout(storage: &dyn Storage, env: &Env, forward: &Forward) -> StdResult<Timestamp> {
+    // delta from user is in seconds
+    let timeout_delta = match forward.timeout {
+        Some(t) => t,
+        None => CONFIG.load(storage)?.default_timeout,
+    };
+    Ok(env.block.time.plus_seconds(timeout_delta))
+}
+
+// the sent transfers are kept until their callback, the ones that can't be sent are credited
+// back to the remote sender
+pub fn reply_forward(
+    deps: DepsMut,
+    env: Env,
+    op_id: u64,
+    reply: Reply,
+) -> Result<Response, ContractError> {
+    let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+    let forward = reply_args
+        .forward
+        .clone()
+        .ok_or(ContractError::NoForward {})?;
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let data = tx.data.ok_or(ContractError::NoReplyData {})?;
+            let response: MsgTransferResponse = proto_decode(data.as_slice())?;
+            let timeout = forward_timeout(deps.storage, &env, &forward)?;
+            let record = ForwardRecord {
+                channel: reply_args.channel,
+                sender: reply_args.sender,
+                amount: Coin::new(reply_args.amount.u128(), reply_args.denom),
+                forward_channel: forward.channel,
+                receiver: forward.receiver,
+                timeout,
+                sequence: response.sequence,
+            };
+            FORWARDS.save(deps.storage, op_id, &record)?;
+            FORWARD_PACKETS.save(
+                deps.storage,
+                (&record.forward_channel, record.sequence),
+                &op_id,
+            )?;
+            Ok(Response::new().add_attribute("forward_id", op_id.to_string()))
+        }
+        SubMsgResult::Err(err) => {
+            credit_reply_output(
+                deps.storage,
+                &reply_args,
+                &reply_args.denom,
+                reply_args.amount,
+            )?;
+            let res = Response::new()
+                .add_attribute("action", "forward_failed")
+                .add_attribute("receiver", reply_args.sender)
+                .add_attribute("denom", reply_args.denom)
+                .add_attribute("amount", reply_args.amount)
+                .add_attribute("error", err);
+            Ok(res)
         }
     }
 }
 
-pub fn reply_ack_on_error(reply: Reply) -> Result<Response, ContractError> {
+// a refund that can't be sent back is recorded, the recipient can claim it later
+pub fn reply_refund(deps: DepsMut, op_id: u64, reply: Reply) -> Result<Response, ContractError> {
     match reply.result {
         SubMsgResult::Ok(_) => Ok(Response::new()),
-        SubMsgResult::Err(err) => Ok(Response::new().set_data(ack_fail(err))),
+        SubMsgResult::Err(err) => {
+            let reply_args = PENDING_OPS.load(deps.storage, op_id)?;
+            increase_pending_refund(
+                deps.storage,
+                &reply_args.sender,
+                &reply_args.denom,
+                reply_args.amount,
+            )?;
+            let res = Response::new()
+                .add_attribute("action", "pending_refund")
+                .add_attribute("recipient", reply_args.sender)
+                .add_attribute("denom", reply_args.denom)
+                .add_attribute("amount", reply_args.amount)
+                .add_attribute("error", err);
+            Ok(res)
+        }
     }
 }
 
-pub fn reply_receive(deps: DepsMut, reply: Reply) -> Result<Response, ContractError> {
+pub fn reply_receive(deps: DepsMut, op_id: u64, reply: Reply) -> Result<Response, ContractError> {
     match reply.result {
         SubMsgResult::Ok(_) => Ok(Response::new()),
         SubMsgResult::Err(err) => {
-            restore_balance_reply(deps.storage)?;
-            Ok(Response::new().set_data(ack_fail(err)))
+            restore_balance_reply(deps.storage, op_id)?;
+            Ok(Response::new().set_data(ack_submsg_fail(err, None)))
         }
     }
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-/// enforces ordering and versioning constraints
+/// enforces ordering and versioning constraints, and the connection allowlist
 pub fn ibc_channel_open(
-    _deps: DepsMut,
+    deps: DepsMut,
     _env: Env,
     msg: IbcChannelOpenMsg,
 ) -> Result<(), ContractError> {
     enforce_order_and_version(msg.channel(), msg.counterparty_version())?;
+    enforce_allowed_connection(deps.storage, msg.channel())?;
 
     Ok(())
 }
@@ -228,12 +825,14 @@ pub fn ibc_channel_connect(
 ) -> Result<IbcBasicResponse, ContractError> {
     // we need to check the counter party version in try and ack (sometimes here)
     enforce_order_and_version(msg.channel(), msg.counterparty_version())?;
+    enforce_allowed_connection(deps.storage, msg.channel())?;
 
     let channel: IbcChannel = msg.into();
     let info = ChannelInfo {
         id: channel.endpoint.channel_id,
         counterparty_endpoint: channel.counterparty_endpoint,
         connection_id: channel.connection_id,
+        version: channel.version,
     };
     CHANNEL_INFO.save(deps.storage, &info.id, &info)?;
 
@@ -244,13 +843,13 @@ fn enforce_order_and_version(
     channel: &IbcChannel,
     counterparty_version: Option<&str>,
 ) -> Result<(), ContractError> {
-    if channel.version.as_str() != ICS20_VERSION {
+    if channel.version.as_str() != ICS20_VERSION && channel.version.as_str() != ACTION_VERSION {
         return Err(ContractError::InvalidIbcVersion {
             version: channel.version.clone(),
         });
     }
     if let Some(version) = counterparty_version {
-        if version != ICS20_VERSION {
+        if version != channel.version {
             return Err(ContractError::InvalidIbcVersion {
                 version: version.to_string(),
             });
@@ -262,14 +861,37 @@ fn enforce_order_and_version(
     Ok(())
 }
 
+// the connection may be revoked between the open and the connect steps
+fn enforce_allowed_connection(
+    storage: &dyn Storage,
+    channel: &IbcChannel,
+) -> Result<(), ContractError> {
+    let port_id = channel.counterparty_endpoint.port_id.as_str();
+    if !ALLOWED_CONNECTIONS.has(storage, (&channel.connection_id, port_id)) {
+        return Err(ContractError::ConnectionNotAllowed {
+            connection_id: channel.connection_id.clone(),
+            port_id: port_id.to_string(),
+        });
+    }
+    Ok(())
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn ibc_channel_close(
-    _deps: DepsMut,
+    deps: DepsMut,
     _env: Env,
     channel: IbcChannelCloseMsg,
 ) -> Result<IbcBasicResponse, ContractError> {
     match channel {
-        IbcChannelCloseMsg::CloseConfirm { .. } => Ok(IbcBasicResponse::new()),
+        // closed by the counterparty, freeze the channel until the admin migrates it
+        IbcChannelCloseMsg::CloseConfirm { channel } => {
+            let id = channel.endpoint.channel_id;
+            CHANNEL_STATUS.save(deps.storage, &id, &ChannelStatus::Closed)?;
+            let res = IbcBasicResponse::new()
+                .add_attribute("action", "close_channel")
+                .add_attribute("channel", id);
+            Ok(res)
+        }
         IbcChannelCloseMsg::CloseInit { .. } => Err(ContractError::CannotClose {}),
         _ => panic!(),
     }
@@ -277,23 +899,100 @@ pub fn ibc_channel_close(
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn ibc_packet_receive(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     msg: IbcPacketReceiveMsg,
 ) -> Result<IbcReceiveResponse, Never> {
     let packet = msg.packet;
 
-    do_ibc_packet_receive(deps, env, &packet).or_else(|err| {
+    let mut record = packet_record(&packet, &env);
+    let res = do_ibc_packet_receive(deps.branch(), env, &packet);
+    record.status = match &res {
+        Ok(res) if has_pending_op(deps.storage, res) => PacketStatus::Pending,
+        Ok(_) => PacketStatus::Success,
+        Err(err) => PacketStatus::Failed {
+            code: err.code(),
+            message: err.to_string(),
+        },
+    };
+    // the history is informative only, it can't change the ack
+    let _ = save_packet_record(
+        deps.storage,
+        &packet.dest.channel_id,
+        packet.sequence,
+        &record,
+    );
+
+    res.or_else(|err| {
         Ok(IbcReceiveResponse::new()
-            .set_ack(ack_fail(err.to_string()))
             .add_attributes(vec![
                 attr("action", "receive"),
                 attr("success", "false"),
                 attr("error", err.to_string()),
-            ]))
+                attr("error_code", err.code().to_string()),
+            ])
+            .set_ack(ack_fail(err, record.action.as_deref())))
     })
 }
 
+// history record of the received packet, the fields are empty if the packet can't be parsed
+fn packet_record(packet: &IbcPacket, env: &Env) -> PacketRecord {
+    let data = from_binary::<Ics20Packet>(&packet.data);
+    let (sender, action, denom, amount) = match (data, from_binary::<ActionPacket>(&packet.data)) {
+        (Ok(msg), _) => {
+            let action = msg
+                .osmo_action(env.contract.address.as_str())
+                .ok()
+                .flatten()
+                .map(|action| action.name().to_string());
+            let denom = parse_voucher(msg.denom.clone(), &packet.src)
+                .map(|voucher| voucher.denom)
+                .unwrap_or(msg.denom);
+            (msg.sender, action, denom, msg.amount)
+        }
+        (_, Ok(msg)) => {
+            let action = Some(msg.action.name().to_string());
+            (msg.sender, action, String::new(), Uint128::zero())
+        }
+        _ => (String::new(), None, String::new(), Uint128::zero()),
+    };
+    PacketRecord {
+        sender,
+        action,
+        denom,
+        amount,
+        status: PacketStatus::Pending,
+        tokens_out: vec![],
+        height: env.block.height,
+        time: env.block.time,
+    }
+}
+
+// the packet is pending if the reply of a submessage finishes its operation
+fn has_pending_op(storage: &dyn Storage, res: &IbcReceiveResponse) -> bool {
+    res.messages
+        .iter()
+        .any(|m| m.reply_on != ReplyOn::Never && PENDING_OPS.has(storage, m.id >> REPLY_KIND_BITS))
+}
+
+// the action failed if the reply sets an error ack
+fn reply_status(res: &Response) -> PacketStatus {
+    let ack = res.data.as_ref().and_then(|data| from_binary(data).ok());
+    match ack {
+        Some(Ics20Ack::Error(err)) => match from_slice::<ErrorAck>(err.as_bytes()) {
+            Ok(ack) => PacketStatus::Failed {
+                code: ack.code,
+                message: ack.message,
+            },
+            Err(_) => PacketStatus::Failed {
+                code: 0,
+                message: err,
+            },
+        },
+        _ => PacketStatus::Success,
+    }
+}
+
 // Returns local denom if the denom is an encoded voucher from the expected endpoint
 // Otherwise, error
 fn parse_voucher(
@@ -323,59 +1022,107 @@ fn parse_voucher(
 
 // this does the work of ibc_packet_receive, we wrap it to turn errors into acknowledgements
 fn do_ibc_packet_receive(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     packet: &IbcPacket,
 ) -> Result<IbcReceiveResponse, ContractError> {
+    let info = CHANNEL_INFO.load(deps.storage, &packet.dest.channel_id)?;
+    if info.version == ACTION_VERSION {
+        return do_action_packet_receive(deps, env, packet, info);
+    }
+
     let msg: Ics20Packet = from_binary(&packet.data)?;
     let channel = packet.dest.channel_id.clone();
+    let action = msg.osmo_action(env.contract.address.as_str())?;
 
     // If the token originated on the remote chain, it looks like "ucosm".
     // If it originated on our chain, it looks like "port/channel/ucosm".
     let voucher = parse_voucher(msg.denom, &packet.src)?;
     let denom = voucher.denom.as_str();
+    let gas_limit = check_gas_limit(
+        deps.as_ref(),
+        &Amount::from_parts(denom.to_string(), msg.amount),
+    )?;
 
     reduce_channel_balance(deps.storage, &channel, denom, msg.amount)?;
+    if let Err(err) = consume_outflow(deps.storage, env.block.time, &channel, denom, msg.amount) {
+        undo_reduce_channel_balance(deps.storage, &channel, denom, msg.amount)?;
+        return Err(err);
+    }
 
     // we need to save the data to update the balances in reply
+    let (return_to, forward) = match &action {
+        Some(OsmoPacket::Swap(swap)) => (swap.return_to, swap.forward.clone()),
+        Some(OsmoPacket::ExitPool(exit_pool)) => (exit_pool.return_to, exit_pool.forward.clone()),
+        _ => (false, None),
+    };
+    let token_out = match &action {
+        Some(OsmoPacket::SwapExactOut(swap)) => Some(swap.token_out.clone()),
+        Some(OsmoPacket::JoinPoolExactOut(join_pool)) => Some(Coin::new(
+            join_pool.share_out_amount.u128(),
+            pool_denom(join_pool.pool_id.u64()),
+        )),
+        Some(OsmoPacket::ExitPoolExactOut(exit_pool)) => Some(exit_pool.token_out.clone()),
+        _ => None,
+    };
+    let token_out_denom = match &action {
+        Some(OsmoPacket::Swap(swap)) => swap.routes.last().map(|r| r.token_out_denom.clone()),
+        Some(OsmoPacket::JoinPool(join_pool)) => Some(pool_denom(join_pool.pool_id.u64())),
+        Some(OsmoPacket::ExitPool(exit_pool)) => Some(exit_pool.token_out_denom.clone()),
+        _ => None,
+    };
+    let fee = match &action {
+        Some(action) => action_fee(deps.storage, action, msg.amount)?,
+        None => Uint128::zero(),
+    };
+    let amount = msg.amount.checked_sub(fee).map_err(StdError::overflow)?;
     let reply_args = ReplyArgs {
         channel: channel.clone(),
         denom: denom.to_string(),
-        amount: msg.amount,
+        amount,
         sender: msg.sender.clone(),
+        return_to,
+        reserved: vec![],
+        fee,
+        sequence: Some(packet.sequence),
+        packet_channel: None,
+        forward,
+        token_out,
+        token_out_denom,
     };
-    REPLY_ARGS.save(deps.storage, &reply_args)?;
-    let to_send = Amount::from_parts(denom.to_string(), msg.amount);
-
-    if let Some(action) = msg.action {
-        let contract = env.contract.address.into();
-        match action {
-            OsmoPacket::Swap(swap) => swap_receive(swap, msg.sender, to_send, contract),
-            OsmoPacket::JoinPool(join_pool) => {
-                receive_join_pool(join_pool, msg.sender, to_send, contract)
-            }
-            OsmoPacket::ExitPool(exit_pool) => {
-                receive_exit_pool(exit_pool, msg.sender, to_send, contract)
-            }
-            OsmoPacket::LockupAccount {} => {
-                nonpayable(&to_send)?;
-                receive_create_lockup(deps, &channel, msg.sender, contract)
-            }
-            OsmoPacket::Lock(lock) => {
-                receive_lock_tokens(deps, &channel, lock, msg.sender, to_send)
-            }
-            OsmoPacket::Claim(claim) => {
-                nonpayable(&to_send)?;
-                receive_claim_tokens(deps, &channel, claim, msg.sender)
+    let op_id = save_pending_op(deps.storage, &reply_args)?;
+    let to_send = Amount::from_parts(denom.to_string(), amount);
+
+    if let Some(action) = action {
+        match receive_action(
+            deps.branch(),
+            env,
+            op_id,
+            &channel,
+            action,
+            msg.sender,
+            to_send,
+        ) {
+            Ok(res) => {
+                remove_finished_op(deps.storage, op_id, &res);
+                if fee.is_zero() {
+                    return Ok(res);
+                }
+                increase_collected_fee(deps.storage, denom, fee)?;
+                Ok(res.add_attribute("fee", format!("{}{}", fee, denom)))
             }
-            OsmoPacket::Unlock(unlock) => {
-                nonpayable(&to_send)?;
-                receive_unlock_tokens(deps, &channel, unlock, msg.sender)
+            Err(err) => {
+                // the action failed, give back the transferred amount to the channel balance
+                undo_reduce_channel_balance(deps.storage, &channel, denom, msg.amount)?;
+                undo_outflow(deps.storage, &channel, denom, msg.amount)?;
+                PENDING_OPS.remove(deps.storage, op_id);
+                Err(err)
             }
         }
     } else {
         let send = send_amount(to_send, msg.receiver.clone());
-        let submsg = SubMsg::reply_on_error(send, RECEIVE_ID);
+        let mut submsg = SubMsg::reply_always(send, op_reply_id(op_id, RECEIVE_ID));
+        submsg.gas_limit = gas_limit;
 
         let res = IbcReceiveResponse::new()
             .set_ack(ack_success())
@@ -391,20 +1138,296 @@ fn do_ibc_packet_receive(
     }
 }
 
-fn swap_receive(
-    swap: SwapPacket,
-    sender: String,
-    token_in: Amount,
-    contract: String,
+pub fn check_gas_limit(deps: Deps, amount: &Amount) -> Result<Option<u64>, ContractError> {
+    match amount {
+        Amount::Cw20(coin) => {
+            // if cw20 token, use the registered gas limit, or error if not whitelisted
+            let addr = deps.api.addr_validate(&coin.address)?;
+            Ok(ALLOW_LIST
+                .may_load(deps.storage, &addr)?
+                .ok_or(ContractError::NotOnAllowList)?
+                .gas_limit)
+        }
+        _ => Ok(None),
+    }
+}
+
+// the action runs with the lockups and balances of the transfer channel. No tokens prove the remote
+// sender, so the action channel must come from the counterparty port paired by the admin with the
+// transfer channel, other ports of the connection could send actions for any sender
+fn do_action_packet_receive(
+    mut deps: DepsMut,
+    env: Env,
+    packet: &IbcPacket,
+    info: ChannelInfo,
 ) -> Result<IbcReceiveResponse, ContractError> {
-    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgSwapExactAmountIn {
-        sender: contract,
-        token_in: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
-            denom: token_in.denom(),
-            amount: token_in.amount().to_string(),
-        }),
-        routes: swap
-            .routes
+    let msg: ActionPacket = from_binary(&packet.data)?;
+    if !msg.action.is_tokenless() {
+        return Err(ContractError::ActionRequiresTokens {
+            action: msg.action.name().to_string(),
+        });
+    }
+    let transfer = CHANNEL_INFO
+        .may_load(deps.storage, &msg.channel)?
+        .ok_or_else(|| ContractError::NoSuchChannel {
+            id: msg.channel.clone(),
+        })?;
+    if transfer.version != ICS20_VERSION {
+        return Err(ContractError::NotTransferChannel {
+            channel: msg.channel,
+        });
+    }
+    if transfer.connection_id != info.connection_id {
+        return Err(ContractError::ChannelOtherConnection {
+            channel: msg.channel,
+        });
+    }
+    let port_id = info.counterparty_endpoint.port_id.as_str();
+    if CHANNEL_ACTION_PORT
+        .may_load(deps.storage, &msg.channel)?
+        .as_deref()
+        != Some(port_id)
+    {
+        return Err(ContractError::ActionPortNotPaired {
+            channel: msg.channel,
+            port_id: port_id.to_string(),
+        });
+    }
+    ensure_channel_open(deps.storage, &msg.channel)?;
+
+    let reply_args = ReplyArgs {
+        channel: msg.channel.clone(),
+        denom: String::new(),
+        amount: Uint128::zero(),
+        sender: msg.sender.clone(),
+        return_to: false,
+        reserved: vec![],
+        fee: Uint128::zero(),
+        sequence: Some(packet.sequence),
+        packet_channel: Some(info.id),
+        forward: None,
+        token_out: None,
+        token_out_denom: None,
+    };
+    let op_id = save_pending_op(deps.storage, &reply_args)?;
+    let to_send = Amount::from_parts(String::new(), Uint128::zero());
+
+    match receive_action(
+        deps.branch(),
+        env,
+        op_id,
+        &msg.channel,
+        msg.action,
+        msg.sender,
+        to_send,
+    ) {
+        Ok(res) => {
+            remove_finished_op(deps.storage, op_id, &res);
+            Ok(res)
+        }
+        Err(err) => {
+            PENDING_OPS.remove(deps.storage, op_id);
+            Err(err)
+        }
+    }
+}
+
+// no reply will finish the operation
+fn remove_finished_op(storage: &mut dyn Storage, op_id: u64, res: &IbcReceiveResponse) {
+    if !res
+        .messages
+        .iter()
+        .any(|m| m.id >> REPLY_KIND_BITS == op_id)
+    {
+        PENDING_OPS.remove(storage, op_id);
+    }
+}
+
+// protocol fee of the action, taken from the transferred amount
+fn action_fee(
+    storage: &dyn Storage,
+    action: &OsmoPacket,
+    amount: Uint128,
+) -> Result<Uint128, ContractError> {
+    let fees = FEES.may_load(storage)?.unwrap_or_default();
+    let fee_bps = match action {
+        OsmoPacket::Swap(_) | OsmoPacket::SwapExactOut(_) | OsmoPacket::SplitSwap(_) => {
+            fees.swap_bps
+        }
+        OsmoPacket::JoinPool(_)
+        | OsmoPacket::JoinPoolExactOut(_)
+        | OsmoPacket::MultiJoinPool(_) => fees.join_pool_bps,
+        OsmoPacket::ExitPool(_)
+        | OsmoPacket::ExitPoolExactOut(_)
+        | OsmoPacket::MultiExitPool(_) => fees.exit_pool_bps,
+        OsmoPacket::Lock(_) => fees.lock_bps,
+        _ => 0,
+    };
+    Ok(amount.multiply_ratio(fee_bps, 10_000u128))
+}
+
+// the fee reported in the ack, if any was taken
+fn fee_coin(reply_args: &ReplyArgs) -> Option<Coin> {
+    if reply_args.fee.is_zero() {
+        None
+    } else {
+        Some(Coin::new(reply_args.fee.u128(), &reply_args.denom))
+    }
+}
+
+fn receive_action(
+    deps: DepsMut,
+    env: Env,
+    op_id: u64,
+    channel: &str,
+    action: OsmoPacket,
+    sender: String,
+    to_send: Amount,
+) -> Result<IbcReceiveResponse, ContractError> {
+    // the osmosis modules only handle native tokens
+    if matches!(to_send, Amount::Cw20(_)) && !to_send.is_empty() {
+        return Err(ContractError::Cw20ActionNotSupported {});
+    }
+    let contract = env.contract.address.to_string();
+    match action {
+        OsmoPacket::Swap(swap) => swap_receive(
+            deps.as_ref(),
+            swap,
+            op_id,
+            channel,
+            sender,
+            to_send,
+            contract,
+        ),
+        OsmoPacket::SwapExactOut(swap) => {
+            swap_exact_out_receive(deps.as_ref(), swap, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::SplitSwap(split) => split_swap_receive(
+            deps.as_ref(),
+            split,
+            op_id,
+            channel,
+            sender,
+            to_send,
+            contract,
+        ),
+        OsmoPacket::JoinPool(join_pool) => {
+            receive_join_pool(deps.as_ref(), join_pool, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::ExitPool(exit_pool) => {
+            receive_exit_pool(deps.as_ref(), exit_pool, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::JoinPoolExactOut(join_pool) => {
+            receive_join_pool_exact_out(deps.as_ref(), join_pool, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::ExitPoolExactOut(exit_pool) => {
+            receive_exit_pool_exact_out(deps.as_ref(), exit_pool, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::MultiJoinPool(join_pool) => {
+            receive_multi_join_pool(deps, op_id, channel, join_pool, sender, to_send, contract)
+        }
+        OsmoPacket::MultiExitPool(exit_pool) => {
+            receive_multi_exit_pool(deps.as_ref(), exit_pool, op_id, sender, to_send, contract)
+        }
+        OsmoPacket::LockupAccount {} => {
+            nonpayable(&to_send)?;
+            receive_create_lockup(deps, op_id, channel, sender, contract)
+        }
+        OsmoPacket::Lock(lock) => receive_lock_tokens(deps, op_id, channel, lock, sender, to_send),
+        OsmoPacket::Claim(claim) => {
+            nonpayable(&to_send)?;
+            receive_claim_tokens(deps, op_id, channel, claim, sender)
+        }
+        OsmoPacket::Unlock(unlock) => {
+            nonpayable(&to_send)?;
+            let lockup_msg = LockupExecuteMsg::Unlock {
+                id: unlock.id,
+                coins: unlock.coins,
+            };
+            receive_unlock_tokens(deps, op_id, channel, lockup_msg, sender, UNLOCK_TOKEN_ID)
+        }
+        OsmoPacket::UnlockAll {} => {
+            nonpayable(&to_send)?;
+            let lockup_msg = LockupExecuteMsg::UnlockAll {};
+            receive_unlock_tokens(deps, op_id, channel, lockup_msg, sender, UNLOCK_ALL_ID)
+        }
+        OsmoPacket::ExtendLock(extend) => {
+            nonpayable(&to_send)?;
+            receive_extend_lock(deps, op_id, channel, extend, sender)
+        }
+        OsmoPacket::Withdraw(withdraw) => {
+            nonpayable(&to_send)?;
+            receive_withdraw(deps, env, channel, withdraw, sender)
+        }
+    }
+}
+
+// the output goes to one destination
+fn check_forward(return_to: bool, forward: &Option<Forward>) -> Result<(), ContractError> {
+    if return_to && forward.is_some() {
+        return Err(ContractError::ForwardWithReturnTo {});
+    }
+    Ok(())
+}
+
+// the input of each route, the last one gets the rounding remainder
+fn split_amounts(split: &SplitSwapPacket, amount: Uint128) -> Result<Vec<Uint128>, ContractError> {
+    if split.routes.is_empty() || split.routes.len() > MAX_SPLIT_ROUTES {
+        return Err(ContractError::InvalidSplitRoutes {});
+    }
+    let mut total_bps = 0u64;
+    for route in &split.routes {
+        if route.share_bps == 0 || route.share_bps > 10_000 {
+            return Err(ContractError::InvalidSplitRoutes {});
+        }
+        total_bps = total_bps
+            .checked_add(route.share_bps)
+            .ok_or(ContractError::InvalidSplitRoutes {})?;
+    }
+    if total_bps != 10_000 {
+        return Err(ContractError::InvalidSplitRoutes {});
+    }
+
+    let mut amounts: Vec<Uint128> = split
+        .routes
+        .iter()
+        .map(|r| amount.multiply_ratio(r.share_bps, 10_000u128))
+        .collect();
+    let split_amount: Uint128 = amounts.iter().sum();
+    if let Some(last) = amounts.last_mut() {
+        *last += amount - split_amount;
+    }
+    if amounts.iter().any(|a| a.is_zero()) {
+        return Err(ContractError::InvalidSplitRoutes {});
+    }
+    Ok(amounts)
+}
+
+fn split_swap_receive(
+    deps: Deps,
+    split: SplitSwapPacket,
+    op_id: u64,
+    channel: &str,
+    sender: String,
+    token_in: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let amounts = split_amounts(&split, token_in.amount())?;
+    let mut denom_out = None;
+    for (route, amount) in split.routes.iter().zip(amounts) {
+        let last_denom = route
+            .pools
+            .last()
+            .map(|r| r.token_out_denom.as_str())
+            .ok_or(ContractError::InvalidSplitRoutes {})?;
+        if *denom_out.get_or_insert(last_denom) != last_denom {
+            return Err(ContractError::SplitRouteDenoms {});
+        }
+
+        let pool_ids = route.pools.iter().map(|r| r.pool_id.u64());
+        check_route_pools(deps.storage, pool_ids, amount)?;
+        let routes: Vec<_> = route
+            .pools
             .iter()
             .map(
                 |r| cw_osmo_proto::osmosis::gamm::v1beta1::SwapAmountInRoute {
@@ -412,11 +1435,135 @@ fn swap_receive(
                     pool_id: r.pool_id.u64(),
                 },
             )
-            .collect(),
+            .collect();
+        let route_in = Amount::from_parts(token_in.denom(), amount);
+        check_slippage(deps, channel, &routes, &route_in, &contract)?;
+    }
+
+    // the routes are swapped by the contract in one message, they are reverted together
+    let msg = ExecuteMsg::SplitSwap {
+        op_id,
+        token_in: Coin::new(token_in.amount().u128(), token_in.denom()),
+        split,
+    };
+    let wasm_msg = WasmMsg::Execute {
+        contract_addr: contract,
+        msg: to_binary(&msg)?,
+        funds: vec![],
+    };
+    let submsg = SubMsg::reply_always(wasm_msg, op_reply_id(op_id, SPLIT_SWAP_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_split_swap")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", token_in.denom())
+        .add_attribute("amount", token_in.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+/// Swap each route of a received split swap, called by the contract itself. The route replies
+/// check the min output, if any route fails all of them are reverted
+pub fn execute_split_swap(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    op_id: u64,
+    token_in: Coin,
+    split: SplitSwapPacket,
+) -> Result<Response, ContractError> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized);
+    }
+
+    let amounts = split_amounts(&split, token_in.amount)?;
+    // the routes were checked to have the same output denom when the packet was received
+    let denom = split
+        .routes
+        .first()
+        .and_then(|r| r.pools.last())
+        .map(|p| p.token_out_denom.clone())
+        .ok_or(ContractError::InvalidSplitRoutes {})?;
+    let state = SplitSwapState {
+        routes: split.routes.len() as u64,
+        token_out_min_amount: split.token_out_min_amount,
+        denom,
+        outputs: vec![],
+    };
+    SPLIT_SWAPS.save(deps.storage, op_id, &state)?;
+
+    let mut submsgs = vec![];
+    for (route, amount) in split.routes.into_iter().zip(amounts) {
+        let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgSwapExactAmountIn {
+            sender: env.contract.address.to_string(),
+            token_in: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+                denom: token_in.denom.clone(),
+                amount: amount.to_string(),
+            }),
+            routes: route
+                .pools
+                .into_iter()
+                .map(
+                    |r| cw_osmo_proto::osmosis::gamm::v1beta1::SwapAmountInRoute {
+                        token_out_denom: r.token_out_denom,
+                        pool_id: r.pool_id.u64(),
+                    },
+                )
+                .collect(),
+            // the min output is checked on the sum of the routes
+            token_out_min_amount: "1".to_string(),
+        };
+        submsgs.push(SubMsg::reply_on_success(
+            tx.to_msg()?,
+            op_reply_id(op_id, SPLIT_ROUTE_ID),
+        ));
+    }
+
+    let res = Response::new()
+        .add_submessages(submsgs)
+        .add_attribute("action", "split_swap")
+        .add_attribute("routes", state.routes.to_string());
+    Ok(res)
+}
+
+fn swap_receive(
+    deps: Deps,
+    swap: SwapPacket,
+    op_id: u64,
+    channel: &str,
+    sender: String,
+    token_in: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    check_forward(swap.return_to, &swap.forward)?;
+    let pool_ids = swap.routes.iter().map(|r| r.pool_id.u64());
+    check_route_pools(deps.storage, pool_ids, token_in.amount())?;
+    let routes: Vec<_> = swap
+        .routes
+        .iter()
+        .map(
+            |r| cw_osmo_proto::osmosis::gamm::v1beta1::SwapAmountInRoute {
+                token_out_denom: r.token_out_denom.to_owned(),
+                pool_id: r.pool_id.u64(),
+            },
+        )
+        .collect();
+    check_slippage(deps, channel, &routes, &token_in, &contract)?;
+
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgSwapExactAmountIn {
+        sender: contract,
+        token_in: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+            denom: token_in.denom(),
+            amount: token_in.amount().to_string(),
+        }),
+        routes,
         token_out_min_amount: swap.token_out_min_amount.to_string(),
     };
 
-    let submsg = SubMsg::reply_always(tx.to_msg()?, SWAP_ID);
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, SWAP_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -430,12 +1577,62 @@ fn swap_receive(
     Ok(res)
 }
 
+fn swap_exact_out_receive(
+    deps: Deps,
+    swap: SwapExactOutPacket,
+    op_id: u64,
+    sender: String,
+    token_in_max: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let pool_ids = swap.routes.iter().map(|r| r.pool_id.u64());
+    check_route_pools(deps.storage, pool_ids, token_in_max.amount())?;
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgSwapExactAmountOut {
+        sender: contract,
+        routes: swap
+            .routes
+            .iter()
+            .map(
+                |r| cw_osmo_proto::osmosis::gamm::v1beta1::SwapAmountOutRoute {
+                    token_in_denom: r.token_in_denom.to_owned(),
+                    pool_id: r.pool_id.u64(),
+                },
+            )
+            .collect(),
+        token_in_max_amount: token_in_max.amount().to_string(),
+        token_out: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+            denom: swap.token_out.denom,
+            amount: swap.token_out.amount.to_string(),
+        }),
+    };
+
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, SWAP_OUT_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_swap_exact_out")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", token_in_max.denom())
+        .add_attribute("amount", token_in_max.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
 fn receive_join_pool(
+    deps: Deps,
     join_pool: JoinPoolPacket,
+    op_id: u64,
     sender: String,
     token_in: Amount,
     contract: String,
 ) -> Result<IbcReceiveResponse, ContractError> {
+    check_pool(
+        deps.storage,
+        join_pool.pool_id.u64(),
+        Some(token_in.amount()),
+    )?;
     let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgJoinSwapExternAmountIn {
         sender: contract,
         token_in: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
@@ -446,7 +1643,7 @@ fn receive_join_pool(
         share_out_min_amount: join_pool.share_out_min_amount.to_string(),
     };
 
-    let submsg = SubMsg::reply_always(tx.to_msg()?, JOIN_POOL_ID);
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, JOIN_POOL_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -461,12 +1658,16 @@ fn receive_join_pool(
 }
 
 fn receive_exit_pool(
+    deps: Deps,
     exit_pool: ExitPoolPacket,
+    op_id: u64,
     sender: String,
     token_in: Amount,
     contract: String,
 ) -> Result<IbcReceiveResponse, ContractError> {
+    check_forward(exit_pool.return_to, &exit_pool.forward)?;
     let pool_id = parse_pool_id(token_in.denom().as_str())?;
+    check_pool(deps.storage, pool_id, Some(token_in.amount()))?;
     let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgExitSwapShareAmountIn {
         sender: contract,
         pool_id,
@@ -475,7 +1676,7 @@ fn receive_exit_pool(
         token_out_min_amount: exit_pool.token_out_min_amount.to_string(),
     };
 
-    let submsg = SubMsg::reply_always(tx.to_msg()?, EXIT_POOL_ID);
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, EXIT_POOL_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -489,8 +1690,183 @@ fn receive_exit_pool(
     Ok(res)
 }
 
+fn receive_join_pool_exact_out(
+    deps: Deps,
+    join_pool: JoinPoolExactOutPacket,
+    op_id: u64,
+    sender: String,
+    token_in_max: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    check_pool(
+        deps.storage,
+        join_pool.pool_id.u64(),
+        Some(token_in_max.amount()),
+    )?;
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgJoinSwapShareAmountOut {
+        sender: contract,
+        pool_id: join_pool.pool_id.u64(),
+        token_in_denom: token_in_max.denom(),
+        share_out_amount: join_pool.share_out_amount.to_string(),
+        token_in_max_amount: token_in_max.amount().to_string(),
+    };
+
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, JOIN_POOL_OUT_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_join_pool_exact_out")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", token_in_max.denom())
+        .add_attribute("amount", token_in_max.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+fn receive_exit_pool_exact_out(
+    deps: Deps,
+    exit_pool: ExitPoolExactOutPacket,
+    op_id: u64,
+    sender: String,
+    share_in_max: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let pool_id = parse_pool_id(share_in_max.denom().as_str())?;
+    check_pool(deps.storage, pool_id, Some(share_in_max.amount()))?;
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgExitSwapExternAmountOut {
+        sender: contract,
+        pool_id,
+        token_out: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+            denom: exit_pool.token_out.denom,
+            amount: exit_pool.token_out.amount.to_string(),
+        }),
+        share_in_max_amount: share_in_max.amount().to_string(),
+    };
+
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, EXIT_POOL_OUT_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_exit_pool_exact_out")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", share_in_max.denom())
+        .add_attribute("amount", share_in_max.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+fn receive_multi_join_pool(
+    deps: DepsMut,
+    op_id: u64,
+    channel: &str,
+    join_pool: MultiJoinPoolPacket,
+    sender: String,
+    token_in: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let invalid_maxs = join_pool
+        .token_in_maxs
+        .iter()
+        .any(|c| c.amount.is_zero() || c.denom == token_in.denom());
+    if join_pool.token_in_maxs.is_empty() || invalid_maxs {
+        return Err(ContractError::InvalidTokenInMaxs {});
+    }
+    check_pool(
+        deps.storage,
+        join_pool.pool_id.u64(),
+        Some(token_in.amount()),
+    )?;
+
+    // other pool assets are taken from the sender balance
+    reduce_user_balances(deps.storage, channel, &sender, &join_pool.token_in_maxs)?;
+    if let Err(err) = reduce_channel_balances(deps.storage, channel, &join_pool.token_in_maxs) {
+        for coin in join_pool.token_in_maxs.iter() {
+            increase_user_balance(deps.storage, channel, &sender, &coin.denom, coin.amount)?;
+        }
+        return Err(err);
+    }
+    PENDING_OPS.update(deps.storage, op_id, |args| -> StdResult<_> {
+        let mut args = args.ok_or_else(|| StdError::not_found("ReplyArgs"))?;
+        args.reserved = join_pool.token_in_maxs.clone();
+        Ok(args)
+    })?;
+
+    let mut token_in_maxs = vec![cw_osmo_proto::cosmos::base::v1beta1::Coin {
+        denom: token_in.denom(),
+        amount: token_in.amount().to_string(),
+    }];
+    token_in_maxs.extend(join_pool.token_in_maxs.iter().map(|c| {
+        cw_osmo_proto::cosmos::base::v1beta1::Coin {
+            denom: c.denom.to_owned(),
+            amount: c.amount.to_string(),
+        }
+    }));
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgJoinPool {
+        sender: contract,
+        pool_id: join_pool.pool_id.u64(),
+        share_out_amount: join_pool.share_out_amount.to_string(),
+        token_in_maxs,
+    };
+
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, MULTI_JOIN_POOL_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_multi_join_pool")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", token_in.denom())
+        .add_attribute("amount", token_in.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+fn receive_multi_exit_pool(
+    deps: Deps,
+    exit_pool: MultiExitPoolPacket,
+    op_id: u64,
+    sender: String,
+    token_in: Amount,
+    contract: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let pool_id = parse_pool_id(token_in.denom().as_str())?;
+    check_pool(deps.storage, pool_id, Some(token_in.amount()))?;
+    let tx = cw_osmo_proto::osmosis::gamm::v1beta1::MsgExitPool {
+        sender: contract,
+        pool_id,
+        share_in_amount: token_in.amount().to_string(),
+        token_out_mins: exit_pool
+            .token_out_mins
+            .iter()
+            .map(|c| cw_osmo_proto::cosmos::base::v1beta1::Coin {
+                denom: c.denom.to_owned(),
+                amount: c.amount.to_string(),
+            })
+            .collect(),
+    };
+
+    let submsg = SubMsg::reply_always(tx.to_msg()?, op_reply_id(op_id, MULTI_EXIT_POOL_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_multi_exit_pool")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", token_in.denom())
+        .add_attribute("amount", token_in.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
 fn receive_create_lockup(
     deps: DepsMut,
+    op_id: u64,
     channel: &str,
     sender: String,
     contract: String,
@@ -512,7 +1888,7 @@ fn receive_create_lockup(
     }
     .into();
 
-    let submsg = SubMsg::reply_always(init_msg, LOCKUP_ID);
+    let submsg = SubMsg::reply_always(init_msg, op_reply_id(op_id, LOCKUP_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -525,6 +1901,7 @@ fn receive_create_lockup(
 
 fn receive_lock_tokens(
     deps: DepsMut,
+    op_id: u64,
     channel: &str,
     lock: LockPacket,
     sender: String,
@@ -543,7 +1920,7 @@ fn receive_lock_tokens(
         to_binary(&lockup_msg)?,
         coins(token_in.amount().u128(), token_in.denom()),
     );
-    let submsg = SubMsg::reply_always(exec_msg, LOCK_TOKEN_ID);
+    let submsg = SubMsg::reply_always(exec_msg, op_reply_id(op_id, LOCK_TOKEN_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -559,6 +1936,7 @@ fn receive_lock_tokens(
 
 fn receive_claim_tokens(
     deps: DepsMut,
+    op_id: u64,
     channel: &str,
     claim: ClaimPacket,
     sender: String,
@@ -570,7 +1948,7 @@ fn receive_claim_tokens(
 
     let lockup_msg = LockupExecuteMsg::Claim { denom: claim.denom };
     let exec_msg = create_lockup_msg(lockup_contract, to_binary(&lockup_msg)?, vec![]);
-    let submsg = SubMsg::reply_always(exec_msg, CLAIM_TOKEN_ID);
+    let submsg = SubMsg::reply_always(exec_msg, op_reply_id(op_id, CLAIM_TOKEN_ID));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -582,20 +1960,22 @@ fn receive_claim_tokens(
     Ok(res)
 }
 
+// unlock by id or all the locks, the reply kind tells them apart
 fn receive_unlock_tokens(
     deps: DepsMut,
+    op_id: u64,
     channel: &str,
-    unlock: UnlockPacket,
+    lockup_msg: LockupExecuteMsg,
     sender: String,
+    reply_kind: u64,
 ) -> Result<IbcReceiveResponse, ContractError> {
     let lock_key = (channel, sender.as_str());
     let lockup_contract = LOCKUP
         .load(deps.storage, lock_key)
         .map_err(|_| ContractError::LockupNotFound {})?;
 
-    let lockup_msg = LockupExecuteMsg::Unlock { id: unlock.id };
     let exec_msg = create_lockup_msg(lockup_contract, to_binary(&lockup_msg)?, vec![]);
-    let submsg = SubMsg::reply_on_error(exec_msg, UNLOCK_TOKEN_ID);
+    let submsg = SubMsg::reply_always(exec_msg, op_reply_id(op_id, reply_kind));
 
     let res = IbcReceiveResponse::new()
         .set_ack(ack_success())
@@ -607,34 +1987,227 @@ fn receive_unlock_tokens(
     Ok(res)
 }
 
-fn create_lockup_msg(contract_addr: String, msg: Binary, funds: Vec<Coin>) -> CosmosMsg {
-    WasmMsg::Execute {
-        contract_addr,
-        msg,
-        funds,
-    }
-    .into()
-}
+fn receive_extend_lock(
+    deps: DepsMut,
+    op_id: u64,
+    channel: &str,
+    extend: ExtendLockPacket,
+    sender: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    let lock_key = (channel, sender.as_str());
+    let lockup_contract = LOCKUP
+        .load(deps.storage, lock_key)
+        .map_err(|_| ContractError::LockupNotFound {})?;
 
-fn nonpayable(amount: &Amount) -> Result<(), ContractError> {
-    if amount.is_empty() {
-        Ok(())
-    } else {
-        Err(ContractError::NonPayable {})
-    }
-}
+    let lockup_msg = LockupExecuteMsg::ExtendLock {
+        id: extend.id,
+        duration: extend.duration,
+    };
+    let exec_msg = create_lockup_msg(lockup_contract, to_binary(&lockup_msg)?, vec![]);
+    let submsg = SubMsg::reply_always(exec_msg, op_reply_id(op_id, EXTEND_LOCK_ID));
+
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success())
+        .add_submessage(submsg)
+        .add_attribute("action", "receive_extend_lock")
+        .add_attribute("sender", sender)
+        .add_attribute("lock_id", extend.id)
+        .add_attribute("duration", extend.duration)
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+fn receive_withdraw(
+    deps: DepsMut,
+    env: Env,
+    channel: &str,
+    withdraw: WithdrawPacket,
+    sender: String,
+) -> Result<IbcReceiveResponse, ContractError> {
+    if withdraw.amount.is_zero() {
+        return Err(ContractError::NoFunds {});
+    }
+    reduce_user_balance(
+        deps.storage,
+        channel,
+        &sender,
+        &withdraw.denom,
+        withdraw.amount,
+    )?;
+    if let Err(err) = consume_inflow(
+        deps.storage,
+        env.block.time,
+        channel,
+        &withdraw.denom,
+        withdraw.amount,
+    ) {
+        increase_user_balance(
+            deps.storage,
+            channel,
+            &sender,
+            &withdraw.denom,
+            withdraw.amount,
+        )?;
+        return Err(err);
+    }
+
+    let amount = Amount::from_parts(withdraw.denom, withdraw.amount);
+    let msg = return_amount(deps.as_ref(), env, channel, &sender, amount.clone())?;
+
+    let ack = AmountResultAck {
+        denom: amount.denom(),
+        amount: amount.amount(),
+        fee: None,
+    };
+    let res = IbcReceiveResponse::new()
+        .set_ack(ack_success_with_body(to_binary(&ack)?))
+        .add_message(msg)
+        .add_attribute("action", "receive_withdraw")
+        .add_attribute("sender", sender)
+        .add_attribute("denom", amount.denom())
+        .add_attribute("amount", amount.amount())
+        .add_attribute("success", "true");
+
+    Ok(res)
+}
+
+fn create_lockup_msg(contract_addr: String, msg: Binary, funds: Vec<Coin>) -> CosmosMsg {
+    WasmMsg::Execute {
+        contract_addr,
+        msg,
+        funds,
+    }
+    .into()
+}
+
+// the pool must be on the allow list, the max amount only limits the pool input
+fn check_pool(
+    storage: &dyn Storage,
+    pool_id: u64,
+    amount_in: Option<Uint128>,
+) -> Result<(), ContractError> {
+    let allow = POOL_ALLOW_LIST
+        .may_load(storage, pool_id)?
+        .ok_or(ContractError::PoolNotAllowed { pool_id })?;
+    if let (Some(max_amount), Some(amount)) = (allow.max_amount, amount_in) {
+        if amount > max_amount {
+            return Err(ContractError::PoolMaxAmountExceeded {
+                pool_id,
+                max_amount,
+            });
+        }
+    }
+    Ok(())
+}
+
+// the input amount only goes to the first pool of the route
+fn check_route_pools(
+    storage: &dyn Storage,
+    pool_ids: impl Iterator<Item = u64>,
+    amount_in: Uint128,
+) -> Result<(), ContractError> {
+    for (i, pool_id) in pool_ids.enumerate() {
+        let amount = if i == 0 { Some(amount_in) } else { None };
+        check_pool(storage, pool_id, amount)?;
+    }
+    Ok(())
+}
+
+// the swap is rejected if the estimated output is too far from the spot price of the route,
+// using the lowest limit of the channel and the route pools
+fn check_slippage(
+    deps: Deps,
+    channel: &str,
+    routes: &[cw_osmo_proto::osmosis::gamm::v1beta1::SwapAmountInRoute],
+    token_in: &Amount,
+    contract: &str,
+) -> Result<(), ContractError> {
+    let mut max_slippage = CHANNEL_MAX_SLIPPAGE.may_load(deps.storage, channel)?;
+    for route in routes {
+        let pool_max = POOL_ALLOW_LIST
+            .may_load(deps.storage, route.pool_id)?
+            .and_then(|allow| allow.max_slippage_bps);
+        max_slippage = match (max_slippage, pool_max) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+    let (max_slippage_bps, first_route) = match (max_slippage, routes.first()) {
+        (Some(max), Some(route)) => (max, route),
+        _ => return Ok(()),
+    };
+
+    let estimate: QuerySwapExactAmountInResponse = query_proto(
+        deps,
+        QuerySwapExactAmountInRequest {
+            sender: contract.to_string(),
+            pool_id: first_route.pool_id,
+            token_in: format!("{}{}", token_in.amount(), token_in.denom()),
+            routes: routes.to_vec(),
+        },
+    )?;
+    let token_out = Uint128::from_str(&estimate.token_out_amount)?;
+
+    // price of the route, the amount of token in paid for one token out.
+    // The legacy gamm query takes the token in as field 2 and the token out as field 3
+    // (`tokenInDenom`/`tokenOutDenom` in osmosis v6.2.0, later renamed base/quote without changing
+    // the result) and returns the balancer spot price (balance_in / weight_in) / (balance_out /
+    // weight_out), without swap fee.
+    let mut spot_price = Decimal::one();
+    let mut denom_in = token_in.denom();
+    for route in routes {
+        let res: QuerySpotPriceResponse = query_proto(
+            deps,
+            QuerySpotPriceRequest {
+                pool_id: route.pool_id,
+                base_asset_denom: denom_in,
+                quote_asset_denom: route.token_out_denom.clone(),
+            },
+        )?;
+        spot_price *= Decimal::from_str(&res.spot_price)?;
+        denom_in = route.token_out_denom.clone();
+    }
+
+    let price_impact_bps = price_impact_bps(token_in.amount(), token_out, spot_price);
+    if price_impact_bps > max_slippage_bps {
+        return Err(ContractError::SlippageExceeded {
+            price_impact_bps,
+            max_slippage_bps,
+        });
+    }
+    Ok(())
+}
+
+// the part of the input lost compared to buying the output at spot price, in basis points
+fn price_impact_bps(amount_in: Uint128, amount_out: Uint128, spot_price: Decimal) -> u64 {
+    let spot_cost = amount_out * spot_price;
+    if amount_in.is_zero() || spot_cost >= amount_in {
+        return 0;
+    }
+    let lost = amount_in - spot_cost;
+    lost.multiply_ratio(10_000u128, amount_in).u128() as u64
+}
+
+fn nonpayable(amount: &Amount) -> Result<(), ContractError> {
+    if amount.is_empty() {
+        Ok(())
+    } else {
+        Err(ContractError::NonPayable {})
+    }
+}
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 /// check if success or failure and update balance, or return funds
 pub fn ibc_packet_ack(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: IbcPacketAckMsg,
 ) -> Result<IbcBasicResponse, ContractError> {
     let ics20msg: Ics20Ack = from_binary(&msg.acknowledgement.data)?;
     match ics20msg {
         Ics20Ack::Result(_) => on_packet_success(msg.original_packet),
-        Ics20Ack::Error(err) => on_packet_failure(deps, msg.original_packet, err),
+        Ics20Ack::Error(err) => on_packet_failure(deps, env, msg.original_packet, err),
     }
 }
 
@@ -642,11 +2215,11 @@ pub fn ibc_packet_ack(
 /// return fund to original sender (same as failure in ibc_packet_ack)
 pub fn ibc_packet_timeout(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: IbcPacketTimeoutMsg,
 ) -> Result<IbcBasicResponse, ContractError> {
     let packet = msg.packet;
-    on_packet_failure(deps, packet, "timeout".to_string())
+    on_packet_failure(deps, env, packet, "timeout".to_string())
 }
 
 // update the balance stored on this (channel, denom) index
@@ -669,16 +2242,63 @@ fn on_packet_success(packet: IbcPacket) -> Result<IbcBasicResponse, ContractErro
 // return the tokens to sender
 fn on_packet_failure(
     deps: DepsMut,
+    env: Env,
     packet: IbcPacket,
     err: String,
 ) -> Result<IbcBasicResponse, ContractError> {
     let msg: Ics20Packet = from_binary(&packet.data)?;
+    // the packets sent before a channel migration update the replacement channel
+    let channel = balance_channel(deps.storage, &packet.src.channel_id)?;
+
+    // returned action output, give the tokens back to the remote sender balance
+    if msg.sender == env.contract.address.as_str() {
+        increase_user_balance(
+            deps.storage,
+            &channel,
+            &msg.receiver,
+            &msg.denom,
+            msg.amount,
+        )?;
+        undo_inflow(deps.storage, &packet.src.channel_id, &msg.denom, msg.amount)?;
+
+        let res = IbcBasicResponse::new()
+            .add_attribute("action", "acknowledge")
+            .add_attribute("sender", msg.sender)
+            .add_attribute("receiver", msg.receiver)
+            .add_attribute("denom", msg.denom)
+            .add_attribute("amount", msg.amount.to_string())
+            .add_attribute("success", "false")
+            .add_attribute("error", err);
 
-    reduce_channel_balance(deps.storage, &packet.src.channel_id, &msg.denom, msg.amount)?;
+        return Ok(res);
+    }
+
+    reduce_channel_balance(deps.storage, &channel, &msg.denom, msg.amount)?;
+    // the refunded tokens didn't leave, the quota is counted on the sending channel
+    undo_inflow(deps.storage, &packet.src.channel_id, &msg.denom, msg.amount)?;
+
+    // keep the refund to record it if the send fails
+    let reply_args = ReplyArgs {
+        channel,
+        denom: msg.denom.clone(),
+        amount: msg.amount,
+        sender: msg.sender.clone(),
+        return_to: false,
+        reserved: vec![],
+        fee: Uint128::zero(),
+        sequence: None,
+        packet_channel: None,
+        forward: None,
+        token_out: None,
+        token_out_denom: None,
+    };
+    let op_id = save_pending_op(deps.storage, &reply_args)?;
 
     let to_send = Amount::from_parts(msg.denom.clone(), msg.amount);
+    let gas_limit = check_gas_limit(deps.as_ref(), &to_send)?;
     let send = send_amount(to_send, msg.sender.clone());
-    let submsg = SubMsg::reply_on_error(send, ACK_FAILURE_ID);
+    let mut submsg = SubMsg::reply_always(send, op_reply_id(op_id, ACK_FAILURE_ID));
+    submsg.gas_limit = gas_limit;
 
     // similar event messages like ibctransfer module
     let res = IbcBasicResponse::new()
@@ -694,29 +2314,56 @@ fn on_packet_failure(
     Ok(res)
 }
 
-fn send_amount(amount: Amount, recipient: String) -> CosmosMsg {
+pub fn send_amount(amount: Amount, recipient: String) -> CosmosMsg {
     match amount {
         Amount::Native(coin) => BankMsg::Send {
             to_address: recipient,
             amount: vec![coin],
         }
         .into(),
+        Amount::Cw20(coin) => {
+            let msg = Cw20ExecuteMsg::Transfer {
+                recipient,
+                amount: coin.amount,
+            };
+            WasmMsg::Execute {
+                contract_addr: coin.address,
+                msg: to_binary(&msg).unwrap(),
+                funds: vec![],
+            }
+            .into()
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::state::{save_lockup, LOCKUP_OWNER};
     use crate::test_helpers::*;
 
-    use crate::contract::{execute, query_channel};
-    use crate::ibc_msg::{AmountResultAck, SwapAmountInRoute};
-    use crate::msg::{ExecuteMsg, TransferMsg};
-    use cosmwasm_std::testing::{mock_env, mock_info};
+    use crate::contract::{execute, query, query_channel, query_user_balances, sudo};
+    use crate::ibc_msg::{
+        AmountResultAck, ExactOutResultAck, SplitRoute, SwapAmountInRoute, SwapAmountOutRoute,
+        UnlockPacket, UnlockingLockAck,
+    };
+    use crate::msg::{
+        AllowMsg, AllowPoolMsg, ChannelStatusResponse, ConnectionMsg, ExecuteMsg, FeesResponse,
+        ForwardResponse, IbcLifecycleComplete, ListChannelStatusResponse, ListForwardsResponse,
+        ListPacketsResponse, ListPendingOpsResponse, ListPendingRefundsResponse,
+        ListRateLimitsResponse, PacketResponse, PendingRefundResponse, QueryMsg, RateLimitMsg,
+        SudoMsg, TransferMsg,
+    };
+    use cosmwasm_std::testing::{
+        mock_env, mock_ibc_channel_close_confirm, mock_ibc_channel_close_init,
+        mock_ibc_channel_connect_ack, mock_ibc_channel_open_init, mock_info, MOCK_CONTRACT_ADDR,
+    };
     use cosmwasm_std::{
-        coins, to_vec, Event, IbcEndpoint, ReplyOn, StdError, StdResult, SubMsgResponse, Timestamp,
-        Uint128, Uint64,
+        coin, coins, to_vec, Attribute, Event, IbcEndpoint, IbcTimeout, ReplyOn, StdError,
+        StdResult, SubMsgResponse, Timestamp, Uint128, Uint64,
     };
+    use cw20::Cw20ReceiveMsg;
+    use cw_controllers::AdminError;
     use serde::de::DeserializeOwned;
     use serde::Serialize;
 
@@ -730,6 +2377,19 @@ mod test {
 
         let fail_json = String::from_utf8(to_vec(&fail).unwrap()).unwrap();
         assert_eq!(r#"{"error":"bad coin"}"#, fail_json.as_str());
+
+        let fail = Ics20Ack::Error(error_ack_msg(
+            &ContractError::InsufficientFunds {},
+            Some("swap"),
+        ));
+        let fail_json = String::from_utf8(to_vec(&fail).unwrap()).unwrap();
+        let expected = r#"{"error":"{\"code\":12,\"message\":\"Insufficient funds to redeem voucher on channel\",\"action\":\"swap\"}"}"#;
+        assert_eq!(expected, fail_json.as_str());
+
+        let fail = Ics20Ack::Error(error_ack_msg(&ContractError::NoFunds {}, None));
+        let fail_json = String::from_utf8(to_vec(&fail).unwrap()).unwrap();
+        let expected = r#"{"error":"{\"code\":7,\"message\":\"Didn't send any funds\"}"}"#;
+        assert_eq!(expected, fail_json.as_str());
     }
 
     #[test]
@@ -758,6 +2418,7 @@ mod test {
                 pool_id: Uint64::new(1),
                 share_out_min_amount: Uint128::new(1),
             })),
+            memo: None,
         };
 
         // Example message generated from the SDK
@@ -767,18 +2428,44 @@ mod test {
         assert_eq!(expected, encoded.as_str());
     }
 
-    fn native_payment(amount: u128, denom: &str, recipient: &str) -> SubMsg {
-        SubMsg::reply_on_error(
+    fn native_payment(amount: u128, denom: &str, recipient: &str, op_id: u64) -> SubMsg {
+        SubMsg::reply_always(
             BankMsg::Send {
                 to_address: recipient.into(),
                 amount: coins(amount, denom),
             },
-            RECEIVE_ID,
+            op_reply_id(op_id, RECEIVE_ID),
         )
     }
 
-    fn check_gamm_submsg(msg: SubMsg, reply_id: u64, action: &str) -> StdResult<()> {
-        if msg.id != reply_id {
+    fn cw20_payment(
+        amount: u128,
+        address: &str,
+        recipient: &str,
+        id: u64,
+        gas_limit: Option<u64>,
+    ) -> SubMsg {
+        let msg = Cw20ExecuteMsg::Transfer {
+            recipient: recipient.into(),
+            amount: Uint128::new(amount),
+        };
+        let exec = WasmMsg::Execute {
+            contract_addr: address.into(),
+            msg: to_binary(&msg).unwrap(),
+            funds: vec![],
+        };
+        let mut msg = SubMsg::reply_always(exec, id);
+        msg.gas_limit = gas_limit;
+        msg
+    }
+
+    fn reply_kind(id: u64) -> u64 {
+        id & ((1 << REPLY_KIND_BITS) - 1)
+    }
+
+    // returns the reply id, including the pending operation id
+    fn check_gamm_submsg(msg: SubMsg, reply_id: u64, action: &str) -> StdResult<u64> {
+        if reply_kind(msg.id) != reply_id {
             return Err(StdError::generic_err("Invalid reply id"));
         }
 
@@ -797,7 +2484,7 @@ mod test {
             _ => return Err(StdError::generic_err("Invalid cosmMsg")),
         };
 
-        Ok(())
+        Ok(msg.id)
     }
 
     fn get_ack_result<T: DeserializeOwned>(data: &Binary) -> StdResult<T> {
@@ -828,6 +2515,7 @@ mod test {
             sender: "remote-sender".to_string(),
             receiver: receiver.to_string(),
             action,
+            memo: None,
         }
     }
 
@@ -858,6 +2546,34 @@ mod test {
         ))
     }
 
+    // the forwards of the tests go to juno1receiver on channel-42
+    fn forward_msg(amount: Coin, timeout: Timestamp) -> CosmosMsg {
+        MsgTransfer {
+            source_port: TRANSFER_PORT.to_string(),
+            source_channel: "channel-42".to_string(),
+            token: Some(cw_osmo_proto::cosmos::base::v1beta1::Coin {
+                denom: amount.denom,
+                amount: amount.amount.to_string(),
+            }),
+            sender: MOCK_CONTRACT_ADDR.to_string(),
+            receiver: "juno1receiver".to_string(),
+            timeout_height: None,
+            timeout_timestamp: timeout.nanos(),
+            memo: format!(r#"{{"ibc_callback":"{}"}}"#, MOCK_CONTRACT_ADDR),
+        }
+        .to_msg()
+        .unwrap()
+    }
+
+    fn mock_transfer_response(sequence: u64) -> SubMsgResponse {
+        let mut data = vec![];
+        cw_osmo_proto::Message::encode(&MsgTransferResponse { sequence }, &mut data).unwrap();
+        SubMsgResponse {
+            events: vec![],
+            data: Some(data.into()),
+        }
+    }
+
     fn mock_rcv_action_packet(
         action: OsmoPacket,
         channel: &str,
@@ -887,7 +2603,40 @@ mod test {
                 funds,
             }),
             ..
-        } if id == reply_id && reply_on.clone() == on && contract_addr.eq(contract) && funds.eq(&mgs_funds) && msg_exp.eq(&from_binary::<T>(msg).unwrap())));
+        } if reply_kind(id) == reply_id && reply_on.clone() == on && contract_addr.eq(contract) && funds.eq(&mgs_funds) && msg_exp.eq(&from_binary::<T>(msg).unwrap())));
+    }
+
+    #[test]
+    fn channel_handshake_connection_allowlist() {
+        let mut deps = setup(&[]);
+
+        // the counterparty port must be allowed on the connection
+        let mut open_msg = mock_ibc_channel_open_init("channel-1", ICS20_ORDERING, ICS20_VERSION);
+        if let IbcChannelOpenMsg::OpenInit { channel } = &mut open_msg {
+            channel.counterparty_endpoint.port_id = "other_port".to_string();
+        }
+        let err = ibc_channel_open(deps.as_mut(), mock_env(), open_msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ConnectionNotAllowed {
+                connection_id: CONNECTION_ID.to_string(),
+                port_id: "other_port".to_string(),
+            }
+        );
+
+        let open_msg = mock_ibc_channel_open_init("channel-1", ICS20_ORDERING, ICS20_VERSION);
+        ibc_channel_open(deps.as_mut(), mock_env(), open_msg).unwrap();
+
+        // revoked before the channel is connected
+        let msg = ExecuteMsg::RevokeConnection(ConnectionMsg {
+            connection_id: CONNECTION_ID.to_string(),
+            counterparty_port_id: COUNTERPARTY_PORT.to_string(),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        let connect_msg = mock_ibc_channel_connect_ack("channel-1", ICS20_ORDERING, ICS20_VERSION);
+        let err = ibc_channel_connect(deps.as_mut(), mock_env(), connect_msg).unwrap_err();
+        assert!(matches!(err, ContractError::ConnectionNotAllowed { .. }));
+        assert!(!CHANNEL_INFO.has(deps.as_ref().storage, "channel-1"));
     }
 
     #[test]
@@ -905,7 +2654,7 @@ mod test {
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet.clone()).unwrap();
         assert!(res.messages.is_empty());
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
-        let no_funds = Ics20Ack::Error(ContractError::InsufficientFunds {}.to_string());
+        let no_funds = Ics20Ack::Error(error_ack_msg(&ContractError::InsufficientFunds {}, None));
         assert_eq!(ack, no_funds);
 
         // we transfer some tokens
@@ -932,7 +2681,7 @@ mod test {
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet).unwrap();
         assert_eq!(1, res.messages.len());
         assert_eq!(
-            native_payment(876543210, denom, "local-rcpt"),
+            native_payment(876543210, denom, "local-rcpt", 1),
             res.messages[0]
         );
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
@@ -947,173 +2696,2672 @@ mod test {
     }
 
     #[test]
-    fn receive_swap_action() {
+    fn send_receive_cw20() {
         let send_channel = "channel-9";
         let mut deps = setup(&["channel-1", "channel-7", send_channel]);
-        let denom = "uatom";
-        let swap_denom = "uosmo";
 
-        let swap = OsmoPacket::Swap(SwapPacket {
-            routes: vec![SwapAmountInRoute {
-                pool_id: 1u8.into(),
-                token_out_denom: swap_denom.to_string(),
-            }],
-            token_out_min_amount: 1u8.into(),
+        let cw20_addr = "token-addr";
+        let cw20_denom = "cw20:token-addr";
+        let gas_limit = 1234567;
+        let allow = ExecuteMsg::Allow(AllowMsg {
+            contract: cw20_addr.to_string(),
+            gas_limit: Some(gas_limit),
         });
-
-        let swap_packet_data = mock_ics20_data(876543210, denom, "", Some(swap));
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), allow).unwrap();
 
         // prepare some mock packets
-        let swap_packet = mock_ibc_rcv_packet(send_channel, &swap_packet_data);
+        let recv_packet = mock_receive_packet(send_channel, 876543210, cw20_denom, "local-rcpt");
+        let recv_high_packet =
+            mock_receive_packet(send_channel, 1876543210, cw20_denom, "local-rcpt");
 
-        // we transfer some tokens
-        let msg = ExecuteMsg::Transfer(TransferMsg {
+        // cannot receive this denom yet
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet.clone()).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let no_funds = Ics20Ack::Error(error_ack_msg(&ContractError::InsufficientFunds {}, None));
+        assert_eq!(ack, no_funds);
+
+        // we send some cw20 tokens over
+        let transfer = TransferMsg {
             channel: send_channel.to_string(),
-            remote_address: "my-remote-address".to_string(),
+            remote_address: "remote-rcpt".to_string(),
             timeout: None,
+        };
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "local-sender".to_string(),
+            amount: Uint128::new(987654321),
+            msg: to_binary(&transfer).unwrap(),
         });
-        let info = mock_info("local-sender", &coins(987654321, denom));
-        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let info = mock_info(cw20_addr, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        let expected = Ics20Packet {
+            denom: cw20_denom.into(),
+            amount: Uint128::new(987654321),
+            sender: "local-sender".to_string(),
+            receiver: "remote-rcpt".to_string(),
+            action: None,
+            memo: None,
+        };
+        let timeout = mock_env().block.time.plus_seconds(DEFAULT_TIMEOUT);
+        assert_eq!(
+            &res.messages[0],
+            &SubMsg::new(IbcMsg::SendPacket {
+                channel_id: send_channel.to_string(),
+                data: to_binary(&expected).unwrap(),
+                timeout: IbcTimeout::with_timestamp(timeout),
+            })
+        );
 
         // query channel state|_|
         let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
-        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
-        assert_eq!(state.total_sent, vec![Amount::native(987654321, denom)]);
+        assert_eq!(state.balances, vec![Amount::cw20(987654321, cw20_addr)]);
+        assert_eq!(state.total_sent, vec![Amount::cw20(987654321, cw20_addr)]);
 
-        // Swap action
+        // cannot receive more than we sent
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_high_packet).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert_eq!(ack, no_funds);
+
+        // cw20 tokens cannot be used in actions
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 876543210, cw20_denom);
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
-        assert_eq!(1, res.messages.len());
-        check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::Cw20ActionNotSupported {};
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&err, Some("swap"))));
 
+        // we can receive less than we sent, transferred with the gas limit
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(
+            cw20_payment(
+                876543210,
+                cw20_addr,
+                "local-rcpt",
+                op_reply_id(2, RECEIVE_ID),
+                Some(gas_limit)
+            ),
+            res.messages[0]
+        );
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
         assert!(matches!(ack, Ics20Ack::Result(_)));
 
-        // Simulate swap reply
-        let r = mock_swap_response();
-        let reply_msg = mock_reply_msg(SWAP_ID, r.events, r.data);
+        // query channel state
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::cw20(111111111, cw20_addr)]);
+        assert_eq!(state.total_sent, vec![Amount::cw20(987654321, cw20_addr)]);
 
-        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
-        let gamm_ack_exp = AmountResultAck {
-            amount: Uint128::new(36601070u128),
-            denom: swap_denom.to_string(),
+        // a timeout gives the tokens back to the sender with the gas limit
+        let timeout_packet = Ics20Packet {
+            amount: Uint128::new(111111111),
+            ..expected
         };
-        assert_eq!(gamm_ack, gamm_ack_exp);
-
-        // query channel state
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            to_binary(&timeout_packet).unwrap(),
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: send_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            1,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        let res = ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        let refund = cw20_payment(
+            111111111,
+            cw20_addr,
+            "local-sender",
+            op_reply_id(3, ACK_FAILURE_ID),
+            Some(gas_limit),
+        );
+        assert_eq!(vec![refund], res.messages);
         let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::cw20(0, cw20_addr)]);
+
+        // the refund fails, it is kept for the sender
+        let reply_msg = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Err("out of gas".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+        let msg = QueryMsg::PendingRefund {
+            recipient: "local-sender".to_string(),
+        };
+        let raw = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let pending: PendingRefundResponse = from_binary(&raw).unwrap();
+        assert_eq!(pending.refunds, vec![Amount::cw20(111111111, cw20_addr)]);
+
+        // claimed with the gas limit
+        let msg = ExecuteMsg::ClaimRefund {};
+        let info = mock_info("local-sender", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let mut refund = cw20_payment(111111111, cw20_addr, "local-sender", 0, Some(gas_limit));
+        refund.reply_on = ReplyOn::Never;
+        assert_eq!(vec![refund], res.messages);
+    }
+
+    #[test]
+    fn claim_failed_refunds() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&[send_channel]);
+        let denom = "uatom";
+
+        // send two transfers to time out
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(1000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap();
+        let info = mock_info("local-sender", &coins(2000, "uosmo"));
+        execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap();
+        let info = mock_info("other-sender", &coins(3000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let fail_refund = |deps: DepsMut, sender: &str, amount: u128, denom: &str| {
+            let packet = Ics20Packet::new(amount.into(), denom, sender, "my-remote-address");
+            let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+                to_binary(&packet).unwrap(),
+                IbcEndpoint {
+                    port_id: CONTRACT_PORT.to_string(),
+                    channel_id: send_channel.to_string(),
+                },
+                IbcEndpoint {
+                    port_id: REMOTE_PORT.to_string(),
+                    channel_id: "channel-1234".to_string(),
+                },
+                1,
+                Timestamp::from_seconds(1665321069).into(),
+            ));
+            let mut deps = deps;
+            let res = ibc_packet_timeout(deps.branch(), mock_env(), timeout_msg).unwrap();
+            assert_eq!(1, res.messages.len());
+            assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+            let reply_msg = Reply {
+                id: res.messages[0].id,
+                result: SubMsgResult::Err("blocked address".to_string()),
+            };
+            let res = reply(deps, mock_env(), reply_msg).unwrap();
+            assert!(res.data.is_none());
+        };
+        fail_refund(deps.as_mut(), "local-sender", 600, denom);
+        fail_refund(deps.as_mut(), "local-sender", 400, denom);
+        fail_refund(deps.as_mut(), "local-sender", 2000, "uosmo");
+        fail_refund(deps.as_mut(), "other-sender", 3000, denom);
+
+        // no operation is left behind
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PendingOps {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let ops: ListPendingOpsResponse = from_binary(&raw).unwrap();
+        assert!(ops.ops.is_empty());
+
+        let list = |deps: Deps, start_after: Option<&str>, limit: Option<u32>| {
+            let msg = QueryMsg::PendingRefunds {
+                start_after: start_after.map(String::from),
+                limit,
+            };
+            let raw = query(deps, mock_env(), msg).unwrap();
+            from_binary::<ListPendingRefundsResponse>(&raw)
+                .unwrap()
+                .refunds
+        };
+        let local_refunds = PendingRefundResponse {
+            recipient: "local-sender".to_string(),
+            refunds: vec![Amount::native(1000, denom), Amount::native(2000, "uosmo")],
+        };
+        let other_refunds = PendingRefundResponse {
+            recipient: "other-sender".to_string(),
+            refunds: vec![Amount::native(3000, denom)],
+        };
         assert_eq!(
-            state.balances,
-            vec![
-                Amount::native(111111111, denom),
-                Amount::native(36601070, swap_denom)
-            ]
+            list(deps.as_ref(), None, None),
+            vec![local_refunds.clone(), other_refunds.clone()]
         );
+        assert_eq!(list(deps.as_ref(), None, Some(1)), vec![local_refunds]);
         assert_eq!(
-            state.total_sent,
+            list(deps.as_ref(), Some("local-sender"), None),
+            vec![other_refunds]
+        );
+
+        // only the recipient refunds are sent
+        let msg = ExecuteMsg::ClaimRefund {};
+        let info = mock_info("local-sender", &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        assert_eq!(
+            res.messages,
             vec![
-                Amount::native(987654321, denom),
-                Amount::native(36601070, swap_denom)
+                SubMsg::new(BankMsg::Send {
+                    to_address: "local-sender".to_string(),
+                    amount: coins(1000, denom),
+                }),
+                SubMsg::new(BankMsg::Send {
+                    to_address: "local-sender".to_string(),
+                    amount: coins(2000, "uosmo"),
+                })
             ]
         );
+        assert_eq!(list(deps.as_ref(), None, None).len(), 1);
+
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert_eq!(err, ContractError::NoRefund {});
     }
 
     #[test]
-    fn receive_liquidty_actions() {
+    fn receive_memo_action() {
         let send_channel = "channel-9";
         let mut deps = setup(&["channel-1", "channel-7", send_channel]);
-        let denom = "uosmo";
-        let pool_denom = "gamm/pool/1";
+        let denom = "uatom";
 
-        let join_pool = OsmoPacket::JoinPool(JoinPoolPacket {
-            pool_id: 1u8.into(),
-            share_out_min_amount: 1u8.into(),
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
         });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
 
-        let exit_pool = OsmoPacket::ExitPool(ExitPoolPacket {
-            token_out_denom: denom.into(),
-            token_out_min_amount: 1u8.into(),
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let memo_packet = |memo: &str| {
+            let mut data = mock_ics20_data(1000, denom, MOCK_CONTRACT_ADDR, None);
+            data.memo = Some(memo.to_string());
+            data
+        };
+        let swap_memo = format!(
+            r#"{{"wasm":{{"contract":"{}","msg":{}}}}}"#,
+            MOCK_CONTRACT_ADDR,
+            String::from_utf8(to_vec(&swap).unwrap()).unwrap()
+        );
+
+        // memos without the wasm key are ignored
+        for memo in ["", "hello", r#"{"forward":{"port":"transfer"}}"#] {
+            assert_eq!(
+                None,
+                memo_packet(memo).osmo_action(MOCK_CONTRACT_ADDR).unwrap()
+            );
+        }
+        let action = memo_packet(&swap_memo).osmo_action(MOCK_CONTRACT_ADDR);
+        assert_eq!(Some(swap.clone()), action.unwrap());
+        let err = memo_packet(&swap_memo).osmo_action("other-contract");
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::MemoOtherContract {
+                contract: MOCK_CONTRACT_ADDR.to_string()
+            }
+        );
+        let mut duplicate = memo_packet(&swap_memo);
+        duplicate.action = Some(swap);
+        let err = duplicate.osmo_action(MOCK_CONTRACT_ADDR).unwrap_err();
+        assert_eq!(err, ContractError::DuplicateAction {});
+
+        // swap from a plain ics20 transfer
+        let packet = mock_ibc_rcv_packet(send_channel, &memo_packet(&swap_memo));
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        // an invalid action is rejected
+        let memo = r#"{"wasm":{"msg":{"swap":{}}}}"#;
+        let packet = mock_ibc_rcv_packet(send_channel, &memo_packet(memo));
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Error(_)));
+    }
+
+    #[test]
+    fn receive_rate_limit() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let msg = ExecuteMsg::AddRateLimit(RateLimitMsg {
+            channel: send_channel.to_string(),
+            denom: denom.to_string(),
+            period: 3600,
+            max_outflow: Some(Uint128::new(1000)),
+            max_inflow: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let outflow = |deps: Deps, env: Env| -> Uint128 {
+            let msg = QueryMsg::RateLimits {
+                channel: send_channel.to_string(),
+            };
+            let res: ListRateLimitsResponse = from_binary(&query(deps, env, msg).unwrap()).unwrap();
+            res.rate_limits[0].outflow
+        };
+
+        let recv_packet = mock_receive_packet(send_channel, 800, denom, "local-rcpt");
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = res.messages[0].id;
+        assert_eq!(Uint128::new(800), outflow(deps.as_ref(), mock_env()));
+
+        // quota exceeded
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet.clone()).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::RateLimitExceeded {
+            channel: send_channel.to_string(),
+            denom: denom.to_string(),
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, None)));
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987653521, denom)]);
+
+        // a failed transfer gives back the quota
+        let reply_msg = Reply {
+            id: reply_id,
+            result: SubMsgResult::Err("bank error".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(Uint128::zero(), outflow(deps.as_ref(), mock_env()));
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv_packet.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+
+        // the quota is given back once the packets left the sliding window
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(3600);
+        assert_eq!(Uint128::new(800), outflow(deps.as_ref(), env.clone()));
+        env.block.time = env.block.time.plus_seconds(360);
+        assert_eq!(Uint128::zero(), outflow(deps.as_ref(), env.clone()));
+        let res = ibc_packet_receive(deps.as_mut(), env.clone(), recv_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(Uint128::new(800), outflow(deps.as_ref(), env));
+    }
+
+    #[test]
+    fn refund_rate_limit() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&[send_channel]);
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::AddRateLimit(RateLimitMsg {
+            channel: send_channel.to_string(),
+            denom: denom.to_string(),
+            period: 3600,
+            max_outflow: None,
+            max_inflow: Some(Uint128::new(1000)),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let transfer = |deps: DepsMut, amount: u128| {
+            let msg = ExecuteMsg::Transfer(TransferMsg {
+                channel: send_channel.to_string(),
+                remote_address: "my-remote-address".to_string(),
+                timeout: None,
+            });
+            let info = mock_info("local-sender", &coins(amount, denom));
+            execute(deps, mock_env(), info, msg)
+        };
+        let inflow = |deps: Deps| -> Uint128 {
+            let msg = QueryMsg::RateLimits {
+                channel: send_channel.to_string(),
+            };
+            let res: ListRateLimitsResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.rate_limits[0].inflow
+        };
+        transfer(deps.as_mut(), 1000).unwrap();
+        assert_eq!(Uint128::new(1000), inflow(deps.as_ref()));
+        transfer(deps.as_mut(), 1).unwrap_err();
+
+        // the refund of the timed out transfer gives back the quota
+        let packet = Ics20Packet::new(1000u128.into(), denom, "local-sender", "my-remote-address");
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            to_binary(&packet).unwrap(),
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: send_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            1,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        let res = ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(Uint128::zero(), inflow(deps.as_ref()));
+        transfer(deps.as_mut(), 1000).unwrap();
+    }
+
+    #[test]
+    fn return_rate_limit() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+
+        let msg = ExecuteMsg::AddRateLimit(RateLimitMsg {
+            channel: send_channel.to_string(),
+            denom: swap_denom.to_string(),
+            period: 3600,
+            max_outflow: None,
+            max_inflow: Some(Uint128::new(50000000)),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let inflow = |deps: Deps| -> Uint128 {
+            let msg = QueryMsg::RateLimits {
+                channel: send_channel.to_string(),
+            };
+            let res: ListRateLimitsResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.rate_limits[0].inflow
+        };
+        let swap_return = |mut deps: DepsMut| {
+            let swap = OsmoPacket::Swap(SwapPacket {
+                routes: vec![SwapAmountInRoute {
+                    pool_id: 1u8.into(),
+                    token_out_denom: swap_denom.to_string(),
+                }],
+                token_out_min_amount: 1u8.into(),
+                return_to: true,
+                forward: None,
+            });
+            let packet = mock_rcv_action_packet(swap, send_channel, 400000000, denom);
+            let res = ibc_packet_receive(deps.branch(), mock_env(), packet).unwrap();
+            let r = mock_swap_response();
+            let reply_msg = mock_reply_msg(res.messages[0].id, r.events, r.data);
+            reply(deps, mock_env(), reply_msg).unwrap()
+        };
+        let withdraw = |deps: DepsMut| {
+            let withdraw = OsmoPacket::Withdraw(WithdrawPacket {
+                denom: swap_denom.to_string(),
+                amount: Uint128::new(36601070),
+            });
+            let packet = mock_rcv_action_packet(withdraw, send_channel, 0, denom);
+            ibc_packet_receive(deps, mock_env(), packet).unwrap()
+        };
+
+        // the returned output is sent over the channel
+        let res = swap_return(deps.as_mut());
+        assert_eq!(1, res.messages.len());
+        let return_packet = match res.messages[0].msg.clone() {
+            CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => data,
+            _ => panic!("Unexpected return message: {:?}", res.messages[0]),
+        };
+        assert_eq!(Uint128::new(36601070), inflow(deps.as_ref()));
+
+        // over the quota the output is kept in the remote sender balance
+        let res = swap_return(deps.as_mut());
+        assert!(res.messages.is_empty());
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("return_to", "rate_limited")));
+        assert_eq!(Uint128::new(36601070), inflow(deps.as_ref()));
+        let res = withdraw(deps.as_mut());
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::RateLimitExceeded {
+            channel: send_channel.to_string(),
+            denom: swap_denom.to_string(),
+        };
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&exceeded, Some("withdraw")))
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(36601070, swap_denom)]
+        );
+
+        // the returned output times out, its quota is given back
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            return_packet,
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: send_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            4,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        assert_eq!(Uint128::zero(), inflow(deps.as_ref()));
+        let res = withdraw(deps.as_mut());
+        assert_eq!(1, res.messages.len());
+        assert_eq!(Uint128::new(36601070), inflow(deps.as_ref()));
+    }
+
+    #[test]
+    fn error_ack_json() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // contract error
+        let withdraw = OsmoPacket::Withdraw(WithdrawPacket {
+            denom: "uosmo".to_string(),
+            amount: Uint128::new(1),
+        });
+        let packet = mock_rcv_action_packet(withdraw, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert_eq!(
+            res.acknowledgement.as_slice(),
+            br#"{"error":"{\"code\":12,\"message\":\"Insufficient funds to redeem voucher on channel\",\"action\":\"withdraw\"}"}"#
+        );
+
+        // submessage error
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let packet = mock_rcv_action_packet(swap, send_channel, 1000, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let reply_msg = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Err("token amount calculated is lesser than min amount".into()),
+        };
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(
+            res.data.unwrap().as_slice(),
+            br#"{"error":"{\"code\":38,\"message\":\"token amount calculated is lesser than min amount\",\"action\":\"swap\"}"}"#
+        );
+    }
+
+    #[test]
+    fn receive_swap_action() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: swap_denom.to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+
+        let swap_packet_data = mock_ics20_data(876543210, denom, "", Some(swap));
+
+        // prepare some mock packets
+        let swap_packet = mock_ibc_rcv_packet(send_channel, &swap_packet_data);
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // query channel state|_|
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
+        assert_eq!(state.total_sent, vec![Amount::native(987654321, denom)]);
+
+        // Swap action
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Result(_)));
+
+        // Simulate swap reply
+        let r = mock_swap_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let gamm_ack_exp = AmountResultAck {
+            amount: Uint128::new(36601070u128),
+            denom: swap_denom.to_string(),
+            fee: None,
+        };
+        assert_eq!(gamm_ack, gamm_ack_exp);
+
+        // query channel state
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(111111111, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+        assert_eq!(
+            state.total_sent,
+            vec![
+                Amount::native(987654321, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+
+        // output is owned by the remote sender
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(36601070, swap_denom)]
+        );
+    }
+
+    #[test]
+    fn receive_packet_history() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+        let swap = |pool_id: u8| {
+            OsmoPacket::Swap(SwapPacket {
+                routes: vec![SwapAmountInRoute {
+                    pool_id: pool_id.into(),
+                    token_out_denom: swap_denom.to_string(),
+                }],
+                token_out_min_amount: 1u8.into(),
+                return_to: false,
+                forward: None,
+            })
+        };
+        let query_history = |deps: Deps, sender: Option<&str>, start_after: Option<u64>| {
+            let msg = QueryMsg::PacketHistory {
+                channel: send_channel.to_string(),
+                sender: sender.map(String::from),
+                start_after,
+                limit: None,
+            };
+            let res: ListPacketsResponse =
+                from_binary(&query(deps, mock_env(), msg).unwrap()).unwrap();
+            res.packets
+        };
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // plain transfer is pending until the bank send reply
+        let mut recv = mock_receive_packet(send_channel, 1000, denom, "local-rcpt");
+        recv.packet.sequence = 1;
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+        let record = query_history(deps.as_ref(), None, None)[0].record.clone();
+        let env = mock_env();
+        let expected = PacketRecord {
+            sender: "remote-sender".to_string(),
+            action: None,
+            denom: denom.to_string(),
+            amount: Uint128::new(1000),
+            status: PacketStatus::Pending,
+            tokens_out: vec![],
+            height: env.block.height,
+            time: env.block.time,
+        };
+        assert_eq!(record, expected);
+
+        let reply_msg = mock_reply_msg(res.messages[0].id, vec![], None);
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Packet {
+                channel: send_channel.to_string(),
+                sequence: 1,
+            },
+        )
+        .unwrap();
+        let packet: PacketResponse = from_binary(&raw).unwrap();
+        assert_eq!(1, packet.sequence);
+        assert_eq!(PacketStatus::Success, packet.record.status);
+
+        // swap records the output
+        let mut recv = mock_rcv_action_packet(swap(1), send_channel, 1000, denom);
+        recv.packet.sequence = 2;
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+        let r = mock_swap_response();
+        let reply_msg = mock_reply_msg(res.messages[0].id, r.events, r.data);
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+        // swap on a pool not allowed fails
+        let mut recv = mock_rcv_action_packet(swap(2), send_channel, 1000, denom);
+        recv.packet.sequence = 3;
+        ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+
+        let packets = query_history(deps.as_ref(), None, Some(1));
+        assert_eq!(2, packets.len());
+        assert_eq!(2, packets[0].sequence);
+        assert_eq!(Some("swap".to_string()), packets[0].record.action);
+        assert_eq!(PacketStatus::Success, packets[0].record.status);
+        assert_eq!(
+            vec![coin(36601070, swap_denom)],
+            packets[0].record.tokens_out
+        );
+        let err = ContractError::PoolNotAllowed { pool_id: 2 };
+        let failed = PacketStatus::Failed {
+            code: err.code(),
+            message: err.to_string(),
+        };
+        assert_eq!(3, packets[1].sequence);
+        assert_eq!(failed, packets[1].record.status);
+        assert!(packets[1].record.tokens_out.is_empty());
+
+        // filter by sender
+        assert_eq!(
+            3,
+            query_history(deps.as_ref(), Some("remote-sender"), None).len()
+        );
+        assert!(query_history(deps.as_ref(), Some("other"), None).is_empty());
+
+        // the oldest records are pruned over the limit
+        let msg = ExecuteMsg::UpdateConfig {
+            default_timeout: None,
+            lockup_id: None,
+            history_limit: Some(2),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        let mut recv = mock_receive_packet(send_channel, 1000, denom, "local-rcpt");
+        recv.packet.sequence = 4;
+        ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+
+        let packets = query_history(deps.as_ref(), None, None);
+        let sequences: Vec<_> = packets.iter().map(|p| p.sequence).collect();
+        assert_eq!(vec![3, 4], sequences);
+        let msg = QueryMsg::Packet {
+            channel: send_channel.to_string(),
+            sequence: 1,
+        };
+        query(deps.as_ref(), mock_env(), msg).unwrap_err();
+    }
+
+    #[test]
+    fn receive_swap_fee() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // 0.3% swap fee
+        let msg = ExecuteMsg::UpdateFees {
+            swap_bps: Some(30),
+            join_pool_bps: None,
+            exit_pool_bps: None,
+            lock_bps: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: swap_denom.to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 10000000, denom);
+
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert!(res.attributes.contains(&attr("fee", "30000uatom")));
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        // the action only gets the amount without fee
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PendingOps {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let pending: ListPendingOpsResponse = from_binary(&raw).unwrap();
+        assert_eq!(Uint128::new(9970000), pending.ops[0].args.amount);
+        assert_eq!(Uint128::new(30000), pending.ops[0].args.fee);
+
+        let r = mock_swap_response();
+        let res = reply(
+            deps.as_mut(),
+            mock_env(),
+            mock_reply_msg(reply_id, r.events, r.data),
+        );
+        let gamm_ack: AmountResultAck = get_ack_result(&res.unwrap().data.unwrap()).unwrap();
+        assert_eq!(Some(coin(30000, denom)), gamm_ack.fee);
+
+        let fees = |deps: Deps| -> FeesResponse {
+            from_binary(&query(deps, mock_env(), QueryMsg::Fees {}).unwrap()).unwrap()
+        };
+        assert_eq!(
+            vec![Amount::native(30000, denom)],
+            fees(deps.as_ref()).collected
+        );
+
+        // the fee is refunded when the swap fails
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        let reply_id = res.messages[0].id;
+        let reply_msg = Reply {
+            id: reply_id,
+            result: SubMsgResult::Err("swap failed".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(
+            vec![Amount::native(30000, denom)],
+            fees(deps.as_ref()).collected
+        );
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(977654321, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_withdraw_action() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: swap_denom.to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 876543210, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        let r = mock_swap_response();
+        let reply_msg = mock_reply_msg(res.messages[0].id, r.events, r.data);
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+        // Withdraw more than owned
+        let withdraw = OsmoPacket::Withdraw(WithdrawPacket {
+            denom: swap_denom.to_string(),
+            amount: Uint128::new(36601071),
+        });
+        let withdraw_packet = mock_rcv_action_packet(withdraw, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), withdraw_packet).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let insufficient = Ics20Ack::Error(error_ack_msg(
+            &ContractError::InsufficientFunds {},
+            Some("withdraw"),
+        ));
+        assert_eq!(ack, insufficient);
+
+        // Withdraw part of the balance
+        let withdraw = OsmoPacket::Withdraw(WithdrawPacket {
+            denom: swap_denom.to_string(),
+            amount: Uint128::new(36601000),
+        });
+        let withdraw_packet = mock_rcv_action_packet(withdraw, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), withdraw_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let ack: AmountResultAck = get_ack_result(&res.acknowledgement).unwrap();
+        assert_eq!(Uint128::new(36601000), ack.amount);
+
+        let withdraw_packet = match res.messages[0].msg.clone() {
+            CosmosMsg::Ibc(IbcMsg::SendPacket {
+                channel_id, data, ..
+            }) => {
+                assert_eq!(channel_id.as_str(), send_channel);
+                let msg: Ics20Packet = from_binary(&data).unwrap();
+                assert_eq!(msg.amount, Uint128::new(36601000));
+                assert_eq!(msg.denom.as_str(), swap_denom);
+                assert_eq!(msg.receiver.as_str(), "remote-sender");
+                msg
+            }
+            _ => panic!("Unexpected withdraw message: {:?}", res.messages[0]),
+        };
+
+        // channel balance is unchanged, tokens already accounted as sent
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(111111111, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(user.unwrap().balances, vec![Amount::native(70, swap_denom)]);
+
+        // Withdraw timeout, tokens are given back to the remote sender
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            to_binary(&withdraw_packet).unwrap(),
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: send_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            4,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        let res = ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(36601070, swap_denom)]
+        );
+    }
+
+    #[test]
+    fn receive_swap_pool_allowlist() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let swap_packet = |pool_ids: &[u64], amount: u128| {
+            let swap = OsmoPacket::Swap(SwapPacket {
+                routes: pool_ids
+                    .iter()
+                    .map(|id| SwapAmountInRoute {
+                        pool_id: (*id).into(),
+                        token_out_denom: "uosmo".to_string(),
+                    })
+                    .collect(),
+                token_out_min_amount: 1u8.into(),
+                return_to: false,
+                forward: None,
+            });
+            mock_rcv_action_packet(swap, send_channel, amount, denom)
+        };
+
+        // second pool of the route is not allowed
+        let res =
+            ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet(&[1, 2], 1000)).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let not_allowed = ContractError::PoolNotAllowed { pool_id: 2 };
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&not_allowed, Some("swap")))
+        );
+
+        // max input amount of the first pool
+        let allow = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 1u8.into(),
+            max_amount: Some(Uint128::new(999)),
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), allow).unwrap();
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet(&[1], 1000)).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::PoolMaxAmountExceeded {
+            pool_id: 1,
+            max_amount: Uint128::new(999),
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, Some("swap"))));
+
+        // rejected packets don't change the balance
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
+
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet(&[1], 999)).unwrap();
+        assert_eq!(1, res.messages.len());
+    }
+
+    #[test]
+    fn receive_swap_slippage() {
+        let send_channel = "channel-9";
+        let deps = setup(&["channel-1", "channel-7", send_channel]);
+        // 2 uatom for 1 uosmo, 1000uatom should get 500uosmo without price impact
+        let mut deps = with_gamm_querier(deps, "2.000000000000000000", "490");
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 1000, denom);
+
+        // no limit by default
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+
+        // 490uosmo is 2% under the spot price
+        let allow = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 1u8.into(),
+            max_amount: None,
+            max_slippage_bps: Some(100),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), allow).unwrap();
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet.clone()).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::SlippageExceeded {
+            price_impact_bps: 200,
+            max_slippage_bps: 100,
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, Some("swap"))));
+
+        deps.querier.token_out_amount = "497".to_string();
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet.clone()).unwrap();
+        assert_eq!(1, res.messages.len());
+
+        // the channel limit is lower than the pool one
+        let msg = ExecuteMsg::SetChannelMaxSlippage {
+            channel: send_channel.to_string(),
+            max_slippage_bps: Some(50),
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            msg.clone(),
+        );
+        assert!(err.is_err());
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(Some(50), state.max_slippage_bps);
+
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::SlippageExceeded {
+            price_impact_bps: 60,
+            max_slippage_bps: 50,
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, Some("swap"))));
+
+        // rejected packets don't change the balance, 2 swaps are pending
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987652321, denom)]);
+    }
+
+    #[test]
+    fn receive_swap_slippage_pool_ratio() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        // 0.5uatom for 1uosmo, 4uosmo for 1uion
+        let pools = vec![
+            (1, vec![coin(1000000, "uatom"), coin(2000000, "uosmo")]),
+            (2, vec![coin(4000000, "uosmo"), coin(1000000, "uion")]),
+        ];
+        let mut deps = with_pool_querier(deps, pools);
+        let denom = "uatom";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let max_slippage = |deps: DepsMut, max_slippage_bps: u64| {
+            let msg = ExecuteMsg::SetChannelMaxSlippage {
+                channel: send_channel.to_string(),
+                max_slippage_bps: Some(max_slippage_bps),
+            };
+            execute(deps, mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+        };
+        let swap_packet = |token_out_denoms: &[&str]| {
+            let routes = token_out_denoms
+                .iter()
+                .enumerate()
+                .map(|(i, token_out_denom)| SwapAmountInRoute {
+                    pool_id: (i as u64 + 1).into(),
+                    token_out_denom: token_out_denom.to_string(),
+                })
+                .collect();
+            let swap = OsmoPacket::Swap(SwapPacket {
+                routes,
+                token_out_min_amount: 1u8.into(),
+                return_to: false,
+                forward: None,
+            });
+            mock_rcv_action_packet(swap, send_channel, 10000, denom)
+        };
+
+        // 10000uatom get 19801uosmo, they cost 9900uatom at spot price: 1% price impact
+        max_slippage(deps.as_mut(), 99);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet(&["uosmo"])).unwrap();
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::SlippageExceeded {
+            price_impact_bps: 100,
+            max_slippage_bps: 99,
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, Some("swap"))));
+
+        max_slippage(deps.as_mut(), 100);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet(&["uosmo"])).unwrap();
+        assert_eq!(1, res.messages.len());
+
+        // 19801uosmo get 4925uion, they cost 9850uatom at the route price (2uatom for 1uion)
+        let packet = swap_packet(&["uosmo", "uion"]);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let exceeded = ContractError::SlippageExceeded {
+            price_impact_bps: 150,
+            max_slippage_bps: 100,
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&exceeded, Some("swap"))));
+    }
+
+    #[test]
+    fn price_impact_calculation() {
+        // 0.5 token in for one token out
+        let price = Decimal::from_str("0.5").unwrap();
+        let impact = |amount_in: u128, amount_out: u128| {
+            price_impact_bps(Uint128::new(amount_in), Uint128::new(amount_out), price)
+        };
+        assert_eq!(0, impact(100, 200));
+        assert_eq!(0, impact(100, 210));
+        assert_eq!(100, impact(100, 198));
+        assert_eq!(10_000, impact(100, 0));
+        assert_eq!(0, impact(0, 0));
+    }
+
+    #[test]
+    fn receive_swap_return_to() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1u8.into(),
+                token_out_denom: swap_denom.to_string(),
+            }],
+            token_out_min_amount: 1u8.into(),
+            return_to: true,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 876543210, denom);
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Swap action
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        // Simulate swap reply, output is sent back to remote sender
+        let r = mock_swap_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(Uint128::new(36601070u128), gamm_ack.amount);
+
+        let return_packet = match res.messages[0].msg.clone() {
+            CosmosMsg::Ibc(IbcMsg::SendPacket {
+                channel_id,
+                data,
+                timeout,
+            }) => {
+                let expected_timeout = mock_env().block.time.plus_seconds(DEFAULT_TIMEOUT);
+                assert_eq!(timeout, expected_timeout.into());
+                assert_eq!(channel_id.as_str(), send_channel);
+                let msg: Ics20Packet = from_binary(&data).unwrap();
+                assert_eq!(msg.amount, Uint128::new(36601070));
+                assert_eq!(msg.denom.as_str(), swap_denom);
+                assert_eq!(msg.sender.as_str(), MOCK_CONTRACT_ADDR);
+                assert_eq!(msg.receiver.as_str(), "remote-sender");
+                msg
+            }
+            _ => panic!("Unexpected return message: {:?}", res.messages[0]),
+        };
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(111111111, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+
+        // Return timeout, tokens keep in channel balance
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            to_binary(&return_packet).unwrap(),
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: send_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            4,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        let res = ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(111111111, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(36601070, swap_denom)]
+        );
+    }
+
+    #[test]
+    fn receive_swap_return_to_multi_hop() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "ibc/46B44899322F3CD854D2D46DEEF881958467CDD4B3B10086DA49296BBED94BED";
+        let hop_denom = "ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC";
+        let swap_denom = "uosmo";
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        // the channel holds tokens of the intermediate denom
+        for token in [denom, hop_denom] {
+            let msg = ExecuteMsg::Transfer(TransferMsg {
+                channel: send_channel.to_string(),
+                remote_address: "my-remote-address".to_string(),
+                timeout: None,
+            });
+            let info = mock_info("local-sender", &coins(500000000, token));
+            execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        }
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![
+                SwapAmountInRoute {
+                    pool_id: 1u8.into(),
+                    token_out_denom: hop_denom.to_string(),
+                },
+                SwapAmountInRoute {
+                    pool_id: 2u8.into(),
+                    token_out_denom: swap_denom.to_string(),
+                },
+            ],
+            token_out_min_amount: 1u8.into(),
+            return_to: true,
+            forward: None,
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 10000000, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        // the first token_swapped event is the intermediate hop
+        let r = mock_swap_multi_hop_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(ack.denom, swap_denom);
+        assert_eq!(ack.amount, Uint128::new(36601070));
+        match res.messages[0].msg.clone() {
+            CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => {
+                let msg: Ics20Packet = from_binary(&data).unwrap();
+                assert_eq!(msg.amount, Uint128::new(36601070));
+                assert_eq!(msg.denom.as_str(), swap_denom);
+            }
+            _ => panic!("Unexpected return message: {:?}", res.messages[0]),
+        };
+
+        // the returned output is added to the channel balance, not the intermediate denom
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(490000000, denom),
+                Amount::native(500000000, hop_denom),
+                Amount::native(36601070, swap_denom),
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_swap_forward() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+        let forward = Forward {
+            channel: "channel-42".to_string(),
+            receiver: "juno1receiver".to_string(),
+            timeout: Some(600),
+        };
+        let swap = |return_to: bool| {
+            OsmoPacket::Swap(SwapPacket {
+                routes: vec![SwapAmountInRoute {
+                    pool_id: 1u8.into(),
+                    token_out_denom: swap_denom.to_string(),
+                }],
+                token_out_min_amount: 1u8.into(),
+                return_to,
+                forward: Some(forward.clone()),
+            })
+        };
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // the output has one destination
+        let swap_packet = mock_rcv_action_packet(swap(true), send_channel, 100, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::ForwardWithReturnTo {};
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&err, Some("swap"))));
+
+        let swap_and_forward = |deps: DepsMut, sequence: u64| {
+            let mut deps = deps;
+            let swap_packet = mock_rcv_action_packet(swap(false), send_channel, 100000000, denom);
+            let res = ibc_packet_receive(deps.branch(), mock_env(), swap_packet).unwrap();
+            let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+            let r = mock_swap_response();
+            let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+            let res = reply(deps.branch(), mock_env(), reply_msg).unwrap();
+            let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+            assert_eq!(Uint128::new(36601070u128), gamm_ack.amount);
+            assert_eq!(1, res.messages.len());
+            assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+            let expected_timeout = mock_env().block.time.plus_seconds(600);
+            assert_eq!(
+                res.messages[0].msg,
+                forward_msg(coin(36601070, swap_denom), expected_timeout)
+            );
+            let forward_id = res.messages[0].id;
+            if sequence > 0 {
+                let sent = mock_transfer_response(sequence);
+                let reply_msg = mock_reply_msg(forward_id, sent.events, sent.data);
+                reply(deps, mock_env(), reply_msg).unwrap();
+            }
+            forward_id >> REPLY_KIND_BITS
+        };
+        let user_balance = |deps: Deps| {
+            let user = query_user_balances(deps, send_channel.into(), "remote-sender".into());
+            user.unwrap().balances
+        };
+        let ack = |sequence: u64, success: bool| {
+            SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+                channel: "channel-42".to_string(),
+                sequence,
+                ack: "e30=".to_string(),
+                success,
+            })
+        };
+
+        // the output is sent, not credited
+        let id = swap_and_forward(deps.as_mut(), 5);
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(887654321, denom)]);
+
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Forwards {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let forwards: ListForwardsResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            forwards.forwards,
+            vec![ForwardResponse {
+                id,
+                forward: ForwardRecord {
+                    channel: send_channel.to_string(),
+                    sender: "remote-sender".to_string(),
+                    amount: coin(36601070, swap_denom),
+                    forward_channel: "channel-42".to_string(),
+                    receiver: "juno1receiver".to_string(),
+                    timeout: mock_env().block.time.plus_seconds(600),
+                    sequence: 5,
+                }
+            }]
+        );
+
+        // the transfer can't be sent, the output is credited back
+        let failed_id = swap_and_forward(deps.as_mut(), 0);
+        let reply_msg = Reply {
+            id: op_reply_id(failed_id, FORWARD_ID),
+            result: SubMsgResult::Err("channel not found".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(
+            user_balance(deps.as_ref()),
+            vec![Amount::native(36601070, swap_denom)]
+        );
+
+        // the first transfer got an error ack, the callback credits it back once
+        sudo(deps.as_mut(), mock_env(), ack(5, false)).unwrap();
+        sudo(deps.as_mut(), mock_env(), ack(5, false)).unwrap();
+        assert_eq!(
+            user_balance(deps.as_ref()),
+            vec![Amount::native(73202140, swap_denom)]
+        );
+        assert!(!FORWARDS.has(deps.as_ref().storage, id));
+
+        // a delivered transfer isn't credited
+        let delivered_id = swap_and_forward(deps.as_mut(), 6);
+        sudo(deps.as_mut(), mock_env(), ack(6, true)).unwrap();
+        assert!(!FORWARDS.has(deps.as_ref().storage, delivered_id));
+        assert_eq!(
+            user_balance(deps.as_ref()),
+            vec![Amount::native(73202140, swap_denom)]
+        );
+
+        // the admin can only refund a transfer without callback once it timed out
+        let id = swap_and_forward(deps.as_mut(), 7);
+        let msg = ExecuteMsg::ResolveForward { id, refund: true };
+        let info = mock_info("someone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+        let info = mock_info(ADMIN_ADDR, &[]);
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::ForwardNotExpired { id });
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(600);
+        execute(deps.as_mut(), env.clone(), info.clone(), msg.clone()).unwrap();
+        assert_eq!(
+            user_balance(deps.as_ref()),
+            vec![Amount::native(109803210, swap_denom)]
+        );
+        let err = execute(deps.as_mut(), env, info, msg).unwrap_err();
+        assert_eq!(err, ContractError::NoSuchForward { id });
+
+        // a late callback doesn't credit it twice
+        let timeout = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+            channel: "channel-42".to_string(),
+            sequence: 7,
+        });
+        sudo(deps.as_mut(), mock_env(), timeout).unwrap();
+        assert_eq!(
+            user_balance(deps.as_ref()),
+            vec![Amount::native(109803210, swap_denom)]
+        );
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(587654321, denom),
+                Amount::native(109803210, swap_denom)
+            ]
+        );
+        assert!(!PENDING_OPS.has(deps.as_ref().storage, id));
+        assert!(!PENDING_OPS.has(deps.as_ref().storage, failed_id));
+    }
+
+    #[test]
+    fn receive_swap_forward_multi_hop() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "ibc/46B44899322F3CD854D2D46DEEF881958467CDD4B3B10086DA49296BBED94BED";
+        let hop_denom = "ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC";
+        let swap_denom = "uosmo";
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(500000000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let swap = OsmoPacket::Swap(SwapPacket {
+            routes: vec![
+                SwapAmountInRoute {
+                    pool_id: 1u8.into(),
+                    token_out_denom: hop_denom.to_string(),
+                },
+                SwapAmountInRoute {
+                    pool_id: 2u8.into(),
+                    token_out_denom: swap_denom.to_string(),
+                },
+            ],
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: Some(Forward {
+                channel: "channel-42".to_string(),
+                receiver: "juno1receiver".to_string(),
+                timeout: None,
+            }),
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 10000000, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), SWAP_ID, "swap").unwrap();
+
+        // the output of the last pool is forwarded, not the first hop denom
+        let r = mock_swap_multi_hop_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(ack.denom, swap_denom);
+        assert_eq!(1, res.messages.len());
+        let timeout = mock_env().block.time.plus_seconds(DEFAULT_TIMEOUT);
+        assert_eq!(
+            res.messages[0].msg,
+            forward_msg(coin(36601070, swap_denom), timeout)
+        );
+    }
+
+    #[test]
+    fn receive_split_swap_multi_hop() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let hop_denom = "ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC";
+        let swap_denom = "uosmo";
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // both routes go through pool 1 and pool 2
+        let route = |share_bps: u64| SplitRoute {
+            pools: vec![
+                SwapAmountInRoute {
+                    pool_id: 1u8.into(),
+                    token_out_denom: hop_denom.to_string(),
+                },
+                SwapAmountInRoute {
+                    pool_id: 2u8.into(),
+                    token_out_denom: swap_denom.to_string(),
+                },
+            ],
+            share_bps,
+        };
+        let split = SplitSwapPacket {
+            routes: vec![route(5000), route(5000)],
+            token_out_min_amount: 70000000u128.into(),
+        };
+        let packet = mock_rcv_action_packet(
+            OsmoPacket::SplitSwap(split.clone()),
+            send_channel,
+            100000000,
+            denom,
+        );
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let op_id = res.messages[0].id >> REPLY_KIND_BITS;
+        let split_msg = ExecuteMsg::SplitSwap {
+            op_id,
+            token_in: coin(100000000, denom),
+            split,
+        };
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info, split_msg).unwrap();
+        assert_eq!(2, res.messages.len());
+
+        // the first event of each route has the hop denom, the output is in the last one
+        for submsg in &res.messages {
+            let r = mock_swap_multi_hop_response();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_reply_msg(submsg.id, r.events, r.data),
+            )
+            .unwrap();
+        }
+        let reply_msg = mock_reply_msg(op_reply_id(op_id, SPLIT_SWAP_ID), vec![], None);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: SplitSwapResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            ack,
+            SplitSwapResultAck {
+                amount: Uint128::new(73202140),
+                denom: swap_denom.to_string(),
+                route_amounts: vec![Uint128::new(36601070), Uint128::new(36601070)],
+                fee: None,
+            }
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(73202140, swap_denom)]
+        );
+    }
+
+    #[test]
+    fn receive_split_swap() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uatom";
+        let swap_denom = "uosmo";
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let route = |pool_id: u8, token_out_denom: &str, share_bps: u64| SplitRoute {
+            pools: vec![SwapAmountInRoute {
+                pool_id: pool_id.into(),
+                token_out_denom: token_out_denom.to_string(),
+            }],
+            share_bps,
+        };
+        let split_swap = |routes: Vec<SplitRoute>, token_out_min_amount: u128| SplitSwapPacket {
+            routes,
+            token_out_min_amount: token_out_min_amount.into(),
+        };
+        let receive_split = |deps: DepsMut, split: SplitSwapPacket| {
+            let packet = mock_rcv_action_packet(
+                OsmoPacket::SplitSwap(split),
+                send_channel,
+                100000000,
+                denom,
+            );
+            ibc_packet_receive(deps, mock_env(), packet).unwrap()
+        };
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // the shares must add up to the whole input, the routes must have the same output
+        let invalid = [
+            (
+                split_swap(
+                    vec![route(1, swap_denom, 6000), route(2, swap_denom, 3000)],
+                    1,
+                ),
+                ContractError::InvalidSplitRoutes {},
+            ),
+            (
+                split_swap(
+                    vec![route(1, swap_denom, 10000), route(2, swap_denom, 0)],
+                    1,
+                ),
+                ContractError::InvalidSplitRoutes {},
+            ),
+            (
+                split_swap(
+                    vec![route(1, swap_denom, 10001), route(2, swap_denom, 0)],
+                    1,
+                ),
+                ContractError::InvalidSplitRoutes {},
+            ),
+            (
+                split_swap(
+                    vec![
+                        route(1, swap_denom, u64::MAX - 5000),
+                        route(2, swap_denom, 15001),
+                    ],
+                    1,
+                ),
+                ContractError::InvalidSplitRoutes {},
+            ),
+            (
+                split_swap(
+                    std::iter::repeat_n(route(1, swap_denom, 909), 10)
+                        .chain([route(2, swap_denom, 910)])
+                        .collect(),
+                    1,
+                ),
+                ContractError::InvalidSplitRoutes {},
+            ),
+            (
+                split_swap(vec![route(1, swap_denom, 6000), route(2, "uion", 4000)], 1),
+                ContractError::SplitRouteDenoms {},
+            ),
+            (
+                split_swap(
+                    vec![route(1, swap_denom, 6000), route(3, swap_denom, 4000)],
+                    1,
+                ),
+                ContractError::PoolNotAllowed { pool_id: 3 },
+            ),
+        ];
+        for (split, err) in invalid {
+            let res = receive_split(deps.as_mut(), split);
+            assert!(res.messages.is_empty());
+            let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+            assert_eq!(
+                ack,
+                Ics20Ack::Error(error_ack_msg(&err, Some("split_swap")))
+            );
+        }
+
+        // the routes are swapped by the contract
+        let split = split_swap(
+            vec![route(1, swap_denom, 6000), route(2, swap_denom, 4000)],
+            70000000,
+        );
+        let res = receive_split(deps.as_mut(), split.clone());
+        assert_eq!(1, res.messages.len());
+        assert_eq!(reply_kind(res.messages[0].id), SPLIT_SWAP_ID);
+        assert_eq!(res.messages[0].reply_on, ReplyOn::Always);
+        let op_id = res.messages[0].id >> REPLY_KIND_BITS;
+        let split_msg = ExecuteMsg::SplitSwap {
+            op_id,
+            token_in: coin(100000000, denom),
+            split,
+        };
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: MOCK_CONTRACT_ADDR.to_string(),
+                msg: to_binary(&split_msg).unwrap(),
+                funds: vec![],
+            })
+        );
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(887654321, denom)]);
+
+        let info = mock_info("someone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, split_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized);
+
+        let info = mock_info(MOCK_CONTRACT_ADDR, &[]);
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), split_msg).unwrap();
+        assert_eq!(2, res.messages.len());
+        for (submsg, amount) in res.messages.iter().zip(["60000000", "40000000"]) {
+            assert_eq!(submsg.reply_on, ReplyOn::Success);
+            assert_eq!(submsg.id, op_reply_id(op_id, SPLIT_ROUTE_ID));
+            match &submsg.msg {
+                CosmosMsg::Stargate { value, .. } => {
+                    let tx: cw_osmo_proto::osmosis::gamm::v1beta1::MsgSwapExactAmountIn =
+                        cw_osmo_proto::Message::decode(value.as_slice()).unwrap();
+                    assert_eq!(tx.token_in.unwrap().amount, amount);
+                }
+                _ => panic!("Unexpected route message: {:?}", submsg),
+            }
+        }
+
+        // each route gets 36601070uosmo
+        for id in [res.messages[0].id, res.messages[1].id] {
+            let r = mock_swap_response();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_reply_msg(id, r.events, r.data),
+            )
+            .unwrap();
+        }
+        let reply_msg = mock_reply_msg(op_reply_id(op_id, SPLIT_SWAP_ID), vec![], None);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: SplitSwapResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            ack,
+            SplitSwapResultAck {
+                amount: Uint128::new(73202140),
+                denom: swap_denom.to_string(),
+                route_amounts: vec![Uint128::new(36601070), Uint128::new(36601070)],
+                fee: None,
+            }
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![Amount::native(73202140, swap_denom)]
+        );
+        assert!(!SPLIT_SWAPS.has(deps.as_ref().storage, op_id));
+        assert!(!PENDING_OPS.has(deps.as_ref().storage, op_id));
+
+        // the combined output is below the min, all the routes are reverted
+        let split = split_swap(
+            vec![route(1, swap_denom, 6000), route(2, swap_denom, 4000)],
+            80000000,
+        );
+        let res = receive_split(deps.as_mut(), split.clone());
+        let op_id = res.messages[0].id >> REPLY_KIND_BITS;
+        let split_msg = ExecuteMsg::SplitSwap {
+            op_id,
+            token_in: coin(100000000, denom),
+            split,
+        };
+        let res = execute(deps.as_mut(), mock_env(), info, split_msg).unwrap();
+        let r = mock_swap_response();
+        let route_reply = mock_reply_msg(res.messages[0].id, r.events.clone(), r.data.clone());
+        reply(deps.as_mut(), mock_env(), route_reply).unwrap();
+        let route_reply = mock_reply_msg(res.messages[1].id, r.events, r.data);
+        let err = reply(deps.as_mut(), mock_env(), route_reply).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::SplitSwapMinOut {
+                amount: Uint128::new(73202140),
+                min: Uint128::new(80000000)
+            }
+        );
+
+        let reply_msg = Reply {
+            id: op_reply_id(op_id, SPLIT_SWAP_ID),
+            result: SubMsgResult::Err(err.to_string()),
+        };
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: Ics20Ack = from_binary(&res.data.unwrap()).unwrap();
+        assert!(matches!(ack, Ics20Ack::Error(_)));
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(887654321, denom),
+                Amount::native(73202140, swap_denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_swap_exact_out_action() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "ibc/46B44899322F3CD854D2D46DEEF881958467CDD4B3B10086DA49296BBED94BED";
+        let swap_denom = "uosmo";
+
+        let swap = OsmoPacket::SwapExactOut(SwapExactOutPacket {
+            routes: vec![SwapAmountOutRoute {
+                pool_id: 1u8.into(),
+                token_in_denom: denom.to_string(),
+            }],
+            token_out: coin(36601070, swap_denom),
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 15000000, denom);
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(20000000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Swap action
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), SWAP_OUT_ID, "swapexactamountout").unwrap();
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(5000000, denom)]);
+
+        // Simulate swap reply
+        let r = mock_swap_out_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: ExactOutResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let ack_exp = ExactOutResultAck {
+            token_in: AmountResultAck {
+                amount: Uint128::new(10000000),
+                denom: denom.to_string(),
+                fee: None,
+            },
+            token_out: AmountResultAck {
+                amount: Uint128::new(36601070),
+                denom: swap_denom.to_string(),
+                fee: None,
+            },
+            fee: None,
+        };
+        assert_eq!(ack, ack_exp);
+
+        // unspent input is refunded to channel balance
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(10000000, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+        assert_eq!(
+            state.total_sent,
+            vec![
+                Amount::native(20000000, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_swap_exact_out_multi_hop() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "ibc/46B44899322F3CD854D2D46DEEF881958467CDD4B3B10086DA49296BBED94BED";
+        let hop_denom = "ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC";
+        let swap_denom = "uosmo";
+        let msg = ExecuteMsg::AllowPool(AllowPoolMsg {
+            pool_id: 2u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN_ADDR, &[]), msg).unwrap();
+
+        let swap = OsmoPacket::SwapExactOut(SwapExactOutPacket {
+            routes: vec![
+                SwapAmountOutRoute {
+                    pool_id: 1u8.into(),
+                    token_in_denom: denom.to_string(),
+                },
+                SwapAmountOutRoute {
+                    pool_id: 2u8.into(),
+                    token_in_denom: hop_denom.to_string(),
+                },
+            ],
+            token_out: coin(36601070, swap_denom),
+        });
+        let swap_packet = mock_rcv_action_packet(swap, send_channel, 15000000, denom);
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(20000000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), swap_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), SWAP_OUT_ID, "swapexactamountout").unwrap();
+
+        // the first token_swapped event is the intermediate hop
+        let r = mock_swap_out_multi_hop_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: ExactOutResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(ack.token_in.amount, Uint128::new(10000000));
+        assert_eq!(
+            ack.token_out,
+            AmountResultAck {
+                amount: Uint128::new(36601070),
+                denom: swap_denom.to_string(),
+                fee: None,
+            }
+        );
+
+        // only the packet output is credited to the sender
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![
+                Amount::native(5000000, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(10000000, denom),
+                Amount::native(36601070, swap_denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_liquidty_actions() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uosmo";
+        let pool_denom = "gamm/pool/1";
+
+        let join_pool = OsmoPacket::JoinPool(JoinPoolPacket {
+            pool_id: 1u8.into(),
+            share_out_min_amount: 1u8.into(),
+        });
+
+        let exit_pool = OsmoPacket::ExitPool(ExitPoolPacket {
+            token_out_denom: denom.into(),
+            token_out_min_amount: 1u8.into(),
+            return_to: false,
+            forward: None,
         });
 
         let join_packet_data = mock_ics20_data(876543210, denom, "", Some(join_pool));
         let exit_packet_data =
             mock_ics20_data(74196992097318119147, pool_denom, "", Some(exit_pool));
 
-        // prepare some mock packets
-        let join_packet = mock_ibc_rcv_packet(send_channel, &join_packet_data);
-        let exit_packet = mock_ibc_rcv_packet(send_channel, &exit_packet_data);
+        // prepare some mock packets
+        let join_packet = mock_ibc_rcv_packet(send_channel, &join_packet_data);
+        let exit_packet = mock_ibc_rcv_packet(send_channel, &exit_packet_data);
+
+        // we transfer some tokens
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // query channel state|_|
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
+        assert_eq!(state.total_sent, vec![Amount::native(987654321, denom)]);
+
+        // Join pool action
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), JOIN_POOL_ID, "join").unwrap();
+
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Result(_)));
+
+        // Simulate join_pool reply
+        let r = mock_join_pool_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let gamm_ack_exp = AmountResultAck {
+            amount: Uint128::new(74196993097318119147u128),
+            denom: pool_denom.to_string(),
+            fee: None,
+        };
+        assert_eq!(gamm_ack, gamm_ack_exp);
+
+        // Exit pool action
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), exit_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = check_gamm_submsg(res.messages[0].clone(), EXIT_POOL_ID, "exit").unwrap();
+
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Result(_)));
+
+        // Simulate exit_pool reply
+        let r = mock_exit_pool_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let gamm_ack_exp = AmountResultAck {
+            amount: Uint128::new(9970022),
+            denom: denom.to_string(),
+            fee: None,
+        };
+        assert_eq!(gamm_ack, gamm_ack_exp);
+
+        // query channel state
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(1000000000000, pool_denom),
+                Amount::native(121081133, denom)
+            ]
+        );
+        assert_eq!(
+            state.total_sent,
+            vec![
+                Amount::native(74196993097318119147, pool_denom),
+                Amount::native(997624343, denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_liquidity_exact_out_actions() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uosmo";
+        let pool_denom = "gamm/pool/1";
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(10000000, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Join pool action, max 6000000uosmo
+        let join_pool = OsmoPacket::JoinPoolExactOut(JoinPoolExactOutPacket {
+            pool_id: 1u8.into(),
+            share_out_amount: Uint128::new(74196993097318119147),
+        });
+        let join_packet = mock_rcv_action_packet(join_pool, send_channel, 6000000, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), JOIN_POOL_OUT_ID, "joinswapshare").unwrap();
+
+        let r = mock_join_pool_exact_out_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: ExactOutResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(Uint128::new(4985011), ack.token_in.amount);
+        assert_eq!(denom, ack.token_in.denom);
+        assert_eq!(Uint128::new(74196993097318119147), ack.token_out.amount);
+        assert_eq!(pool_denom, ack.token_out.denom);
+
+        // unspent input is refunded to channel balance
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(74196993097318119147, pool_denom),
+                Amount::native(5014989, denom)
+            ]
+        );
+
+        // Exit pool action
+        let exit_pool = OsmoPacket::ExitPoolExactOut(ExitPoolExactOutPacket {
+            token_out: coin(4985011, denom),
+        });
+        let exit_packet =
+            mock_rcv_action_packet(exit_pool, send_channel, 74196993097318119147, pool_denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), exit_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), EXIT_POOL_OUT_ID, "exitswapextern").unwrap();
+
+        let r = mock_exit_pool_exact_out_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: ExactOutResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(Uint128::new(74196993097318119147), ack.token_in.amount);
+        assert_eq!(Uint128::new(4985011), ack.token_out.amount);
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(0, pool_denom),
+                Amount::native(10000000, denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_multi_liquidity_actions() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uosmo";
+        let ibc_denom = "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2";
+        let pool_denom = "gamm/pool/1";
+
+        // we transfer some tokens
+        for (amount, token) in [(10000000, denom), (200000, ibc_denom)] {
+            let msg = ExecuteMsg::Transfer(TransferMsg {
+                channel: send_channel.to_string(),
+                remote_address: "my-remote-address".to_string(),
+                timeout: None,
+            });
+            let info = mock_info("local-sender", &coins(amount, token));
+            execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        }
+
+        // Invalid max amounts
+        let join_pool = OsmoPacket::MultiJoinPool(MultiJoinPoolPacket {
+            pool_id: 1u8.into(),
+            share_out_amount: Uint128::new(74196993097318119147),
+            token_in_maxs: coins(150000, denom),
+        });
+        let join_packet = mock_rcv_action_packet(join_pool, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let invalid_maxs = Ics20Ack::Error(error_ack_msg(
+            &ContractError::InvalidTokenInMaxs {},
+            Some("multi_join_pool"),
+        ));
+        assert_eq!(ack, invalid_maxs);
+
+        // Multi join pool action
+        let join_pool = OsmoPacket::MultiJoinPool(MultiJoinPoolPacket {
+            pool_id: 1u8.into(),
+            share_out_amount: Uint128::new(74196993097318119147),
+            token_in_maxs: coins(150000, ibc_denom),
+        });
+        let join_packet = mock_rcv_action_packet(join_pool, send_channel, 5000000, denom);
+
+        // remote sender doesn't own the other assets
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet.clone()).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let insufficient = Ics20Ack::Error(error_ack_msg(
+            &ContractError::InsufficientFunds {},
+            Some("multi_join_pool"),
+        ));
+        assert_eq!(ack, insufficient);
+
+        increase_user_balance(
+            deps.as_mut().storage,
+            send_channel,
+            "remote-sender",
+            ibc_denom,
+            Uint128::new(150000),
+        )
+        .unwrap();
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), MULTI_JOIN_POOL_ID, "joinpool").unwrap();
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(50000, ibc_denom),
+                Amount::native(5000000, denom)
+            ]
+        );
+
+        // Simulate join reply, unspent inputs are refunded
+        let r = mock_multi_join_pool_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: PoolResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let ack_exp = PoolResultAck {
+            tokens_in: vec![coin(100008, ibc_denom), coin(4985011, denom)],
+            tokens_out: vec![coin(74196993097318119147, pool_denom)],
+            fee: None,
+        };
+        assert_eq!(ack, ack_exp);
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(74196993097318119147, pool_denom),
+                Amount::native(99992, ibc_denom),
+                Amount::native(5014989, denom)
+            ]
+        );
+        let user = query_user_balances(deps.as_ref(), send_channel.into(), "remote-sender".into());
+        assert_eq!(
+            user.unwrap().balances,
+            vec![
+                Amount::native(74196993097318119147, pool_denom),
+                Amount::native(49992, ibc_denom),
+                Amount::native(14989, denom)
+            ]
+        );
+
+        // Multi exit pool action
+        let exit_pool = OsmoPacket::MultiExitPool(MultiExitPoolPacket {
+            token_out_mins: vec![],
+        });
+        let exit_packet =
+            mock_rcv_action_packet(exit_pool, send_channel, 74196993097318119147, pool_denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), exit_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id =
+            check_gamm_submsg(res.messages[0].clone(), MULTI_EXIT_POOL_ID, "exitpool").unwrap();
+
+        // Simulate exit reply
+        let r = mock_multi_exit_pool_response();
+        let reply_msg = mock_reply_msg(reply_id, r.events, r.data);
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: PoolResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let ack_exp = PoolResultAck {
+            tokens_in: vec![coin(74196993097318119147, pool_denom)],
+            tokens_out: vec![coin(100008, ibc_denom), coin(4985011, denom)],
+            fee: None,
+        };
+        assert_eq!(ack, ack_exp);
+
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(
+            state.balances,
+            vec![
+                Amount::native(0, pool_denom),
+                Amount::native(200000, ibc_denom),
+                Amount::native(10000000, denom)
+            ]
+        );
+    }
+
+    #[test]
+    fn receive_action_channel() {
+        let send_channel = "channel-9";
+        let action_channel = "channel-10";
+        let mut deps = setup(&[send_channel]);
+        add_channel_with_version(deps.as_mut(), action_channel, ACTION_VERSION);
+        let lockup_contract = "lockup-addr".to_string();
+
+        let action_packet = |channel: &str, action: OsmoPacket| {
+            let data = ActionPacket {
+                channel: channel.to_string(),
+                sender: "remote-sender".to_string(),
+                action,
+            };
+            mock_ibc_rcv_packet(action_channel, &data)
+        };
+        let get_record = |deps: Deps| {
+            let msg = QueryMsg::Packet {
+                channel: action_channel.to_string(),
+                sequence: 3,
+            };
+            let raw = query(deps, mock_env(), msg).unwrap();
+            from_binary::<PacketResponse>(&raw).unwrap().record
+        };
+
+        // tokens can only be sent on transfer channels
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: action_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(1000, "uosmo"));
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::NotTransferChannel {
+                channel: action_channel.to_string()
+            }
+        );
+
+        // actions with tokens are rejected
+        let lock = OsmoPacket::Lock(LockPacket {
+            duration: 86400u64.into(),
+        });
+        let packet = action_packet(send_channel, lock);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert!(res.messages.is_empty());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::ActionRequiresTokens {
+            action: "lock".to_string(),
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&err, Some("lock"))));
+
+        // the lockups must be of a transfer channel on the same connection
+        let packet = action_packet(action_channel, OsmoPacket::LockupAccount {});
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::NotTransferChannel {
+            channel: action_channel.to_string(),
+        };
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&err, Some("lockup_account")))
+        );
 
-        // we transfer some tokens
-        let msg = ExecuteMsg::Transfer(TransferMsg {
+        let other_channel = ChannelInfo {
+            connection_id: "connection-5".to_string(),
+            ..mock_channel_info("channel-11")
+        };
+        CHANNEL_INFO
+            .save(deps.as_mut().storage, "channel-11", &other_channel)
+            .unwrap();
+        let packet = action_packet("channel-11", OsmoPacket::LockupAccount {});
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::ChannelOtherConnection {
+            channel: "channel-11".to_string(),
+        };
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&err, Some("lockup_account")))
+        );
+
+        // the action port must be paired with the transfer channel by the admin
+        let packet = action_packet(send_channel, OsmoPacket::LockupAccount {});
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let err = ContractError::ActionPortNotPaired {
             channel: send_channel.to_string(),
-            remote_address: "my-remote-address".to_string(),
-            timeout: None,
-        });
-        let info = mock_info("local-sender", &coins(987654321, denom));
-        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+            port_id: COUNTERPARTY_PORT.to_string(),
+        };
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&err, Some("lockup_account")))
+        );
 
-        // query channel state|_|
+        let pair = |channel: &str| ExecuteMsg::SetChannelActionPort {
+            channel: channel.to_string(),
+            port_id: Some(COUNTERPARTY_PORT.to_string()),
+        };
+        let info = mock_info("other", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, pair(send_channel)).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+        let info = mock_info(ADMIN_ADDR, &[]);
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            pair(action_channel),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            ContractError::NotTransferChannel {
+                channel: action_channel.to_string()
+            }
+        );
+        execute(deps.as_mut(), mock_env(), info, pair(send_channel)).unwrap();
         let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
-        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
-        assert_eq!(state.total_sent, vec![Amount::native(987654321, denom)]);
-
-        // Join pool action
-        let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
-        assert_eq!(1, res.messages.len());
-        check_gamm_submsg(res.messages[0].clone(), JOIN_POOL_ID, "join").unwrap();
+        assert_eq!(state.action_port, Some(COUNTERPARTY_PORT.to_string()));
 
+        // another approved port of the connection can't send actions for the remote senders
+        let foreign_channel = ChannelInfo {
+            counterparty_endpoint: IbcEndpoint {
+                port_id: "wasm.foreign".to_string(),
+                channel_id: "channel-8".to_string(),
+            },
+            version: ACTION_VERSION.to_string(),
+            ..mock_channel_info("channel-12")
+        };
+        CHANNEL_INFO
+            .save(deps.as_mut().storage, "channel-12", &foreign_channel)
+            .unwrap();
+        let data = ActionPacket {
+            channel: send_channel.to_string(),
+            sender: "remote-sender".to_string(),
+            action: OsmoPacket::LockupAccount {},
+        };
+        let packet = mock_ibc_rcv_packet("channel-12", &data);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert!(res.messages.is_empty());
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
-        assert!(matches!(ack, Ics20Ack::Result(_)));
-
-        // Simulate join_pool reply
-        let r = mock_join_pool_response();
-        let reply_msg = mock_reply_msg(JOIN_POOL_ID, r.events, r.data);
-        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
-        let gamm_ack_exp = AmountResultAck {
-            amount: Uint128::new(74196993097318119147u128),
-            denom: pool_denom.to_string(),
+        let err = ContractError::ActionPortNotPaired {
+            channel: send_channel.to_string(),
+            port_id: "wasm.foreign".to_string(),
         };
-        assert_eq!(gamm_ack, gamm_ack_exp);
+        assert_eq!(
+            ack,
+            Ics20Ack::Error(error_ack_msg(&err, Some("lockup_account")))
+        );
 
-        // Exit pool action
-        let res = ibc_packet_receive(deps.as_mut(), mock_env(), exit_packet).unwrap();
+        // the lockup account is created for the transfer channel
+        let packet = action_packet(send_channel, OsmoPacket::LockupAccount {});
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
         assert_eq!(1, res.messages.len());
-        check_gamm_submsg(res.messages[0].clone(), EXIT_POOL_ID, "exit").unwrap();
-
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
         assert!(matches!(ack, Ics20Ack::Result(_)));
+        assert_eq!(get_record(deps.as_ref()).status, PacketStatus::Pending);
 
-        // Simulate exit_pool reply
-        let r = mock_exit_pool_response();
-        let reply_msg = mock_reply_msg(EXIT_POOL_ID, r.events, r.data);
-        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
-        assert_eq!(0, res.messages.len());
-        let gamm_ack: AmountResultAck = get_ack_result(&res.data.unwrap()).unwrap();
-        let gamm_ack_exp = AmountResultAck {
-            amount: Uint128::new(9970022),
-            denom: denom.to_string(),
+        let init_ctr_response = Binary::from_base64("Cgtsb2NrdXAtYWRkcg==").unwrap();
+        let reply_msg = mock_reply_msg(res.messages[0].id, vec![], Some(init_ctr_response));
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let lockup = LOCKUP
+            .load(deps.as_ref().storage, (send_channel, "remote-sender"))
+            .unwrap();
+        assert_eq!(lockup_contract, lockup);
+
+        // the record is kept on the action channel
+        let record = get_record(deps.as_ref());
+        assert_eq!(record.status, PacketStatus::Success);
+        assert_eq!(record.sender, "remote-sender");
+        assert_eq!(record.action, Some("lockup_account".to_string()));
+
+        // claim through the lockup of the transfer channel
+        let claim = OsmoPacket::Claim(ClaimPacket {
+            denom: "uosmo".to_string(),
+        });
+        let packet = action_packet(send_channel, claim);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let lockup_msg = LockupExecuteMsg::Claim {
+            denom: "uosmo".to_string(),
         };
-        assert_eq!(gamm_ack, gamm_ack_exp);
+        assert_submsg_wasm(
+            res.messages[0].clone(),
+            CLAIM_TOKEN_ID,
+            ReplyOn::Always,
+            &lockup_contract,
+            lockup_msg,
+            vec![],
+        );
 
-        // query channel state
+        // a failed action doesn't change the balances of the transfer channel
+        let reply_msg = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Err("nothing to claim".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
-        assert_eq!(
-            state.balances,
-            vec![
-                Amount::native(1000000000000, pool_denom),
-                Amount::native(121081133, denom)
-            ]
-        );
-        assert_eq!(
-            state.total_sent,
-            vec![
-                Amount::native(74196993097318119147, pool_denom),
-                Amount::native(997624343, denom)
-            ]
-        );
+        assert!(state.balances.is_empty());
+        assert!(matches!(
+            get_record(deps.as_ref()).status,
+            PacketStatus::Failed { .. }
+        ));
     }
 
     #[test]
@@ -1127,7 +5375,10 @@ mod test {
         let lock = OsmoPacket::Lock(LockPacket {
             duration: 86400u64.into(),
         });
-        let unlock = OsmoPacket::Unlock(UnlockPacket { id: 1u64.into() });
+        let unlock = OsmoPacket::Unlock(UnlockPacket {
+            id: 1u64.into(),
+            coins: vec![],
+        });
 
         // prepare some mock packets
         let lockup_packet = mock_rcv_action_packet(lockup, send_channel, 0, denom);
@@ -1148,7 +5399,10 @@ mod test {
         assert_eq!(0, res.messages.len());
 
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
-        let no_lockup_account = Ics20Ack::Error(ContractError::LockupNotFound {}.to_string());
+        let no_lockup_account = Ics20Ack::Error(error_ack_msg(
+            &ContractError::LockupNotFound {},
+            Some("unlock"),
+        ));
         assert_eq!(ack, no_lockup_account);
 
         // Create Lockup account action
@@ -1160,12 +5414,17 @@ mod test {
 
         // Simulate reply lockup created (MsgInstantiateContractResponse {address: "lockup-addr"})
         let init_ctr_response = Binary::from_base64("Cgtsb2NrdXAtYWRkcg==").unwrap();
-        let reply_msg = mock_reply_msg(LOCKUP_ID, vec![], Some(init_ctr_response));
+        let reply_msg = mock_reply_msg(res.messages[0].id, vec![], Some(init_ctr_response));
         let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         assert_eq!(0, res.messages.len());
 
         let ack: LockupAck = get_ack_result(&res.data.unwrap()).unwrap();
         assert_eq!(ack.contract, lockup_contract);
+        let (channel, owner) = LOCKUP_OWNER
+            .load(deps.as_ref().storage, &lockup_contract)
+            .unwrap();
+        assert_eq!(send_channel, channel);
+        assert_eq!("remote-sender", owner);
 
         // Lock tokens
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), lock_packet).unwrap();
@@ -1190,11 +5449,14 @@ mod test {
         assert_eq!(1, res.messages.len());
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
         assert!(matches!(ack, Ics20Ack::Result(_)));
-        let lockup_msg = LockupExecuteMsg::Unlock { id: 1u64.into() };
+        let lockup_msg = LockupExecuteMsg::Unlock {
+            id: 1u64.into(),
+            coins: vec![],
+        };
         assert_submsg_wasm(
             res.messages[0].clone(),
             UNLOCK_TOKEN_ID,
-            ReplyOn::Error,
+            ReplyOn::Always,
             &lockup_contract,
             lockup_msg,
             vec![],
@@ -1202,13 +5464,64 @@ mod test {
 
         // Simluate unlock reply error
         let reply_msg = Reply {
-            id: UNLOCK_TOKEN_ID,
+            id: res.messages[0].id,
             result: SubMsgResult::Err("No found lockup id".to_string()),
         };
         let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         assert_eq!(0, res.messages.len());
         let ack: Ics20Ack = from_binary(&res.data.unwrap()).unwrap();
-        assert!(matches!(ack, Ics20Ack::Error(_)));
+        let failed = ContractError::SubMsgFailed {
+            msg: "No found lockup id".to_string(),
+        };
+        assert_eq!(ack, Ics20Ack::Error(error_ack_msg(&failed, Some("unlock"))));
+
+        // Partial unlock
+        let unlock = OsmoPacket::Unlock(UnlockPacket {
+            id: 1u64.into(),
+            coins: coins(100u128, denom),
+        });
+        let unlock_packet = mock_rcv_action_packet(unlock, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), unlock_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let lockup_msg = LockupExecuteMsg::Unlock {
+            id: 1u64.into(),
+            coins: coins(100u128, denom),
+        };
+        assert_submsg_wasm(
+            res.messages[0].clone(),
+            UNLOCK_TOKEN_ID,
+            ReplyOn::Always,
+            &lockup_contract,
+            lockup_msg,
+            vec![],
+        );
+
+        // Unlock all
+        let unlock_all_packet =
+            mock_rcv_action_packet(OsmoPacket::UnlockAll {}, send_channel, 0, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), unlock_all_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_submsg_wasm(
+            res.messages[0].clone(),
+            UNLOCK_ALL_ID,
+            ReplyOn::Always,
+            &lockup_contract,
+            LockupExecuteMsg::UnlockAll {},
+            vec![],
+        );
+
+        // Simulate reply with the unlocking locks
+        let unlock_data = json_to_reply_proto(
+            r#"{"unlocks":[{"lock_id":"1","end_time":"2022-10-23 13:11:09 +0000 UTC"}]}"#,
+        );
+        let reply_msg = mock_reply_msg(res.messages[0].id, vec![], Some(unlock_data.into()));
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let ack: UnlockResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        let expected = vec![UnlockingLockAck {
+            lock_id: 1u64.into(),
+            end_time: "2022-10-23 13:11:09 +0000 UTC".to_string(),
+        }];
+        assert_eq!(expected, ack.unlocks);
 
         // query channel state
         let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
@@ -1256,7 +5569,7 @@ mod test {
 
         // Simulate reply lockup created (MsgInstantiateContractResponse {address: "lockup-addr"})
         let init_ctr_response = Binary::from_base64("Cgtsb2NrdXAtYWRkcg==").unwrap();
-        let reply_msg = mock_reply_msg(LOCKUP_ID, vec![], Some(init_ctr_response));
+        let reply_msg = mock_reply_msg(res.messages[0].id, vec![], Some(init_ctr_response));
         let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         assert_eq!(0, res.messages.len());
 
@@ -1266,6 +5579,7 @@ mod test {
         // Claim lockup rewards.
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), claim_packet).unwrap();
         assert_eq!(1, res.messages.len());
+        let reply_id = res.messages[0].id;
         let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
         assert!(matches!(ack, Ics20Ack::Result(_)));
         let lockup_msg = LockupExecuteMsg::Claim {
@@ -1285,7 +5599,7 @@ mod test {
             "{{\"amount\":\"{}\",\"denom\":\"{}\"}}",
             rewards, denom
         ));
-        let reply_msg = mock_reply_msg(CLAIM_TOKEN_ID, vec![], Some(rewards_data.into()));
+        let reply_msg = mock_reply_msg(reply_id, vec![], Some(rewards_data.into()));
         let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         assert_eq!(0, res.messages.len());
 
@@ -1299,6 +5613,91 @@ mod test {
         assert_eq!(state.total_sent, vec![Amount::native(987700000, denom)]);
     }
 
+    #[test]
+    fn receive_extend_lock() {
+        let send_channel = "channel-9";
+        let mut deps = setup(&["channel-1", "channel-7", send_channel]);
+        let denom = "uosmo";
+        let lockup_contract = "lockup-addr".to_string();
+
+        let extend = OsmoPacket::ExtendLock(ExtendLockPacket {
+            id: 7u64.into(),
+            duration: 1209600u64.into(),
+        });
+        let extend_packet = mock_rcv_action_packet(extend.clone(), send_channel, 0, denom);
+
+        // we transfer some tokens to register denom
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: send_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(987654321, denom));
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+        // Extend invalid, no lockup account
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), extend_packet.clone()).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        let no_lockup_account = Ics20Ack::Error(error_ack_msg(
+            &ContractError::LockupNotFound {},
+            Some("extend_lock"),
+        ));
+        assert_eq!(ack, no_lockup_account);
+
+        // Extend invalid, the packet transfers tokens
+        save_lockup(
+            deps.as_mut().storage,
+            send_channel,
+            "remote-sender",
+            &lockup_contract,
+        )
+        .unwrap();
+        let payable_packet = mock_rcv_action_packet(extend, send_channel, 100, denom);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), payable_packet).unwrap();
+        assert_eq!(0, res.messages.len());
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Error(_)));
+
+        // Extend lock
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), extend_packet).unwrap();
+        assert_eq!(1, res.messages.len());
+        let reply_id = res.messages[0].id;
+        let ack: Ics20Ack = from_binary(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, Ics20Ack::Result(_)));
+        let lockup_msg = LockupExecuteMsg::ExtendLock {
+            id: 7u64.into(),
+            duration: 1209600u64.into(),
+        };
+        assert_submsg_wasm(
+            res.messages[0].clone(),
+            EXTEND_LOCK_ID,
+            ReplyOn::Always,
+            &lockup_contract,
+            lockup_msg,
+            vec![],
+        );
+
+        // Simulate reply with the new duration
+        let extend_data = json_to_reply_proto(r#"{"lock_id":"7","duration":"1209600"}"#);
+        let reply_msg = mock_reply_msg(reply_id, vec![], Some(extend_data.into()));
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        let ack: ExtendLockResultAck = get_ack_result(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            ack,
+            ExtendLockResultAck {
+                lock_id: 7u64.into(),
+                duration: 1209600u64.into(),
+            }
+        );
+
+        // Balances are unchanged
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(987654321, denom)]);
+    }
+
     #[test]
     fn reply_on_errors() {
         let send_channel = "channel-9";
@@ -1311,7 +5710,7 @@ mod test {
             share_out_min_amount: 1u8.into(),
         });
         let reply_msg = Reply {
-            id: JOIN_POOL_ID,
+            id: op_reply_id(1, JOIN_POOL_ID),
             result: SubMsgResult::Err(error_msg.clone()),
         };
 
@@ -1331,10 +5730,290 @@ mod test {
         );
         let res = ibc_packet_receive(deps.as_mut(), mock_env(), join_packet).unwrap();
         assert_eq!(1, res.messages.len());
+        assert_eq!(reply_msg.id, res.messages[0].id);
+
+        // Operation is pending until the reply
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PendingOps {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let pending: ListPendingOpsResponse = from_binary(&raw).unwrap();
+        assert_eq!(1, pending.ops.len());
+        assert_eq!(1, pending.ops[0].id);
+        assert_eq!(Uint128::new(1000), pending.ops[0].args.amount);
 
         // Reply with error result
         let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
         let ack: Ics20Ack = from_binary(&res.data.unwrap()).unwrap();
-        assert!(matches!(ack, Ics20Ack::Error(err) if err == error_msg));
+        let failed = ContractError::SubMsgFailed { msg: error_msg };
+        let expected = Ics20Ack::Error(error_ack_msg(&failed, Some("join_pool")));
+        assert_eq!(ack, expected);
+
+        // balance restored and operation removed
+        let state = query_channel(deps.as_ref(), send_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(1000, denom)]);
+        assert!(!PENDING_OPS.has(deps.as_ref().storage, 1));
+    }
+
+    #[test]
+    fn close_and_migrate_channel() {
+        let closed_channel = "channel-9";
+        let new_channel = "channel-10";
+        let mut deps = setup(&[closed_channel, new_channel]);
+        let admin = mock_info(ADMIN_ADDR, &[]);
+
+        let msg = ExecuteMsg::Transfer(TransferMsg {
+            channel: closed_channel.to_string(),
+            remote_address: "my-remote-address".to_string(),
+            timeout: None,
+        });
+        let info = mock_info("local-sender", &coins(1000, "uatom"));
+        execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap();
+        let storage = deps.as_mut().storage;
+        increase_user_balance(
+            storage,
+            closed_channel,
+            "remote-owner",
+            "uosmo",
+            50u128.into(),
+        )
+        .unwrap();
+        save_lockup(storage, closed_channel, "remote-owner", "lockup-1").unwrap();
+        save_lockup(storage, closed_channel, "other-owner", "lockup-2").unwrap();
+
+        // we never close the channel
+        let close = mock_ibc_channel_close_init(closed_channel, IbcOrder::Unordered, ICS20_VERSION);
+        let err = ibc_channel_close(deps.as_mut(), mock_env(), close).unwrap_err();
+        assert_eq!(err, ContractError::CannotClose {});
+
+        // closed by the counterparty
+        let close =
+            mock_ibc_channel_close_confirm(closed_channel, IbcOrder::Unordered, ICS20_VERSION);
+        ibc_channel_close(deps.as_mut(), mock_env(), close).unwrap();
+        let state = query_channel(deps.as_ref(), closed_channel.to_string()).unwrap();
+        assert_eq!(state.status, ChannelStatus::Closed);
+        assert_eq!(state.balances, vec![Amount::native(1000, "uatom")]);
+
+        let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelClosed {
+                id: closed_channel.to_string()
+            }
+        );
+
+        let migrate = |new_channel: &str, limit: Option<u32>| ExecuteMsg::MigrateChannel {
+            channel: closed_channel.to_string(),
+            new_channel: new_channel.to_string(),
+            limit,
+        };
+        let info = mock_info("someone", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info, migrate(new_channel, None)).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate(closed_channel, None),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelClosed {
+                id: closed_channel.to_string()
+            }
+        );
+        let msg = ExecuteMsg::MigrateChannel {
+            channel: new_channel.to_string(),
+            new_channel: closed_channel.to_string(),
+            limit: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), admin.clone(), msg).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelNotClosed {
+                id: new_channel.to_string()
+            }
+        );
+
+        // the replacement must be on the same connection
+        let other_info = ChannelInfo {
+            id: "channel-11".to_string(),
+            counterparty_endpoint: IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-35".to_string(),
+            },
+            connection_id: "connection-3".to_string(),
+            version: ICS20_VERSION.to_string(),
+        };
+        CHANNEL_INFO
+            .save(deps.as_mut().storage, "channel-11", &other_info)
+            .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate("channel-11", None),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelMismatch {
+                id: closed_channel.to_string(),
+                channel: "channel-11".to_string()
+            }
+        );
+
+        // a lockup collision is found before anything is moved
+        save_lockup(
+            deps.as_mut().storage,
+            new_channel,
+            "other-owner",
+            "lockup-3",
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate(new_channel, None),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::LockupExists {
+                channel: new_channel.to_string(),
+                owner: "other-owner".to_string()
+            }
+        );
+        let state = query_channel(deps.as_ref(), closed_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(1000, "uatom")]);
+        assert_eq!(state.status, ChannelStatus::Closed);
+        assert!(LOCKUP.has(deps.as_ref().storage, (closed_channel, "remote-owner")));
+        LOCKUP.remove(deps.as_mut().storage, (new_channel, "other-owner"));
+        LOCKUP_OWNER.remove(deps.as_mut().storage, "lockup-3");
+
+        // the balances are moved first, then the user balances and the lockups
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate(new_channel, Some(2)),
+        )
+        .unwrap();
+        let state = query_channel(deps.as_ref(), closed_channel.to_string()).unwrap();
+        assert!(state.balances.is_empty());
+        assert_eq!(
+            state.status,
+            ChannelStatus::Migrating {
+                channel: new_channel.to_string()
+            }
+        );
+        let state = query_channel(deps.as_ref(), new_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(1000, "uatom")]);
+        assert_eq!(state.total_sent, vec![Amount::native(1000, "uatom")]);
+        let balances = query_user_balances(
+            deps.as_ref(),
+            new_channel.to_string(),
+            "remote-owner".to_string(),
+        )
+        .unwrap();
+        assert_eq!(balances.balances, vec![Amount::native(50, "uosmo")]);
+
+        // the replacement can't change
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate("channel-11", None),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelMigrated {
+                id: closed_channel.to_string(),
+                channel: new_channel.to_string()
+            }
+        );
+
+        // a packet sent before the migration times out, the new channel is updated
+        let packet = Ics20Packet::new(
+            1000u128.into(),
+            "uatom",
+            "local-sender",
+            "my-remote-address",
+        );
+        let timeout_msg = IbcPacketTimeoutMsg::new(IbcPacket::new(
+            to_binary(&packet).unwrap(),
+            IbcEndpoint {
+                port_id: CONTRACT_PORT.to_string(),
+                channel_id: closed_channel.to_string(),
+            },
+            IbcEndpoint {
+                port_id: REMOTE_PORT.to_string(),
+                channel_id: "channel-1234".to_string(),
+            },
+            1,
+            Timestamp::from_seconds(1665321069).into(),
+        ));
+        let res = ibc_packet_timeout(deps.as_mut(), mock_env(), timeout_msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        let state = query_channel(deps.as_ref(), new_channel.to_string()).unwrap();
+        assert_eq!(state.balances, vec![Amount::native(0, "uatom")]);
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            admin.clone(),
+            migrate(new_channel, None),
+        )
+        .unwrap();
+        assert!(res
+            .attributes
+            .contains(&Attribute::new("status", "migrated")));
+        for (owner, address) in [("remote-owner", "lockup-1"), ("other-owner", "lockup-2")] {
+            assert!(!LOCKUP.has(deps.as_ref().storage, (closed_channel, owner)));
+            let lockup = LOCKUP
+                .load(deps.as_ref().storage, (new_channel, owner))
+                .unwrap();
+            assert_eq!(lockup, address);
+            let (channel, _) = LOCKUP_OWNER.load(deps.as_ref().storage, address).unwrap();
+            assert_eq!(channel, new_channel);
+        }
+
+        let raw = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ClosedChannels {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        let res: ListChannelStatusResponse = from_binary(&raw).unwrap();
+        assert_eq!(
+            res.channels,
+            vec![ChannelStatusResponse {
+                id: closed_channel.to_string(),
+                status: ChannelStatus::Migrated {
+                    channel: new_channel.to_string()
+                }
+            }]
+        );
+
+        let err =
+            execute(deps.as_mut(), mock_env(), admin, migrate(new_channel, None)).unwrap_err();
+        assert_eq!(
+            err,
+            ContractError::ChannelMigrated {
+                id: closed_channel.to_string(),
+                channel: new_channel.to_string()
+            }
+        );
     }
 }
@@ -1,7 +1,10 @@
-use cosmwasm_std::{Binary, Uint128, Uint64};
+use cosmwasm_std::{from_slice, Binary, Coin, Uint128, Uint64};
 use schemars::JsonSchema;
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 
+use crate::ContractError;
+
 /// The format for sending an ics20 packet.
 /// Proto defined here: https://github.com/cosmos/cosmos-sdk/blob/v0.42.0/proto/ibc/applications/transfer/v1/transfer.proto#L11-L20
 /// This is compatible with the JSON serialization
@@ -18,6 +21,9 @@ pub struct Ics20Packet {
     /// Action packet
     #[serde(skip_serializing_if = "Option::is_none")]
     pub action: Option<OsmoPacket>,
+    /// optional memo, it can carry the action with the ibc-hooks format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
 }
 
 impl Ics20Packet {
@@ -28,8 +34,68 @@ impl Ics20Packet {
             sender: sender.to_string(),
             receiver: receiver.to_string(),
             action: None,
+            memo: None,
         }
     }
+
+    /// The action set in the action field, or in the memo as `{"wasm": {"msg": {...}}}`.
+    /// Memos without the wasm key are plain transfers.
+    pub fn osmo_action(&self, contract: &str) -> Result<Option<OsmoPacket>, ContractError> {
+        let memo = match self.memo.as_deref() {
+            Some(memo) if has_wasm_key(memo) => memo,
+            _ => return Ok(self.action.clone()),
+        };
+        if self.action.is_some() {
+            return Err(ContractError::DuplicateAction {});
+        }
+
+        let memo: WasmMemo = from_slice(memo.as_bytes())?;
+        if let Some(memo_contract) = memo.wasm.contract {
+            if memo_contract != contract {
+                return Err(ContractError::MemoOtherContract {
+                    contract: memo_contract,
+                });
+            }
+        }
+        Ok(Some(memo.wasm.msg))
+    }
+}
+
+/// ibc-hooks style memo, the contract is optional
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct WasmMemo {
+    pub wasm: WasmMemoMsg,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct WasmMemoMsg {
+    /// must be this contract if set
+    pub contract: Option<String>,
+    pub msg: OsmoPacket,
+}
+
+// only used to look for the wasm key, other memo fields are ignored
+#[derive(Deserialize)]
+struct MemoKeys {
+    wasm: Option<IgnoredAny>,
+}
+
+fn has_wasm_key(memo: &str) -> bool {
+    matches!(
+        from_slice::<MemoKeys>(memo.as_bytes()),
+        Ok(MemoKeys { wasm: Some(_) })
+    )
+}
+
+/// The format of the packets received on action channels, they carry an action without tokens.
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ActionPacket {
+    /// transfer channel of this chain on the same connection, the action uses its lockups and
+    /// balances
+    pub channel: String,
+    /// the sender address
+    pub sender: String,
+    pub action: OsmoPacket,
 }
 
 /// This is a generic ICS acknowledgement format.
@@ -42,6 +108,17 @@ pub enum Ics20Ack {
     Error(String),
 }
 
+/// Content of the error acks, serialized as JSON in the Ics20Ack error string
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ErrorAck {
+    /// Stable error code, see `ContractError::code`
+    pub code: u32,
+    pub message: String,
+    /// Action of the packet, if it was parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+}
+
 pub struct Voucher {
     pub denom: String,
 }
@@ -51,14 +128,69 @@ pub struct Voucher {
 pub enum OsmoPacket {
     /// Swap assets.
     Swap(SwapPacket),
+    /// Swap assets to get an exact output amount.
+    SwapExactOut(SwapExactOutPacket),
+    /// Swap assets over several routes, each with a share of the input.
+    SplitSwap(SplitSwapPacket),
     /// Join a specific pool.
     JoinPool(JoinPoolPacket),
     /// Exit a specific pool.
     ExitPool(ExitPoolPacket),
+    /// Join a specific pool to get an exact share amount.
+    JoinPoolExactOut(JoinPoolExactOutPacket),
+    /// Exit a specific pool to get an exact token amount.
+    ExitPoolExactOut(ExitPoolExactOutPacket),
+    /// Join a specific pool with all pool assets (no swap).
+    MultiJoinPool(MultiJoinPoolPacket),
+    /// Exit a specific pool into all pool assets (no swap).
+    MultiExitPool(MultiExitPoolPacket),
     LockupAccount {},
     Lock(LockPacket),
     Claim(ClaimPacket),
     Unlock(UnlockPacket),
+    /// Begin unlocking all the locks of the lockup account.
+    UnlockAll {},
+    /// Extend the duration of a lock, it must be longer than the current one.
+    ExtendLock(ExtendLockPacket),
+    /// Send tokens from the sender balance back to the sender.
+    Withdraw(WithdrawPacket),
+}
+
+impl OsmoPacket {
+    /// The action name, as used in the packet JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            OsmoPacket::Swap(_) => "swap",
+            OsmoPacket::SwapExactOut(_) => "swap_exact_out",
+            OsmoPacket::SplitSwap(_) => "split_swap",
+            OsmoPacket::JoinPool(_) => "join_pool",
+            OsmoPacket::ExitPool(_) => "exit_pool",
+            OsmoPacket::JoinPoolExactOut(_) => "join_pool_exact_out",
+            OsmoPacket::ExitPoolExactOut(_) => "exit_pool_exact_out",
+            OsmoPacket::MultiJoinPool(_) => "multi_join_pool",
+            OsmoPacket::MultiExitPool(_) => "multi_exit_pool",
+            OsmoPacket::LockupAccount {} => "lockup_account",
+            OsmoPacket::Lock(_) => "lock",
+            OsmoPacket::Claim(_) => "claim",
+            OsmoPacket::Unlock(_) => "unlock",
+            OsmoPacket::UnlockAll {} => "unlock_all",
+            OsmoPacket::ExtendLock(_) => "extend_lock",
+            OsmoPacket::Withdraw(_) => "withdraw",
+        }
+    }
+
+    /// Actions that don't use the transferred tokens, they can be sent on action channels
+    pub fn is_tokenless(&self) -> bool {
+        matches!(
+            self,
+            OsmoPacket::LockupAccount {}
+                | OsmoPacket::Claim(_)
+                | OsmoPacket::Unlock(_)
+                | OsmoPacket::UnlockAll {}
+                | OsmoPacket::ExtendLock(_)
+                | OsmoPacket::Withdraw(_)
+        )
+    }
 }
 
 /// Swap Packet
@@ -66,6 +198,23 @@ pub enum OsmoPacket {
 pub struct SwapPacket {
     pub routes: Vec<SwapAmountInRoute>,
     pub token_out_min_amount: Uint128,
+    /// Send the output tokens back to the remote sender over the same channel
+    #[serde(default)]
+    pub return_to: bool,
+    /// Send the output tokens to another chain
+    #[serde(default)]
+    pub forward: Option<Forward>,
+}
+
+/// Destination of the action output, sent with an ics20 transfer from Osmosis. The output is
+/// credited back to the remote sender if the transfer fails or times out
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Forward {
+    /// Osmosis channel of the transfer module to the destination chain
+    pub channel: String,
+    pub receiver: String,
+    /// How long the transfer lives in seconds. If not specified, use default_timeout
+    pub timeout: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -74,6 +223,35 @@ pub struct SwapAmountInRoute {
     pub token_out_denom: String,
 }
 
+/// SplitSwap Packet, the transferred amount is split between the routes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SplitSwapPacket {
+    pub routes: Vec<SplitRoute>,
+    /// Min output amount of all the routes together
+    pub token_out_min_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SplitRoute {
+    pub pools: Vec<SwapAmountInRoute>,
+    /// Share of the transferred amount in basis points, the shares add up to 10000.
+    /// The last route gets the rounding remainder
+    pub share_bps: u64,
+}
+
+/// SwapExactOut Packet, the transferred amount is the max input amount
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SwapExactOutPacket {
+    pub routes: Vec<SwapAmountOutRoute>,
+    pub token_out: Coin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SwapAmountOutRoute {
+    pub pool_id: Uint64,
+    pub token_in_denom: String,
+}
+
 /// JoinPool Packet
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct JoinPoolPacket {
@@ -81,11 +259,45 @@ pub struct JoinPoolPacket {
     pub share_out_min_amount: Uint128,
 }
 
-/// JoinPool Packet
+/// ExitPool Packet
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ExitPoolPacket {
     pub token_out_denom: String,
     pub token_out_min_amount: Uint128,
+    /// Send the output tokens back to the remote sender over the same channel
+    #[serde(default)]
+    pub return_to: bool,
+    /// Send the output tokens to another chain
+    #[serde(default)]
+    pub forward: Option<Forward>,
+}
+
+/// JoinPoolExactOut Packet, the transferred amount is the max input amount
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct JoinPoolExactOutPacket {
+    pub pool_id: Uint64,
+    pub share_out_amount: Uint128,
+}
+
+/// ExitPoolExactOut Packet, the transferred amount is the max share input amount
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExitPoolExactOutPacket {
+    pub token_out: Coin,
+}
+
+/// MultiJoinPool Packet, the transferred token is used as max amount of its denom,
+/// the other assets are taken from the sender balance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MultiJoinPoolPacket {
+    pub pool_id: Uint64,
+    pub share_out_amount: Uint128,
+    pub token_in_maxs: Vec<Coin>,
+}
+
+/// MultiExitPool Packet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MultiExitPoolPacket {
+    pub token_out_mins: Vec<Coin>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -101,17 +313,94 @@ pub struct ClaimPacket {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct UnlockPacket {
     pub id: Uint64,
+    /// Unlock only these coins of the lock, the full lock if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub coins: Vec<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExtendLockPacket {
+    pub id: Uint64,
+    /// New duration in seconds
+    pub duration: Uint64,
+}
+
+/// Withdraw Packet, the amount is taken from the sender balance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawPacket {
+    pub denom: String,
+    pub amount: Uint128,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct AmountResultAck {
     pub amount: Uint128,
     pub denom: String,
+    /// Protocol fee taken from the transferred amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SplitSwapResultAck {
+    /// Output of all the routes
+    pub amount: Uint128,
+    pub denom: String,
+    /// Output of each route, in the packet order
+    pub route_amounts: Vec<Uint128>,
+    /// Protocol fee taken from the transferred amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExactOutResultAck {
+    /// Amount spent
+    pub token_in: AmountResultAck,
+    /// Amount received
+    pub token_out: AmountResultAck,
+    /// Protocol fee taken from the transferred amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PoolResultAck {
+    /// Tokens spent
+    pub tokens_in: Vec<Coin>,
+    /// Tokens received
+    pub tokens_out: Vec<Coin>,
+    /// Protocol fee taken from the transferred amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Coin>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct LockResultAck {
     pub lock_id: Uint64,
+    /// Protocol fee taken from the transferred amount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Coin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UnlockResultAck {
+    /// Locks that started unlocking
+    pub unlocks: Vec<UnlockingLockAck>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UnlockingLockAck {
+    pub lock_id: Uint64,
+    /// Unlock time, as reported by osmosis
+    pub end_time: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExtendLockResultAck {
+    pub lock_id: Uint64,
+    /// New duration in seconds
+    pub duration: Uint64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -3,6 +3,7 @@ pub mod contract;
 mod error;
 pub mod ibc;
 mod ibc_msg;
+mod migrations;
 pub mod msg;
 mod parse;
 pub mod state;
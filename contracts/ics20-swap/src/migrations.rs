@@ -0,0 +1,113 @@
+// v1 format is anything older than 0.2.0
+pub mod v1 {
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    use cosmwasm_std::{Empty, Order, StdResult, Storage};
+    use cw_storage_plus::{Bound, Item};
+
+    use crate::state::{
+        Config, ALLOWED_CONNECTIONS, CHANNEL_INFO, CONFIG, DEFAULT_HISTORY_LIMIT, LOCKUP,
+        LOCKUP_OWNER,
+    };
+    use crate::ContractError;
+
+    pub const CONFIG_V1: Item<ConfigV1> = Item::new("ics20_config");
+
+    // replaced by PENDING_OPS
+    const REPLY_ARGS: Item<cosmwasm_std::Empty> = Item::new("reply_args");
+
+    // the entries left to migrate, removed once everything is migrated
+    pub const MIGRATION_CURSOR: Item<MigrationCursor> = Item::new("v1_migration");
+
+    #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+    pub struct ConfigV1 {
+        pub default_timeout: u64,
+        pub lockup_id: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+    #[serde(rename_all = "snake_case")]
+    pub enum MigrationCursor {
+        /// the lockups are indexed first, start_after is the last (channel, owner) indexed
+        Lockups {
+            start_after: Option<(String, String)>,
+        },
+        /// then the connections of the channels are allowed
+        Channels { start_after: Option<String> },
+    }
+
+    // move the config to the current format and drop the single reply slot, the lockups and
+    // channels are migrated in batches by migrate_entries
+    pub fn migrate_state(storage: &mut dyn Storage) -> Result<(), ContractError> {
+        let old_config = CONFIG_V1.load(storage)?;
+        let config = Config {
+            default_timeout: old_config.default_timeout,
+            lockup_id: old_config.lockup_id,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        };
+        CONFIG.save(storage, &config)?;
+        REPLY_ARGS.remove(storage);
+        MIGRATION_CURSOR.save(storage, &MigrationCursor::Lockups { start_after: None })?;
+        Ok(())
+    }
+
+    // index the lockups by address and allow the connections of the channels, up to limit
+    // entries. Returns the number of entries migrated and if everything is migrated.
+    pub fn migrate_entries(
+        storage: &mut dyn Storage,
+        limit: usize,
+    ) -> Result<(usize, bool), ContractError> {
+        let mut cursor = match MIGRATION_CURSOR.may_load(storage)? {
+            Some(cursor) => cursor,
+            None => return Ok((0, true)),
+        };
+        let mut migrated = 0;
+
+        if let MigrationCursor::Lockups { start_after } = &cursor {
+            let start = start_after
+                .as_ref()
+                .map(|(channel, owner)| Bound::exclusive((channel.as_str(), owner.as_str())));
+            let lockups = LOCKUP
+                .range(storage, start, None, Order::Ascending)
+                .take(limit)
+                .collect::<StdResult<Vec<_>>>()?;
+            migrated += lockups.len();
+            let last = lockups.last().map(|(key, _)| key.clone());
+            for ((channel, owner), address) in lockups {
+                LOCKUP_OWNER.save(storage, &address, &(channel, owner))?;
+            }
+            cursor = if migrated < limit {
+                MigrationCursor::Channels { start_after: None }
+            } else {
+                MigrationCursor::Lockups { start_after: last }
+            };
+        }
+
+        if let MigrationCursor::Channels { start_after } = &cursor {
+            let start = start_after.as_deref().map(Bound::exclusive);
+            let channels = CHANNEL_INFO
+                .range(storage, start, None, Order::Ascending)
+                .take(limit - migrated)
+                .collect::<StdResult<Vec<_>>>()?;
+            let done = migrated + channels.len() < limit;
+            migrated += channels.len();
+            let last = channels.last().map(|(id, _)| id.clone());
+            for (_, info) in channels {
+                let key = (
+                    info.connection_id.as_str(),
+                    info.counterparty_endpoint.port_id.as_str(),
+                );
+                ALLOWED_CONNECTIONS.save(storage, key, &Empty {})?;
+            }
+            if done {
+                MIGRATION_CURSOR.remove(storage);
+                return Ok((migrated, true));
+            }
+            cursor = MigrationCursor::Channels { start_after: last };
+        }
+
+        MIGRATION_CURSOR.save(storage, &cursor)?;
+        Ok((migrated, false))
+    }
+}
@@ -1,9 +1,11 @@
-use cosmwasm_std::Uint64;
+use cosmwasm_std::{Coin, Uint128, Uint64};
+use cw20::Cw20ReceiveMsg;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::amount::Amount;
-use crate::state::ChannelInfo;
+use crate::ibc_msg::SplitSwapPacket;
+use crate::state::{ChannelInfo, ChannelStatus, Fees, ForwardRecord, PacketRecord, ReplyArgs};
 
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct InitMsg {
@@ -11,13 +13,167 @@ pub struct InitMsg {
     pub default_timeout: u64,
     /// Lockup code ID
     pub lockup_id: u64,
+    /// Who can update the config
+    pub admin: String,
+    /// Initial pool allowlist - actions can only use pools previously allowed by governance
+    pub allowlist: Vec<AllowPoolMsg>,
+    /// Initial cw20 allowlist - all cw20 tokens we will send must be previously allowed by governance
+    #[serde(default)]
+    pub cw20_allowlist: Vec<AllowMsg>,
+    /// Initial connections allowed to open channels
+    #[serde(default)]
+    pub connections: Vec<ConnectionMsg>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ConnectionMsg {
+    pub connection_id: String,
+    pub counterparty_port_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AllowMsg {
+    pub contract: String,
+    pub gas_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AllowPoolMsg {
+    pub pool_id: Uint64,
+    /// Max input amount of one action, no limit if not set
+    pub max_amount: Option<Uint128>,
+    /// Max price impact of a swap through the pool in basis points, no limit if not set
+    pub max_slippage_bps: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RateLimitMsg {
+    pub channel: String,
+    pub denom: String,
+    /// Length of the sliding window in seconds
+    pub period: u64,
+    /// Max amount released by received packets in any period, no limit if not set
+    pub max_outflow: Option<Uint128>,
+    /// Max amount sent to the remote chain in any period, no limit if not set
+    pub max_inflow: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// Set the admin, contracts migrated from 0.1.0 don't have one
+    pub admin: Option<String>,
+    /// Pools to allow, contracts migrated from 0.1.0 have an empty allowlist
+    #[serde(default)]
+    pub allowlist: Vec<AllowPoolMsg>,
+}
+
+/// Called by the ibc-hooks module with the result of the transfers sent with an `ibc_callback`
+/// memo
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SudoMsg {
+    IbcLifecycleComplete(IbcLifecycleComplete),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IbcLifecycleComplete {
+    /// The transfer was acknowledged, success is false for an error ack
+    IbcAck {
+        channel: String,
+        sequence: u64,
+        ack: String,
+        success: bool,
+    },
+    IbcTimeout {
+        channel: String,
+        sequence: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
+    /// This accepts a properly-encoded ReceiveMsg from a cw20 contract
+    Receive(Cw20ReceiveMsg),
     /// This allows us to transfer *exactly one* native token
     Transfer(TransferMsg),
+    /// Send the refunds of failed transfers that couldn't be sent back to the sender
+    ClaimRefund {},
+    /// Change the default timeout, the lockup code ID or the packet records kept per channel
+    /// (must be called by current admin)
+    UpdateConfig {
+        default_timeout: Option<u64>,
+        lockup_id: Option<u64>,
+        history_limit: Option<u64>,
+    },
+    /// Change the admin (must be called by current admin)
+    UpdateAdmin { admin: String },
+    /// This must be called by admin, will allow a new cw20 token to be sent
+    Allow(AllowMsg),
+    /// This must be called by admin, channels can be opened on the connection with the counterparty port
+    ApproveConnection(ConnectionMsg),
+    /// This must be called by admin, new channels can no longer be opened on the connection.
+    /// Open channels are not affected
+    RevokeConnection(ConnectionMsg),
+    /// This must be called by admin, will allow a new pool or update its max amount
+    AllowPool(AllowPoolMsg),
+    /// This must be called by admin, actions can no longer use the pool
+    DisallowPool { pool_id: Uint64 },
+    /// This must be called by admin, set the max price impact of swaps received on the channel
+    /// in basis points, or remove it
+    SetChannelMaxSlippage {
+        channel: String,
+        max_slippage_bps: Option<u64>,
+    },
+    /// This must be called by admin, set the counterparty port of the action channels that can
+    /// send actions for the remote senders of the transfer channel, or remove it
+    SetChannelActionPort {
+        channel: String,
+        port_id: Option<String>,
+    },
+    /// Change the protocol fee of each action type in basis points (must be called by current admin)
+    UpdateFees {
+        swap_bps: Option<u64>,
+        join_pool_bps: Option<u64>,
+        exit_pool_bps: Option<u64>,
+        lock_bps: Option<u64>,
+    },
+    /// Send collected fees, all of the denom if amount is not set.
+    /// The recipient is the admin if not set (must be called by current admin)
+    WithdrawFees {
+        denom: String,
+        amount: Option<Uint128>,
+        recipient: Option<String>,
+    },
+    /// This must be called by admin, will add or replace the rate limit of a channel denom.
+    /// The quota starts unused
+    AddRateLimit(RateLimitMsg),
+    /// This must be called by admin, clear the quota used in the sliding window
+    ResetRateLimit { channel: String, denom: String },
+    /// This must be called by admin, the channel denom is no longer limited
+    RemoveRateLimit { channel: String, denom: String },
+    /// This must be called by admin, move the balances and lockups of a closed channel to the
+    /// replacement channel. Moves up to limit entries, call it again until the channel is migrated
+    MigrateChannel {
+        channel: String,
+        new_channel: String,
+        limit: Option<u32>,
+    },
+    /// This must be called by admin after a migration from a version older than 0.2.0 that
+    /// didn't finish, migrates up to limit lockups and channels. Call it again until the
+    /// status is migrated
+    MigrateState { limit: Option<u32> },
+    /// This must be called by admin for a forwarded output whose ibc-hooks callback never came,
+    /// a refund is only accepted once the transfer timed out
+    ResolveForward { id: u64, refund: bool },
+    /// Swap over the routes of a received SplitSwap packet, only called by the contract itself
+    /// so the swaps are reverted together
+    SplitSwap {
+        op_id: u64,
+        token_in: Coin,
+        split: SplitSwapPacket,
+    },
 }
 
 /// This is the message we accept via Receive
@@ -37,18 +193,92 @@ pub struct TransferMsg {
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     /// Show all channels we have connected to. Return type is ListChannelsResponse.
-    ListChannels {},
+    ListChannels {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
     /// Returns the details of the name channel, error if not created.
     /// Return type: ChannelResponse.
     Channel { id: String },
-    /// Show the Config. Returns ConfigResponse
+    /// List the channels that are not open anymore with their status.
+    /// Return type: ListChannelStatusResponse.
+    ClosedChannels {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Show the Config. Returns ConfigResponse (including admin as well)
     Config {},
     /// Returns the lockup address of the channel and owner, empty if not created.
     /// Return type: LockupResponse.
     Lockup { channel: String, owner: String },
     /// Show all lockups created by channel.
     /// Return type: ListLockupResponse.
-    AllLockups { channel: String },
+    AllLockups {
+        channel: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Returns the channel and owner of a lockup account, error if not created by this contract.
+    /// Return type: LockupOwnerResponse.
+    LockupOwner { address: String },
+    /// Returns the balances owned by the remote owner on the channel.
+    /// Return type: UserBalancesResponse.
+    UserBalances { channel: String, owner: String },
+    /// Show operations waiting for a reply, they should only remain if something went wrong.
+    /// Return type: ListPendingOpsResponse.
+    PendingOps {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Show the action outputs sent to another chain and not resolved yet.
+    /// Return type: ListForwardsResponse.
+    Forwards {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Query if a given cw20 contract is allowed. Returns AllowedResponse
+    Allowed { contract: String },
+    /// List all allowed cw20 contracts. Returns ListAllowedResponse
+    ListAllowed {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Returns the refunds of failed transfers waiting to be claimed by the recipient.
+    /// Return type: PendingRefundResponse.
+    PendingRefund { recipient: String },
+    /// List the unclaimed refunds by recipient. Return type: ListPendingRefundsResponse.
+    PendingRefunds {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// List the connections allowed to open channels. Returns ListConnectionsResponse
+    ListConnections {
+        start_after: Option<ConnectionMsg>,
+        limit: Option<u32>,
+    },
+    /// Query if a given pool is allowed. Returns AllowedPoolResponse
+    AllowedPool { pool_id: Uint64 },
+    /// List all allowed pools. Returns ListAllowedPoolsResponse
+    ListAllowedPools {
+        start_after: Option<Uint64>,
+        limit: Option<u32>,
+    },
+    /// Show the protocol fees and the collected amounts. Returns FeesResponse
+    Fees {},
+    /// Show the rate limits of the channel with the quota used in the last period.
+    /// Return type: ListRateLimitsResponse.
+    RateLimits { channel: String },
+    /// Returns what happened to a received packet, error if not recorded or pruned.
+    /// Return type: PacketResponse.
+    Packet { channel: String, sequence: u64 },
+    /// List the received packets of the channel by sequence, only the ones of the remote sender
+    /// if set. Return type: ListPacketsResponse.
+    PacketHistory {
+        channel: String,
+        sender: Option<String>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -65,11 +295,55 @@ pub struct ChannelResponse {
     /// The total number of tokens that have been sent over this channel
     /// (even if many have been returned, so balance is low)
     pub total_sent: Vec<Amount>,
+    /// Max price impact of received swaps in basis points
+    pub max_slippage_bps: Option<u64>,
+    /// Counterparty port of the action channels paired with this transfer channel
+    pub action_port: Option<String>,
+    /// Closed channels don't accept transfers
+    pub status: ChannelStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ChannelStatusResponse {
+    pub id: String,
+    pub status: ChannelStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListChannelStatusResponse {
+    pub channels: Vec<ChannelStatusResponse>,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 pub struct ConfigResponse {
     pub default_timeout: u64,
+    pub lockup_id: u64,
+    pub history_limit: u64,
+    pub admin: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct FeesResponse {
+    pub fees: Fees,
+    /// Fees that can be withdrawn by the admin
+    pub collected: Vec<Amount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct RateLimitResponse {
+    pub denom: String,
+    pub period: u64,
+    pub max_outflow: Option<Uint128>,
+    pub max_inflow: Option<Uint128>,
+    /// Amount released by received packets in the last period
+    pub outflow: Uint128,
+    /// Amount sent to the remote chain in the last period
+    pub inflow: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListRateLimitsResponse {
+    pub rate_limits: Vec<RateLimitResponse>,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -80,11 +354,100 @@ pub struct LockupResponse {
     pub address: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct LockupOwnerResponse {
+    pub channel: String,
+    /// Remote owner on the channel
+    pub owner: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
 pub struct ListLockupResponse {
     pub lockups: Vec<LockupResponse>,
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct UserBalancesResponse {
+    /// Tokens owned by the remote owner, can be withdrawn over the channel
+    pub balances: Vec<Amount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct PendingOpResponse {
+    /// Operation id, carried in the reply id
+    pub id: u64,
+    pub args: ReplyArgs,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListPendingOpsResponse {
+    pub ops: Vec<PendingOpResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ForwardResponse {
+    /// Forward id, used to resolve it
+    pub id: u64,
+    pub forward: ForwardRecord,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListForwardsResponse {
+    pub forwards: Vec<ForwardResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct PendingRefundResponse {
+    pub recipient: String,
+    /// Tokens that can be claimed with ClaimRefund
+    pub refunds: Vec<Amount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListPendingRefundsResponse {
+    pub refunds: Vec<PendingRefundResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct PacketResponse {
+    /// Sequence of the packet on the channel
+    pub sequence: u64,
+    pub record: PacketRecord,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListPacketsResponse {
+    pub packets: Vec<PacketResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct AllowedResponse {
+    pub is_allowed: bool,
+    pub gas_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListAllowedResponse {
+    pub allow: Vec<AllowMsg>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListConnectionsResponse {
+    pub connections: Vec<ConnectionMsg>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct AllowedPoolResponse {
+    pub is_allowed: bool,
+    pub max_amount: Option<Uint128>,
+    pub max_slippage_bps: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ListAllowedPoolsResponse {
+    pub pools: Vec<AllowPoolMsg>,
+}
+
 // Lockup contract InstantiateMsg
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct LockupInitMsg {
@@ -95,7 +458,20 @@ pub struct LockupInitMsg {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum LockupExecuteMsg {
-    Lock { duration: Uint64 },
-    Unlock { id: Uint64 },
-    Claim { denom: String },
+    Lock {
+        duration: Uint64,
+    },
+    Unlock {
+        id: Uint64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        coins: Vec<Coin>,
+    },
+    UnlockAll {},
+    ExtendLock {
+        id: Uint64,
+        duration: Uint64,
+    },
+    Claim {
+        denom: String,
+    },
 }
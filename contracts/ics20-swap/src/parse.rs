@@ -1,9 +1,10 @@
-use crate::ibc_msg::AmountResultAck;
+use crate::ibc_msg::{AmountResultAck, ExactOutResultAck};
 use crate::ContractError;
 use cosmwasm_std::{Attribute, Coin, Event, SubMsgResponse, Uint128};
 use cw_osmo_proto::osmosis::gamm::v1beta1::{
-    MsgExitSwapShareAmountInResponse, MsgJoinSwapExternAmountInResponse,
-    MsgSwapExactAmountInResponse,
+    MsgExitSwapExternAmountOutResponse, MsgExitSwapShareAmountInResponse,
+    MsgJoinSwapExternAmountInResponse, MsgJoinSwapShareAmountOutResponse,
+    MsgSwapExactAmountInResponse, MsgSwapExactAmountOutResponse,
 };
 use cw_osmo_proto::proto_ext::proto_decode;
 
@@ -13,6 +14,10 @@ pub const JOIN_POOL_EVENT: &str = "coinbase";
 pub const JOIN_POOL_ATTR: &str = "amount";
 pub const EXIT_POOL_EVENT: &str = "token_swapped";
 pub const EXIT_POOL_ATTR: &str = "tokens_out";
+pub const MULTI_JOIN_POOL_EVENT: &str = "pool_joined";
+pub const MULTI_JOIN_POOL_ATTR: &str = "tokens_in";
+pub const MULTI_EXIT_POOL_EVENT: &str = "pool_exited";
+pub const MULTI_EXIT_POOL_ATTR: &str = "tokens_out";
 
 pub trait GammResult {
     fn amount(&self) -> &String;
@@ -24,18 +29,36 @@ impl GammResult for MsgSwapExactAmountInResponse {
     }
 }
 
+impl GammResult for MsgSwapExactAmountOutResponse {
+    fn amount(&self) -> &String {
+        &self.token_in_amount
+    }
+}
+
 impl GammResult for MsgJoinSwapExternAmountInResponse {
     fn amount(&self) -> &String {
         &self.share_out_amount
     }
 }
 
+impl GammResult for MsgJoinSwapShareAmountOutResponse {
+    fn amount(&self) -> &String {
+        &self.token_in_amount
+    }
+}
+
 impl GammResult for MsgExitSwapShareAmountInResponse {
     fn amount(&self) -> &String {
         &self.token_out_amount
     }
 }
 
+impl GammResult for MsgExitSwapExternAmountOutResponse {
+    fn amount(&self) -> &String {
+        &self.share_in_amount
+    }
+}
+
 pub fn find_event_type(events: Vec<Event>, key: &str) -> Option<Event> {
     events.into_iter().find(|ev| ev.ty.eq(&key))
 }
@@ -73,6 +96,15 @@ pub fn parse_coin(value: &str) -> Result<Coin, ContractError> {
     })
 }
 
+pub fn parse_coins(value: &str) -> Result<Vec<Coin>, ContractError> {
+    // coins: 100uatom,200uosmo
+    value.split(',').map(parse_coin).collect()
+}
+
+pub fn pool_denom(pool_id: u64) -> String {
+    format!("gamm/pool/{}", pool_id)
+}
+
 pub fn parse_pool_id(denom: &str) -> Result<u64, ContractError> {
     // denom: gamm/pool/1
     let pool_str = denom.trim_start_matches("gamm/pool/");
@@ -111,6 +143,71 @@ pub fn parse_gamm_result<M: GammResult + cw_osmo_proto::Message + std::default::
     let ack = AmountResultAck {
         amount: Uint128::from(amount),
         denom: token_out.denom,
+        fee: None,
+    };
+
+    Ok(ack)
+}
+
+// the last value of the last event, the output of a multi-hop swap
+pub fn parse_last_output(
+    msg: SubMsgResponse,
+    event: &str,
+    attribute: &str,
+) -> Result<Coin, ContractError> {
+    let event = msg
+        .events
+        .into_iter()
+        .rev()
+        .find(|ev| ev.ty.eq(&event))
+        .ok_or(ContractError::GammResultNotFound {})?;
+
+    let values = find_attributes(event.attributes, attribute);
+    let token_out_str = values.last().ok_or(ContractError::GammResultNotFound {})?;
+
+    parse_coin(token_out_str.as_str())
+}
+
+pub fn parse_pool_coins(
+    msg: SubMsgResponse,
+    event: &str,
+    attribute: &str,
+) -> Result<Vec<Coin>, ContractError> {
+    let event = find_event_type(msg.events, event).ok_or(ContractError::GammResultNotFound {})?;
+
+    let values = find_attributes(event.attributes, attribute);
+    let coins_str = values.last().ok_or(ContractError::GammResultNotFound {})?;
+
+    parse_coins(coins_str.as_str())
+}
+
+// the exact output is set by the packet, the events don't always have it (a multi-hop swap
+// emits an event per pool), only the input spent is read from the response
+pub fn parse_exact_out_result<M: GammResult + cw_osmo_proto::Message + std::default::Default>(
+    msg: SubMsgResponse,
+    token_in_denom: &str,
+    token_out: Coin,
+) -> Result<ExactOutResultAck, ContractError> {
+    // response amount is the input spent
+    let data = msg.data.ok_or(ContractError::NoReplyData {})?;
+    let response: M = proto_decode(data.as_slice())?;
+    let amount = response
+        .amount()
+        .parse::<u128>()
+        .map_err(|_| ContractError::InvalidAmountValue {})?;
+
+    let ack = ExactOutResultAck {
+        token_in: AmountResultAck {
+            amount: Uint128::from(amount),
+            denom: token_in_denom.to_string(),
+            fee: None,
+        },
+        token_out: AmountResultAck {
+            amount: token_out.amount,
+            denom: token_out.denom,
+            fee: None,
+        },
+        fee: None,
     };
 
     Ok(ack)
@@ -122,7 +219,7 @@ mod test {
     use crate::test_helpers::*;
 
     use crate::ContractError;
-    use cosmwasm_std::{Binary, SubMsgResponse, Uint128};
+    use cosmwasm_std::{coin, Binary, SubMsgResponse, Uint128};
 
     #[test]
     fn parse_token_str() {
@@ -154,6 +251,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_coins_str() {
+        let coins = parse_coins("100008ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2,4985011uosmo").unwrap();
+        assert_eq!(2, coins.len());
+        assert_eq!(Uint128::new(100008), coins[0].amount);
+        assert_eq!(
+            "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2",
+            coins[0].denom
+        );
+        assert_eq!(Uint128::new(4985011), coins[1].amount);
+        assert_eq!("uosmo", coins[1].denom);
+
+        let coins = parse_coins("6543gamm/pool/1").unwrap();
+        assert_eq!(1, coins.len());
+
+        let err = parse_coins("100uatom,").unwrap_err();
+        assert_eq!(ContractError::InvalidAmountValue {}, err);
+    }
+
     #[test]
     fn find_events_attributes() {
         let events = mock_swap_events();
@@ -190,6 +306,64 @@ mod test {
         assert_eq!("uosmo", token.denom);
     }
 
+    #[test]
+    fn parse_swap_out() {
+        let msg = SubMsgResponse {
+            events: mock_join_pool_events(),
+            data: None,
+        };
+        let err_result = parse_exact_out_result::<MsgSwapExactAmountOutResponse>(
+            msg,
+            "uatom",
+            coin(36601070, "uosmo"),
+        )
+        .unwrap_err();
+        assert_eq!(ContractError::NoReplyData {}, err_result);
+
+        // the first event is the intermediate hop, the packet output is used
+        let result = parse_exact_out_result::<MsgSwapExactAmountOutResponse>(
+            mock_swap_out_multi_hop_response(),
+            "uatom",
+            coin(36601070, "uosmo"),
+        )
+        .unwrap();
+
+        assert_eq!(Uint128::new(10000000), result.token_in.amount);
+        assert_eq!("uatom", result.token_in.denom);
+        assert_eq!(Uint128::new(36601070), result.token_out.amount);
+        assert_eq!("uosmo", result.token_out.denom);
+    }
+
+    #[test]
+    fn parse_join_pool_exact_out() {
+        let result = parse_exact_out_result::<MsgJoinSwapShareAmountOutResponse>(
+            mock_join_pool_exact_out_response(),
+            "uosmo",
+            coin(74196993097318119147, pool_denom(1)),
+        )
+        .unwrap();
+
+        assert_eq!(Uint128::new(4985011), result.token_in.amount);
+        assert_eq!("uosmo", result.token_in.denom);
+        assert_eq!(Uint128::new(74196993097318119147), result.token_out.amount);
+        assert_eq!("gamm/pool/1", result.token_out.denom);
+    }
+
+    #[test]
+    fn parse_exit_pool_exact_out() {
+        let result = parse_exact_out_result::<MsgExitSwapExternAmountOutResponse>(
+            mock_exit_pool_exact_out_response(),
+            "gamm/pool/1",
+            coin(4985011, "uosmo"),
+        )
+        .unwrap();
+
+        assert_eq!(Uint128::new(74196993097318119147), result.token_in.amount);
+        assert_eq!("gamm/pool/1", result.token_in.denom);
+        assert_eq!(Uint128::new(4985011), result.token_out.amount);
+        assert_eq!("uosmo", result.token_out.denom);
+    }
+
     #[test]
     fn parse_join_pool_result() {
         let events = mock_join_pool_events();
@@ -210,6 +384,36 @@ mod test {
         assert_eq!("gamm/pool/1", token.denom);
     }
 
+    #[test]
+    fn parse_multi_pool_result() {
+        let err = parse_pool_coins(
+            mock_swap_response(),
+            MULTI_JOIN_POOL_EVENT,
+            MULTI_JOIN_POOL_ATTR,
+        )
+        .unwrap_err();
+        assert_eq!(ContractError::GammResultNotFound {}, err);
+
+        let coins = parse_pool_coins(
+            mock_multi_join_pool_response(),
+            MULTI_JOIN_POOL_EVENT,
+            MULTI_JOIN_POOL_ATTR,
+        )
+        .unwrap();
+        assert_eq!(2, coins.len());
+        assert_eq!(Uint128::new(4985011), coins[1].amount);
+        assert_eq!("uosmo", coins[1].denom);
+
+        let coins = parse_pool_coins(
+            mock_multi_exit_pool_response(),
+            MULTI_EXIT_POOL_EVENT,
+            MULTI_EXIT_POOL_ATTR,
+        )
+        .unwrap();
+        assert_eq!(2, coins.len());
+        assert_eq!(Uint128::new(100008), coins[0].amount);
+    }
+
     #[test]
     fn parse_exit_pool_result() {
         let events = mock_exit_pool_events();
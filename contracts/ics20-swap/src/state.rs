@@ -1,25 +1,98 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{IbcEndpoint, StdResult, Storage, Uint128};
+use cosmwasm_std::{Addr, Coin, Empty, IbcEndpoint, Order, StdResult, Storage, Timestamp, Uint128};
+use cw_controllers::Admin;
 use cw_storage_plus::{Item, Map};
 
+use crate::ibc::ICS20_VERSION;
+use crate::ibc_msg::Forward;
 use crate::ContractError;
 
+pub const ADMIN: Admin = Admin::new("admin");
+
 pub const CONFIG: Item<Config> = Item::new("ics20_config");
 
-// Used to pass info from the ibc_packet_receive to the reply handler
-pub const REPLY_ARGS: Item<ReplyArgs> = Item::new("reply_args");
+/// indexed by operation id, used to pass info from the ibc_packet_receive to the reply handler.
+/// The operation is removed once its reply is handled
+pub const PENDING_OPS: Map<u64, ReplyArgs> = Map::new("pending_ops");
+
+/// last used operation id
+pub const OP_COUNTER: Item<u64> = Item::new("op_counter");
 
 /// static info on one channel that doesn't change
 pub const CHANNEL_INFO: Map<&str, ChannelInfo> = Map::new("channel_info");
 
+/// indexed by channel_id, the channels that are not open anymore
+pub const CHANNEL_STATUS: Map<&str, ChannelStatus> = Map::new("channel_status");
+
 /// indexed by (channel_id, denom) maintaining the balance of the channel in that currency
 pub const CHANNEL_STATE: Map<(&str, &str), ChannelState> = Map::new("channel_state");
 
 /// indexed by (channel_id, owner) saving lockup account
 pub const LOCKUP: Map<(&str, &str), String> = Map::new("lockup");
 
+/// indexed by lockup account, the (channel_id, owner) of LOCKUP
+pub const LOCKUP_OWNER: Map<&str, (String, String)> = Map::new("lockup_owner");
+
+/// indexed by (channel_id, remote_sender, denom) maintaining the action outputs owned by each remote sender
+pub const USER_BALANCE: Map<(&str, &str, &str), Uint128> = Map::new("user_balance");
+
+/// pools that actions can use, indexed by pool id
+pub const POOL_ALLOW_LIST: Map<u64, AllowPoolInfo> = Map::new("pool_allow_list");
+
+/// indexed by (connection_id, counterparty_port_id) allowed to open channels
+pub const ALLOWED_CONNECTIONS: Map<(&str, &str), Empty> = Map::new("allowed_connections");
+
+/// cw20 contracts that can be sent over the channels, indexed by contract address
+pub const ALLOW_LIST: Map<&Addr, AllowInfo> = Map::new("allow_list");
+
+/// protocol fee of each action type
+pub const FEES: Item<Fees> = Item::new("fees");
+
+/// indexed by denom, protocol fees not withdrawn yet
+pub const COLLECTED_FEES: Map<&str, Uint128> = Map::new("collected_fees");
+
+/// indexed by (recipient, denom), refunds of failed transfers that couldn't be sent back,
+/// the recipient can claim them
+pub const PENDING_REFUNDS: Map<(&str, &str), Uint128> = Map::new("pending_refunds");
+
+/// indexed by operation id, action outputs sent to another chain until the ibc-hooks callback
+/// reports the ack or the timeout of the transfer
+pub const FORWARDS: Map<u64, ForwardRecord> = Map::new("forwards");
+
+/// indexed by (forward channel_id, packet sequence), operation id of the forwarded transfer
+pub const FORWARD_PACKETS: Map<(&str, u64), u64> = Map::new("forward_packets");
+
+/// indexed by operation id, the outputs of the split swap routes while they are executed
+pub const SPLIT_SWAPS: Map<u64, SplitSwapState> = Map::new("split_swaps");
+
+/// indexed by (channel_id, denom), limits the balance changes of the channel over a time window
+pub const RATE_LIMITS: Map<(&str, &str), RateLimit> = Map::new("rate_limits");
+
+/// number of buckets in the period of a rate limit, the window slides one bucket at a time
+pub const RATE_LIMIT_BUCKETS: u64 = 10;
+
+/// indexed by channel_id, max price impact in basis points of the swaps received on the channel
+pub const CHANNEL_MAX_SLIPPAGE: Map<&str, u64> = Map::new("channel_max_slippage");
+
+/// indexed by transfer channel_id, counterparty port of the action channels allowed to send
+/// actions for its remote senders
+pub const CHANNEL_ACTION_PORT: Map<&str, String> = Map::new("channel_action_port");
+
+/// indexed by (channel_id, packet sequence), what happened to each received packet.
+/// Only the last `Config::history_limit` packets of each channel are kept
+pub const PACKET_HISTORY: Map<(&str, u64), PacketRecord> = Map::new("packet_history");
+
+/// indexed by channel_id, number of records in PACKET_HISTORY
+pub const PACKET_HISTORY_COUNT: Map<&str, u64> = Map::new("packet_history_count");
+
+/// records kept per channel if the config doesn't set it
+pub const DEFAULT_HISTORY_LIMIT: u64 = 1000;
+
+// max records removed with each new one, the history shrinks progressively when the limit is lowered
+const MAX_HISTORY_PRUNE: u64 = 10;
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
 pub struct ChannelState {
     pub outstanding: Uint128,
@@ -30,6 +103,132 @@ pub struct ChannelState {
 pub struct Config {
     pub default_timeout: u64,
     pub lockup_id: u64,
+    /// max packet records kept per channel, 0 disables the history
+    #[serde(default = "default_history_limit")]
+    pub history_limit: u64,
+}
+
+fn default_history_limit() -> u64 {
+    DEFAULT_HISTORY_LIMIT
+}
+
+/// Fees in basis points, taken from the transferred amount of the action
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
+pub struct Fees {
+    /// Swap and SwapExactOut
+    pub swap_bps: u64,
+    /// JoinPool, JoinPoolExactOut and MultiJoinPool
+    pub join_pool_bps: u64,
+    /// ExitPool, ExitPoolExactOut and MultiExitPool
+    pub exit_pool_bps: u64,
+    /// Lock
+    pub lock_bps: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
+pub struct AllowPoolInfo {
+    /// max input amount of one action, no limit if not set
+    pub max_amount: Option<Uint128>,
+    /// max price impact of a swap in basis points, no limit if not set
+    #[serde(default)]
+    pub max_slippage_bps: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
+pub struct AllowInfo {
+    /// gas limit of the cw20 transfers, no limit if not set
+    pub gas_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct RateLimit {
+    /// length of the sliding window in seconds
+    pub period: u64,
+    /// max amount released by received packets in any period, no limit if not set
+    pub max_outflow: Option<Uint128>,
+    /// max amount sent to the remote chain in any period, no limit if not set
+    pub max_inflow: Option<Uint128>,
+    /// quota used in the buckets of the window, oldest first
+    pub buckets: Vec<FlowBucket>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct FlowBucket {
+    /// block time in seconds at the start of the bucket
+    pub start: u64,
+    pub outflow: Uint128,
+    pub inflow: Uint128,
+}
+
+impl RateLimit {
+    // the window slides by buckets of a tenth of the period
+    fn bucket_width(&self) -> u64 {
+        self.period.div_ceil(RATE_LIMIT_BUCKETS)
+    }
+
+    // drop the buckets that left the window. The window keeps one more bucket than the period
+    // needs, so every flow of the last period is counted and the max can't be exceeded over a
+    // bucket boundary
+    fn slide(&mut self, now: Timestamp) {
+        let width = self.bucket_width();
+        let current = now.seconds() / width * width;
+        let oldest = current.saturating_sub(RATE_LIMIT_BUCKETS * width);
+        self.buckets.retain(|bucket| bucket.start >= oldest);
+    }
+
+    // the bucket holding the flows at the given time, the window must have been slid
+    fn current_bucket(&mut self, now: Timestamp) -> &mut FlowBucket {
+        let width = self.bucket_width();
+        let start = now.seconds() / width * width;
+        if self.buckets.last().map(|bucket| bucket.start) != Some(start) {
+            self.buckets.push(FlowBucket {
+                start,
+                outflow: Uint128::zero(),
+                inflow: Uint128::zero(),
+            });
+        }
+        self.buckets.last_mut().unwrap()
+    }
+
+    // the quota used in the window ending at the given time
+    pub fn current_flow(&self, now: Timestamp) -> (Uint128, Uint128) {
+        let mut limit = self.clone();
+        limit.slide(now);
+        limit.buckets.iter().fold(
+            (Uint128::zero(), Uint128::zero()),
+            |(outflow, inflow), bucket| (outflow + bucket.outflow, inflow + bucket.inflow),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct PacketRecord {
+    /// remote sender
+    pub sender: String,
+    /// name of the osmosis action, not set for plain transfers
+    pub action: Option<String>,
+    /// transferred token, the local denom if the voucher is valid
+    pub denom: String,
+    pub amount: Uint128,
+    pub status: PacketStatus,
+    /// action outputs, credited to the sender balance or sent back
+    pub tokens_out: Vec<Coin>,
+    /// block of the packet reception
+    pub height: u64,
+    pub time: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketStatus {
+    /// waiting for the reply of the action
+    Pending,
+    Success,
+    /// code and message of the error ack
+    Failed {
+        code: u32,
+        message: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -40,6 +239,26 @@ pub struct ChannelInfo {
     pub counterparty_endpoint: IbcEndpoint,
     /// the connection this exists on (you can use to query client/consensus info)
     pub connection_id: String,
+    /// the app version, ics20 for transfer channels
+    #[serde(default = "default_channel_version")]
+    pub version: String,
+}
+
+fn default_channel_version() -> String {
+    ICS20_VERSION.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelStatus {
+    #[default]
+    Open,
+    /// closed by the counterparty, the balances and lockups are frozen until migrated
+    Closed,
+    /// the balances and lockups are being moved to the replacement channel
+    Migrating { channel: String },
+    /// the balances and lockups were moved to the replacement channel
+    Migrated { channel: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -48,18 +267,371 @@ pub struct ReplyArgs {
     pub denom: String,
     pub amount: Uint128,
     pub sender: String,
+    /// Send the action output back to the sender
+    #[serde(default)]
+    pub return_to: bool,
+    /// Extra funds taken from the channel balance
+    #[serde(default)]
+    pub reserved: Vec<Coin>,
+    /// Protocol fee taken from the transferred amount, the action only gets the rest
+    #[serde(default)]
+    pub fee: Uint128,
+    /// Sequence of the received packet, to update its record
+    #[serde(default)]
+    pub sequence: Option<u64>,
+    /// Action channel of the received packet, the record is kept there instead of the channel
+    #[serde(default)]
+    pub packet_channel: Option<String>,
+    /// Send the action output to another chain
+    #[serde(default)]
+    pub forward: Option<Forward>,
+    /// Exact output set by the packet, credited instead of the gamm events
+    #[serde(default)]
+    pub token_out: Option<Coin>,
+    /// Output denom set by the packet, the first swap event of a multi-hop route has the
+    /// denom of the first pool
+    #[serde(default)]
+    pub token_out_denom: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct SplitSwapState {
+    /// number of routes, the min output is checked with the last one
+    pub routes: u64,
+    pub token_out_min_amount: Uint128,
+    /// output denom of the last pool of the routes
+    pub denom: String,
+    /// output of each finished route
+    pub outputs: Vec<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct ForwardRecord {
+    /// channel and remote sender credited back if the transfer fails or times out
+    pub channel: String,
+    pub sender: String,
+    pub amount: Coin,
+    /// transfer channel and receiver on the destination chain
+    pub forward_channel: String,
+    pub receiver: String,
+    pub timeout: Timestamp,
+    /// sequence of the transfer packet on the forward channel
+    pub sequence: u64,
+}
+
+pub fn load_channel_status(storage: &dyn Storage, channel: &str) -> StdResult<ChannelStatus> {
+    Ok(CHANNEL_STATUS
+        .may_load(storage, channel)?
+        .unwrap_or_default())
+}
+
+// the channel keeping the balances, the replacement once the channel migration started
+pub fn balance_channel(storage: &dyn Storage, channel: &str) -> StdResult<String> {
+    match load_channel_status(storage, channel)? {
+        ChannelStatus::Migrating { channel } | ChannelStatus::Migrated { channel } => Ok(channel),
+        _ => Ok(channel.to_string()),
+    }
+}
+
+pub fn ensure_channel_open(storage: &dyn Storage, channel: &str) -> Result<(), ContractError> {
+    match load_channel_status(storage, channel)? {
+        ChannelStatus::Open => Ok(()),
+        _ => Err(ContractError::ChannelClosed {
+            id: channel.to_string(),
+        }),
+    }
+}
+
+// move the balances and up to limit user balances and lockups of the channel to the new channel,
+// returns the number of moved entries.
+// All the balances are moved at once (one per denom), the packets that fail after update the
+// new channel
+pub fn move_channel_entries(
+    storage: &mut dyn Storage,
+    channel: &str,
+    new_channel: &str,
+    limit: usize,
+) -> Result<usize, ContractError> {
+    let balances = CHANNEL_STATE
+        .prefix(channel)
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut moved = balances.len();
+
+    let user_balances = USER_BALANCE
+        .sub_prefix(channel)
+        .range(storage, None, None, Order::Ascending)
+        .take(limit.saturating_sub(moved))
+        .collect::<StdResult<Vec<_>>>()?;
+    moved += user_balances.len();
+
+    let lockups = LOCKUP
+        .prefix(channel)
+        .range(storage, None, None, Order::Ascending)
+        .take(limit.saturating_sub(moved))
+        .collect::<StdResult<Vec<_>>>()?;
+    moved += lockups.len();
+
+    // a lockup account can't be merged with another one, nothing is moved if one exists
+    if let Some((owner, _)) = lockups
+        .iter()
+        .find(|(owner, _)| LOCKUP.has(storage, (new_channel, owner)))
+    {
+        return Err(ContractError::LockupExists {
+            channel: new_channel.to_string(),
+            owner: owner.clone(),
+        });
+    }
+
+    for (denom, state) in balances {
+        CHANNEL_STATE.update(storage, (new_channel, &denom), |orig| -> StdResult<_> {
+            let mut new_state = orig.unwrap_or_default();
+            new_state.outstanding += state.outstanding;
+            new_state.total_sent += state.total_sent;
+            Ok(new_state)
+        })?;
+        CHANNEL_STATE.remove(storage, (channel, &denom));
+    }
+    for ((owner, denom), amount) in user_balances {
+        increase_user_balance(storage, new_channel, &owner, &denom, amount)?;
+        USER_BALANCE.remove(storage, (channel, &owner, &denom));
+    }
+    for (owner, address) in lockups {
+        save_lockup(storage, new_channel, &owner, &address)?;
+        LOCKUP.remove(storage, (channel, &owner));
+    }
+
+    Ok(moved)
+}
+
+// save the lockup account of the owner and its reverse lookup
+pub fn save_lockup(
+    storage: &mut dyn Storage,
+    channel: &str,
+    owner: &str,
+    address: &str,
+) -> StdResult<()> {
+    LOCKUP.save(storage, (channel, owner), &address.to_string())?;
+    LOCKUP_OWNER.save(storage, address, &(channel.to_string(), owner.to_string()))
+}
+
+// save the reply args with a new operation id
+pub fn save_pending_op(storage: &mut dyn Storage, reply_args: &ReplyArgs) -> StdResult<u64> {
+    let op_id = OP_COUNTER.may_load(storage)?.unwrap_or_default() + 1;
+    OP_COUNTER.save(storage, &op_id)?;
+    PENDING_OPS.save(storage, op_id, reply_args)?;
+    Ok(op_id)
+}
+
+pub fn restore_balance_reply(storage: &mut dyn Storage, op_id: u64) -> Result<(), ContractError> {
+    let reply_args = PENDING_OPS.load(storage, op_id)?;
+
+    // the fee is refunded with the transferred amount, action packets don't transfer tokens
+    let transferred = reply_args.amount + reply_args.fee;
+    if !transferred.is_zero() {
+        undo_reduce_channel_balance(storage, &reply_args.channel, &reply_args.denom, transferred)?;
+        undo_outflow(storage, &reply_args.channel, &reply_args.denom, transferred)?;
+    }
+    if !reply_args.fee.is_zero() {
+        reduce_collected_fee(storage, &reply_args.denom, reply_args.fee)?;
+    }
+    for coin in reply_args.reserved.iter() {
+        refund_reply_input(storage, &reply_args, &coin.denom, coin.amount)?;
+    }
+
+    Ok(())
 }
 
-pub fn restore_balance_reply(storage: &mut dyn Storage) -> Result<(), ContractError> {
-    let reply_args = REPLY_ARGS.load(storage)?;
+// credit the action output to the channel and to the remote sender
+pub fn credit_reply_output(
+    storage: &mut dyn Storage,
+    reply_args: &ReplyArgs,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    increase_channel_balance(storage, &reply_args.channel, denom, amount)?;
+    increase_user_balance(
+        storage,
+        &reply_args.channel,
+        &reply_args.sender,
+        denom,
+        amount,
+    )?;
+    add_packet_output(storage, reply_args, denom, amount)?;
+    Ok(())
+}
 
-    undo_reduce_channel_balance(
+// give back unspent action input to the channel and to the remote sender
+pub fn refund_reply_input(
+    storage: &mut dyn Storage,
+    reply_args: &ReplyArgs,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    undo_reduce_channel_balance(storage, &reply_args.channel, denom, amount)?;
+    increase_user_balance(
         storage,
         &reply_args.channel,
-        &reply_args.denom,
-        reply_args.amount,
+        &reply_args.sender,
+        denom,
+        amount,
+    )
+}
+
+// save the record of a received packet and prune the oldest ones over the limit
+pub fn save_packet_record(
+    storage: &mut dyn Storage,
+    channel: &str,
+    sequence: u64,
+    record: &PacketRecord,
+) -> StdResult<()> {
+    let limit = CONFIG.load(storage)?.history_limit;
+    let mut count = PACKET_HISTORY_COUNT
+        .may_load(storage, channel)?
+        .unwrap_or_default();
+    if limit > 0 {
+        PACKET_HISTORY.save(storage, (channel, sequence), record)?;
+        count += 1;
+    }
+
+    let to_prune = count.saturating_sub(limit).min(MAX_HISTORY_PRUNE);
+    let oldest = PACKET_HISTORY
+        .prefix(channel)
+        .keys(storage, None, None, Order::Ascending)
+        .take(to_prune as usize)
+        .collect::<StdResult<Vec<_>>>()?;
+    for old in oldest {
+        PACKET_HISTORY.remove(storage, (channel, old));
+        count -= 1;
+    }
+    PACKET_HISTORY_COUNT.save(storage, channel, &count)
+}
+
+// add an action output to the record of the packet, if it is still kept
+pub fn add_packet_output(
+    storage: &mut dyn Storage,
+    reply_args: &ReplyArgs,
+    denom: &str,
+    amount: Uint128,
+) -> StdResult<()> {
+    update_packet_record(storage, reply_args, |record| {
+        record.tokens_out.push(Coin::new(amount.u128(), denom))
+    })
+}
+
+// set the final status of the packet once its action is finished
+pub fn finish_packet_record(
+    storage: &mut dyn Storage,
+    reply_args: &ReplyArgs,
+    status: PacketStatus,
+) -> StdResult<()> {
+    update_packet_record(storage, reply_args, |record| record.status = status)
+}
+
+fn update_packet_record(
+    storage: &mut dyn Storage,
+    reply_args: &ReplyArgs,
+    action: impl FnOnce(&mut PacketRecord),
+) -> StdResult<()> {
+    let sequence = match reply_args.sequence {
+        Some(sequence) => sequence,
+        None => return Ok(()),
+    };
+    let channel = reply_args
+        .packet_channel
+        .as_deref()
+        .unwrap_or(&reply_args.channel);
+    let key = (channel, sequence);
+    if let Some(mut record) = PACKET_HISTORY.may_load(storage, key)? {
+        action(&mut record);
+        PACKET_HISTORY.save(storage, key, &record)?;
+    }
+    Ok(())
+}
+
+pub fn increase_collected_fee(
+    storage: &mut dyn Storage,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    COLLECTED_FEES.update(storage, denom, |orig| -> StdResult<_> {
+        Ok(orig.unwrap_or_default() + amount)
+    })?;
+    Ok(())
+}
+
+pub fn reduce_collected_fee(
+    storage: &mut dyn Storage,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    COLLECTED_FEES.update(storage, denom, |orig| -> Result<_, ContractError> {
+        orig.unwrap_or_default()
+            .checked_sub(amount)
+            .or(Err(ContractError::InsufficientFunds {}))
+    })?;
+    Ok(())
+}
+
+pub fn increase_user_balance(
+    storage: &mut dyn Storage,
+    channel: &str,
+    owner: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    USER_BALANCE.update(storage, (channel, owner, denom), |orig| -> StdResult<_> {
+        Ok(orig.unwrap_or_default() + amount)
+    })?;
+    Ok(())
+}
+
+pub fn increase_pending_refund(
+    storage: &mut dyn Storage,
+    recipient: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    PENDING_REFUNDS.update(storage, (recipient, denom), |orig| -> StdResult<_> {
+        Ok(orig.unwrap_or_default() + amount)
+    })?;
+    Ok(())
+}
+
+pub fn reduce_user_balance(
+    storage: &mut dyn Storage,
+    channel: &str,
+    owner: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    USER_BALANCE.update(
+        storage,
+        (channel, owner, denom),
+        |orig| -> Result<_, ContractError> {
+            orig.unwrap_or_default()
+                .checked_sub(amount)
+                .or(Err(ContractError::InsufficientFunds {}))
+        },
     )?;
+    Ok(())
+}
 
+// reduce all user balances or none of them
+pub fn reduce_user_balances(
+    storage: &mut dyn Storage,
+    channel: &str,
+    owner: &str,
+    coins: &[Coin],
+) -> Result<(), ContractError> {
+    for (i, coin) in coins.iter().enumerate() {
+        if let Err(err) = reduce_user_balance(storage, channel, owner, &coin.denom, coin.amount) {
+            for reduced in &coins[..i] {
+                increase_user_balance(storage, channel, owner, &reduced.denom, reduced.amount)?;
+            }
+            return Err(err);
+        }
+    }
     Ok(())
 }
 
@@ -100,6 +672,23 @@ pub fn reduce_channel_balance(
     Ok(())
 }
 
+// reduce all balances or none of them
+pub fn reduce_channel_balances(
+    storage: &mut dyn Storage,
+    channel: &str,
+    coins: &[Coin],
+) -> Result<(), ContractError> {
+    for (i, coin) in coins.iter().enumerate() {
+        if let Err(err) = reduce_channel_balance(storage, channel, &coin.denom, coin.amount) {
+            for reduced in &coins[..i] {
+                undo_reduce_channel_balance(storage, channel, &reduced.denom, reduced.amount)?;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
 // this is like increase, but it only "un-subtracts" (= adds) outstanding, not total_sent
 // calling `reduce_channel_balance` and then `undo_reduce_channel_balance` should leave state unchanged.
 pub fn undo_reduce_channel_balance(
@@ -115,3 +704,110 @@ pub fn undo_reduce_channel_balance(
     })?;
     Ok(())
 }
+
+// add the amount released by a received packet to the rate limit quota, error if the max is exceeded
+pub fn consume_outflow(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    consume_flow(storage, now, channel, denom, amount, true)
+}
+
+// add the amount sent to the remote chain to the rate limit quota, error if the max is exceeded
+pub fn consume_inflow(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    consume_flow(storage, now, channel, denom, amount, false)
+}
+
+fn consume_flow(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+    outflow: bool,
+) -> Result<(), ContractError> {
+    let mut limit = match RATE_LIMITS.may_load(storage, (channel, denom))? {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    limit.slide(now);
+
+    let (used_outflow, used_inflow) = limit.current_flow(now);
+    let (used, max) = if outflow {
+        (used_outflow + amount, limit.max_outflow)
+    } else {
+        (used_inflow + amount, limit.max_inflow)
+    };
+    if matches!(max, Some(max) if used > max) {
+        return Err(ContractError::RateLimitExceeded {
+            channel: channel.to_string(),
+            denom: denom.to_string(),
+        });
+    }
+
+    let bucket = limit.current_bucket(now);
+    if outflow {
+        bucket.outflow += amount;
+    } else {
+        bucket.inflow += amount;
+    }
+    RATE_LIMITS.save(storage, (channel, denom), &limit)?;
+    Ok(())
+}
+
+// give back the quota of a received packet that failed
+pub fn undo_outflow(
+    storage: &mut dyn Storage,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    undo_flow(storage, channel, denom, amount, true)
+}
+
+// give back the quota of a sent packet that was refunded
+pub fn undo_inflow(
+    storage: &mut dyn Storage,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<(), ContractError> {
+    undo_flow(storage, channel, denom, amount, false)
+}
+
+// the time the flow was consumed isn't kept, the quota is given back from the newest buckets
+fn undo_flow(
+    storage: &mut dyn Storage,
+    channel: &str,
+    denom: &str,
+    amount: Uint128,
+    outflow: bool,
+) -> Result<(), ContractError> {
+    if let Some(mut limit) = RATE_LIMITS.may_load(storage, (channel, denom))? {
+        let mut left = amount;
+        for bucket in limit.buckets.iter_mut().rev() {
+            let flow = if outflow {
+                &mut bucket.outflow
+            } else {
+                &mut bucket.inflow
+            };
+            let released = left.min(*flow);
+            *flow -= released;
+            left -= released;
+            if left.is_zero() {
+                break;
+            }
+        }
+        RATE_LIMITS.save(storage, (channel, denom), &limit)?;
+    }
+    Ok(())
+}
@@ -8,31 +8,48 @@ use cosmwasm_std::testing::{
     mock_dependencies, mock_env, mock_ibc_channel_connect_ack, mock_ibc_channel_open_init,
     mock_info, MockApi, MockQuerier, MockStorage,
 };
-use cosmwasm_std::{attr, Binary, DepsMut, Event, IbcEndpoint, OwnedDeps, SubMsgResponse};
+use cosmwasm_std::{
+    attr, from_slice, Binary, Coin, ContractResult, Decimal, DepsMut, Empty, Event, IbcEndpoint,
+    OwnedDeps, Querier, QuerierResult, QueryRequest, SubMsgResponse, SystemError, SystemResult,
+    Uint128,
+};
+use cw_osmo_proto::osmosis::gamm::v1beta1::{
+    QuerySpotPriceRequest, QuerySpotPriceResponse, QuerySwapExactAmountInRequest,
+    QuerySwapExactAmountInResponse,
+};
+use cw_osmo_proto::Message;
 
-use crate::msg::InitMsg;
+use crate::msg::{AllowPoolMsg, ConnectionMsg, InitMsg};
+use crate::parse::parse_coin;
 
 pub const DEFAULT_TIMEOUT: u64 = 3600; // 1 hour,
 pub const CONTRACT_PORT: &str = "ibc:wasm1234567890abcdef";
 pub const REMOTE_PORT: &str = "transfer";
 pub const CONNECTION_ID: &str = "connection-2";
+pub const ADMIN_ADDR: &str = "gov";
+pub const COUNTERPARTY_PORT: &str = "their_port";
 
 pub fn mock_channel_info(channel_id: &str) -> ChannelInfo {
     ChannelInfo {
         id: channel_id.to_string(),
         counterparty_endpoint: IbcEndpoint {
-            port_id: "their_port".to_string(),
+            port_id: COUNTERPARTY_PORT.to_string(),
             channel_id: "channel-7".to_string(),
         },
         connection_id: CONNECTION_ID.into(),
+        version: ICS20_VERSION.into(),
     }
 }
 
 // we simulate instantiate and ack here
-pub fn add_channel(mut deps: DepsMut, channel_id: &str) {
-    let open_msg = mock_ibc_channel_open_init(channel_id, ICS20_ORDERING, ICS20_VERSION);
+pub fn add_channel(deps: DepsMut, channel_id: &str) {
+    add_channel_with_version(deps, channel_id, ICS20_VERSION);
+}
+
+pub fn add_channel_with_version(mut deps: DepsMut, channel_id: &str, version: &str) {
+    let open_msg = mock_ibc_channel_open_init(channel_id, ICS20_ORDERING, version);
     ibc_channel_open(deps.branch(), mock_env(), open_msg).unwrap();
-    let connect_msg = mock_ibc_channel_connect_ack(channel_id, ICS20_ORDERING, ICS20_VERSION);
+    let connect_msg = mock_ibc_channel_connect_ack(channel_id, ICS20_ORDERING, version);
     ibc_channel_connect(deps.branch(), mock_env(), connect_msg).unwrap();
 }
 
@@ -43,6 +60,17 @@ pub fn setup(channels: &[&str]) -> OwnedDeps<MockStorage, MockApi, MockQuerier>
     let instantiate_msg = InitMsg {
         default_timeout: DEFAULT_TIMEOUT,
         lockup_id: 1,
+        admin: ADMIN_ADDR.to_string(),
+        allowlist: vec![AllowPoolMsg {
+            pool_id: 1u8.into(),
+            max_amount: None,
+            max_slippage_bps: None,
+        }],
+        cw20_allowlist: vec![],
+        connections: vec![ConnectionMsg {
+            connection_id: CONNECTION_ID.to_string(),
+            counterparty_port_id: COUNTERPARTY_PORT.to_string(),
+        }],
     };
     let info = mock_info(&String::from("anyone"), &[]);
     let res = instantiate(deps.as_mut(), mock_env(), info, instantiate_msg).unwrap();
@@ -54,6 +82,142 @@ pub fn setup(channels: &[&str]) -> OwnedDeps<MockStorage, MockApi, MockQuerier>
     deps
 }
 
+/// Answers the gamm spot price and swap estimate queries, other queries go to the mock querier
+pub struct GammQuerier {
+    base: MockQuerier,
+    pub spot_price: String,
+    pub token_out_amount: String,
+}
+
+impl Querier for GammQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        let data = match request {
+            QueryRequest::Stargate { path, .. } if path.ends_with("/SpotPrice") => {
+                QuerySpotPriceResponse {
+                    spot_price: self.spot_price.clone(),
+                }
+                .encode_to_vec()
+            }
+            QueryRequest::Stargate { path, .. } if path.ends_with("/EstimateSwapExactAmountIn") => {
+                QuerySwapExactAmountInResponse {
+                    token_out_amount: self.token_out_amount.clone(),
+                }
+                .encode_to_vec()
+            }
+            _ => return self.base.raw_query(bin_request),
+        };
+        SystemResult::Ok(ContractResult::Ok(data.into()))
+    }
+}
+
+pub fn with_gamm_querier(
+    deps: OwnedDeps<MockStorage, MockApi, MockQuerier>,
+    spot_price: &str,
+    token_out_amount: &str,
+) -> OwnedDeps<MockStorage, MockApi, GammQuerier> {
+    OwnedDeps {
+        storage: deps.storage,
+        api: deps.api,
+        querier: GammQuerier {
+            base: deps.querier,
+            spot_price: spot_price.to_string(),
+            token_out_amount: token_out_amount.to_string(),
+        },
+        custom_query_type: deps.custom_query_type,
+    }
+}
+
+/// Answers the gamm queries from the balances of pools with two assets of the same weight and no
+/// swap fee, like the balancer pools of osmosis v6.2.0
+pub struct PoolQuerier {
+    base: MockQuerier,
+    pub pools: Vec<(u64, Vec<Coin>)>,
+}
+
+impl PoolQuerier {
+    fn balance(&self, pool_id: u64, denom: &str) -> Uint128 {
+        self.pools
+            .iter()
+            .find(|(id, _)| *id == pool_id)
+            .and_then(|(_, assets)| assets.iter().find(|c| c.denom == denom))
+            .map(|c| c.amount)
+            .unwrap()
+    }
+}
+
+impl Querier for PoolQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        let data = match request {
+            QueryRequest::Stargate { path, data } if path.ends_with("/SpotPrice") => {
+                let req = QuerySpotPriceRequest::decode(data.as_slice()).unwrap();
+                // fields 2 and 3 are `tokenInDenom` and `tokenOutDenom` in v6.2.0, the price is
+                // the balance ratio: token in paid for one token out
+                let spot_price = Decimal::from_ratio(
+                    self.balance(req.pool_id, &req.base_asset_denom),
+                    self.balance(req.pool_id, &req.quote_asset_denom),
+                );
+                QuerySpotPriceResponse {
+                    spot_price: spot_price.to_string(),
+                }
+                .encode_to_vec()
+            }
+            QueryRequest::Stargate { path, data }
+                if path.ends_with("/EstimateSwapExactAmountIn") =>
+            {
+                let req = QuerySwapExactAmountInRequest::decode(data.as_slice()).unwrap();
+                let mut token = parse_coin(&req.token_in).unwrap();
+                for route in req.routes {
+                    // constant product: x * y = k
+                    let balance_in = self.balance(route.pool_id, &token.denom);
+                    let balance_out = self.balance(route.pool_id, &route.token_out_denom);
+                    let amount =
+                        balance_out.multiply_ratio(token.amount, balance_in + token.amount);
+                    token = Coin::new(amount.u128(), route.token_out_denom);
+                }
+                QuerySwapExactAmountInResponse {
+                    token_out_amount: token.amount.to_string(),
+                }
+                .encode_to_vec()
+            }
+            _ => return self.base.raw_query(bin_request),
+        };
+        SystemResult::Ok(ContractResult::Ok(data.into()))
+    }
+}
+
+pub fn with_pool_querier(
+    deps: OwnedDeps<MockStorage, MockApi, MockQuerier>,
+    pools: Vec<(u64, Vec<Coin>)>,
+) -> OwnedDeps<MockStorage, MockApi, PoolQuerier> {
+    OwnedDeps {
+        storage: deps.storage,
+        api: deps.api,
+        querier: PoolQuerier {
+            base: deps.querier,
+            pools,
+        },
+        custom_query_type: deps.custom_query_type,
+    }
+}
+
 pub fn json_to_reply_proto(json: &str) -> Vec<u8> {
     let mut proto_data = vec![10u8, json.len() as u8];
     proto_data.extend_from_slice(json.as_bytes());
@@ -68,6 +232,56 @@ pub fn mock_swap_response() -> SubMsgResponse {
     }
 }
 
+pub fn mock_swap_out_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: mock_swap_events(),
+        data: Some(Binary::from_base64("CggxMDAwMDAwMA==").unwrap()),
+    }
+}
+
+pub fn mock_swap_multi_hop_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: mock_swap_multi_hop_events(),
+        data: Some(Binary::from_base64("CggzNjYwMTA3MA==").unwrap()),
+    }
+}
+
+pub fn mock_swap_out_multi_hop_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: mock_swap_multi_hop_events(),
+        data: Some(Binary::from_base64("CggxMDAwMDAwMA==").unwrap()),
+    }
+}
+
+// swap ibc/46B4.. -> ibc/BE1B.. -> uosmo, one event per pool
+pub fn mock_swap_multi_hop_events() -> Vec<Event> {
+    vec![
+        Event::new("token_swapped").add_attributes(vec![
+            attr("module", "gamm"),
+            attr("sender", "osmo1q4aw0vtcyyredprm4ncmr4jdj70kpgyr3"),
+            attr("pool_id", "1"),
+            attr(
+                "tokens_in",
+                "10000000ibc/46B44899322F3CD854D2D46DEEF881958467CDD4B3B10086DA49296BBED94BED",
+            ),
+            attr(
+                "tokens_out",
+                "338527564ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC",
+            ),
+        ]),
+        Event::new("token_swapped").add_attributes(vec![
+            attr("module", "gamm"),
+            attr("sender", "osmo1q4aw0vtcyyredprm4ncmr4jdj70kpgyr3"),
+            attr("pool_id", "2"),
+            attr(
+                "tokens_in",
+                "338527564ibc/BE1BB42D4BE3C30D50B68D7C41DB4DFCE9678E8EF8C539F6E6A9345048894FCC",
+            ),
+            attr("tokens_out", "36601070uosmo"),
+        ]),
+    ]
+}
+
 pub fn mock_swap_events() -> Vec<Event> {
     return vec![
         Event::new("token_swapped").add_attributes(vec![
@@ -133,6 +347,13 @@ pub fn mock_join_pool_events() -> Vec<Event> {
     ];
 }
 
+pub fn mock_join_pool_exact_out_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: mock_join_pool_events(),
+        data: Some(Binary::from_base64("Cgc0OTg1MDEx").unwrap()),
+    }
+}
+
 pub fn mock_exit_pool_response() -> SubMsgResponse {
     SubMsgResponse {
         events: mock_exit_pool_events(),
@@ -140,6 +361,25 @@ pub fn mock_exit_pool_response() -> SubMsgResponse {
     }
 }
 
+// the pool is exited into the token out, there is no swap event
+pub fn mock_exit_pool_exact_out_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: vec![
+            Event::new("pool_exited").add_attributes(vec![
+                attr("module", "gamm"),
+                attr("sender", "osmo1q4aw0vtcyyredprm4ncmr4jdj70kpgyr3"),
+                attr("pool_id", "1"),
+                attr("tokens_out", "4985011uosmo"),
+            ]),
+            Event::new("burn").add_attributes(vec![
+                attr("burner", "osmo1c9y7crgg6y9pfkq0y8mqzknqz84c3etr0kpcvj"),
+                attr("amount", "74196993097318119147gamm/pool/1"),
+            ]),
+        ],
+        data: Some(Binary::from_base64("ChQ3NDE5Njk5MzA5NzMxODExOTE0Nw==").unwrap()),
+    }
+}
+
 pub fn mock_exit_pool_events() -> Vec<Event> {
     return vec![
         Event::new("pool_exited").add_attributes(vec![
@@ -161,3 +401,39 @@ pub fn mock_exit_pool_events() -> Vec<Event> {
         ]),
     ];
 }
+
+pub fn mock_multi_join_pool_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: vec![
+            Event::new("pool_joined").add_attributes(vec![
+                attr("module", "gamm"),
+                attr("sender", "osmo1q4aw0vtcyyredprm4ncmr4jdj70kpgyr3"),
+                attr("pool_id", "1"),
+                attr("tokens_in", "100008ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2,4985011uosmo"),
+            ]),
+            Event::new("coinbase").add_attributes(vec![
+                attr("minter", "osmo1c9y7crgg6y9pfkq0y8mqzknqz84c3etr0kpcvj"),
+                attr("amount", "74196993097318119147gamm/pool/1"),
+            ]),
+        ],
+        data: None,
+    }
+}
+
+pub fn mock_multi_exit_pool_response() -> SubMsgResponse {
+    SubMsgResponse {
+        events: vec![
+            Event::new("pool_exited").add_attributes(vec![
+                attr("module", "gamm"),
+                attr("sender", "osmo1q4aw0vtcyyredprm4ncmr4jdj70kpgyr3"),
+                attr("pool_id", "1"),
+                attr("tokens_out", "100008ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2,4985011uosmo"),
+            ]),
+            Event::new("burn").add_attributes(vec![
+                attr("burner", "osmo1c9y7crgg6y9pfkq0y8mqzknqz84c3etr0kpcvj"),
+                attr("amount", "74196993097318119147gamm/pool/1"),
+            ]),
+        ],
+        data: None,
+    }
+}
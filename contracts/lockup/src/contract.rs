@@ -1,28 +1,37 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, BankMsg, Binary, Coin, CosmosMsg, Deps, DepsMut, Env, MessageInfo, Reply, Response,
-    StdError, StdResult, SubMsg, SubMsgResult, Uint64,
+    to_binary, BankMsg, Binary, Coin, CosmosMsg, Deps, DepsMut, Env, Event, MessageInfo, Reply,
+    Response, StdError, StdResult, SubMsg, SubMsgResult, Uint64,
 };
 use cw2::set_contract_version;
 use cw_osmo_proto::osmosis::lockup;
 use cw_osmo_proto::proto_ext::{proto_decode, MessageExt};
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, LockResult, QueryMsg};
+use crate::msg::{
+    ExecuteMsg, ExtendLockResult, InstantiateMsg, LockResult, QueryMsg, UnlockResult, UnlockingLock,
+};
 use crate::state::ADMIN;
 
 use cw_utils::{nonpayable, one_coin};
+use std::convert::TryFrom;
 
 const CONTRACT_NAME: &str = "crates.io:cw-osmo-lockup";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const LOCK_TOKEN_ID: u64 = 0x43ab;
+const UNLOCK_TOKEN_ID: u64 = 0x43ac;
+
+const BEGIN_UNLOCK_EVENT: &str = "begin_unlock";
+const LOCK_ID_ATTR: &str = "period_lock_id";
+const UNLOCK_TIME_ATTR: &str = "unlock_time";
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn reply(_deps: DepsMut, _env: Env, reply: Reply) -> Result<Response, ContractError> {
     match reply.id {
         LOCK_TOKEN_ID => reply_lock(reply),
+        UNLOCK_TOKEN_ID => reply_unlock(reply),
         _ => Err(ContractError::UnknownReplyId { id: reply.id }),
     }
 }
@@ -43,6 +52,43 @@ pub fn reply_lock(reply: Reply) -> Result<Response, ContractError> {
     }
 }
 
+// report the locks that started unlocking, from the begin_unlock events
+pub fn reply_unlock(reply: Reply) -> Result<Response, ContractError> {
+    match reply.result {
+        SubMsgResult::Ok(tx) => {
+            let unlocks = tx
+                .events
+                .iter()
+                .filter(|event| event.ty == BEGIN_UNLOCK_EVENT)
+                .map(parse_unlocking_lock)
+                .collect::<Result<Vec<_>, _>>()?;
+            let result = UnlockResult { unlocks };
+
+            Ok(Response::new().set_data(to_binary(&result)?))
+        }
+        SubMsgResult::Err(err) => Err(StdError::generic_err(err).into()),
+    }
+}
+
+fn parse_unlocking_lock(event: &Event) -> Result<UnlockingLock, ContractError> {
+    let attr_value = |key: &str| {
+        event
+            .attributes
+            .iter()
+            .find(|attr| attr.key == key)
+            .map(|attr| attr.value.clone())
+    };
+    let lock_id = attr_value(LOCK_ID_ATTR)
+        .and_then(|id| id.parse::<u64>().ok())
+        .ok_or(ContractError::NoFoundLockId {})?;
+    let end_time = attr_value(UNLOCK_TIME_ATTR).ok_or(ContractError::NoFoundLockEndTime {})?;
+
+    Ok(UnlockingLock {
+        lock_id: lock_id.into(),
+        end_time,
+    })
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     mut deps: DepsMut,
@@ -71,7 +117,11 @@ pub fn execute(
             let coin = one_coin(&info)?;
             execute_lock(deps, info, duration, coin, contract)
         }
-        ExecuteMsg::Unlock { id } => execute_unlock(deps, info, contract, id),
+        ExecuteMsg::Unlock { id, coins } => execute_unlock(deps, info, contract, id, coins),
+        ExecuteMsg::UnlockAll {} => execute_unlock_all(deps, info, contract),
+        ExecuteMsg::ExtendLock { id, duration } => {
+            execute_extend_lock(deps, info, contract, id, duration)
+        }
         ExecuteMsg::Claim { denom } => execute_claim(deps, info, contract, denom),
         ExecuteMsg::UpdateAdmin { admin } => {
             let admin = deps.api.addr_validate(&admin)?;
@@ -80,6 +130,12 @@ pub fn execute(
     }
 }
 
+// the proto duration is signed, a larger one would wrap to a negative duration
+fn lock_duration(duration: Uint64) -> Result<cw_osmo_proto::Duration, ContractError> {
+    let seconds = i64::try_from(duration.u64()).map_err(|_| ContractError::InvalidDuration {})?;
+    Ok(cw_osmo_proto::Duration { seconds, nanos: 0 })
+}
+
 pub fn execute_lock(
     deps: DepsMut,
     info: MessageInfo,
@@ -91,10 +147,7 @@ pub fn execute_lock(
 
     let tx = lockup::MsgLockTokens {
         owner: contract,
-        duration: Some(cw_osmo_proto::Duration {
-            seconds: duration.u64() as i64,
-            nanos: 0,
-        }),
+        duration: Some(lock_duration(duration)?),
         coins: vec![cw_osmo_proto::cosmos::base::v1beta1::Coin {
             denom: token_in.denom,
             amount: token_in.amount.to_string(),
@@ -113,6 +166,7 @@ pub fn execute_unlock(
     info: MessageInfo,
     contract: String,
     lock_id: Uint64,
+    coins: Vec<Coin>,
 ) -> Result<Response, ContractError> {
     nonpayable(&info)?;
 
@@ -121,18 +175,72 @@ pub fn execute_unlock(
     }
     ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
 
+    // the full lock is unlocked if no coins are set
     let tx = lockup::MsgBeginUnlocking {
         owner: contract,
         id: lock_id.u64(),
-        coins: vec![],
+        coins: coins
+            .into_iter()
+            .map(|coin| cw_osmo_proto::cosmos::base::v1beta1::Coin {
+                denom: coin.denom,
+                amount: coin.amount.to_string(),
+            })
+            .collect(),
     };
+    let submsg = SubMsg::reply_on_success(tx.to_msg()?, UNLOCK_TOKEN_ID);
 
     Ok(Response::new()
-        .add_message(tx.to_msg()?)
+        .add_submessage(submsg)
         .add_attribute("action", "unlock")
         .add_attribute("lock_id", lock_id.to_string()))
 }
 
+pub fn execute_unlock_all(
+    deps: DepsMut,
+    info: MessageInfo,
+    contract: String,
+) -> Result<Response, ContractError> {
+    nonpayable(&info)?;
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let tx = lockup::MsgBeginUnlockingAll { owner: contract };
+    let submsg = SubMsg::reply_on_success(tx.to_msg()?, UNLOCK_TOKEN_ID);
+
+    Ok(Response::new()
+        .add_submessage(submsg)
+        .add_attribute("action", "unlock_all"))
+}
+
+pub fn execute_extend_lock(
+    deps: DepsMut,
+    info: MessageInfo,
+    contract: String,
+    lock_id: Uint64,
+    duration: Uint64,
+) -> Result<Response, ContractError> {
+    nonpayable(&info)?;
+
+    if lock_id.is_zero() {
+        return Err(ContractError::InvalidLockId {});
+    }
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let tx = lockup::MsgExtendLockup {
+        owner: contract,
+        id: lock_id.u64(),
+        duration: Some(lock_duration(duration)?),
+    };
+    // osmosis rejects a shorter duration, the whole execution fails with it
+    let result = ExtendLockResult { lock_id, duration };
+
+    Ok(Response::new()
+        .set_data(to_binary(&result)?)
+        .add_message(tx.to_msg()?)
+        .add_attribute("action", "extend_lock")
+        .add_attribute("lock_id", lock_id.to_string())
+        .add_attribute("duration", duration.to_string()))
+}
+
 pub fn execute_claim(
     deps: DepsMut,
     info: MessageInfo,
@@ -221,6 +329,14 @@ mod test {
         let err = execute(deps.as_mut(), mock_env(), sender, msg.clone()).unwrap_err();
         assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
 
+        // lock token: the duration doesn't fit the proto duration
+        let invalid = ExecuteMsg::Lock {
+            duration: u64::MAX.into(),
+        };
+        let sender = mock_info("owner", &coins(1000u128, denom));
+        let err = execute(deps.as_mut(), mock_env(), sender, invalid).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDuration {});
+
         // lock token: Valid owner
         let sender = mock_info("owner", &coins(1000u128, denom));
         let res = execute(deps.as_mut(), mock_env(), sender, msg).unwrap();
@@ -287,7 +403,10 @@ mod test {
     fn execute_unlock() {
         let mut deps = setup_init();
 
-        let msg = ExecuteMsg::Unlock { id: 1u64.into() };
+        let msg = ExecuteMsg::Unlock {
+            id: 1u64.into(),
+            coins: vec![],
+        };
 
         // unlock token: Invalid owner
         let sender = mock_info("any", &[]);
@@ -303,6 +422,132 @@ mod test {
         let sender = mock_info("owner", &[]);
         let res = execute(deps.as_mut(), mock_env(), sender, msg).unwrap();
         assert_eq!(1, res.messages.len());
+        assert_eq!(UNLOCK_TOKEN_ID, res.messages[0].id);
+
+        // partial unlock
+        let msg = ExecuteMsg::Unlock {
+            id: 1u64.into(),
+            coins: coins(500u128, "gamm/pool/1"),
+        };
+        let sender = mock_info("owner", &[]);
+        let res = execute(deps.as_mut(), mock_env(), sender, msg).unwrap();
+        assert_eq!(1, res.messages.len());
+
+        // Simulate reply result
+        let reply_msg = Reply {
+            id: UNLOCK_TOKEN_ID,
+            result: SubMsgResult::Ok(SubMsgResponse {
+                events: mock_lock_events(),
+                data: None,
+            }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let unlock_res: UnlockResult = from_binary(&res.data.unwrap()).unwrap();
+        let expected = vec![UnlockingLock {
+            lock_id: 16u64.into(),
+            end_time: "0001-01-01 00:00:00 +0000 UTC".to_string(),
+        }];
+        assert_eq!(expected, unlock_res.unlocks);
+
+        // the end time is required
+        let events = vec![Event::new("begin_unlock").add_attribute("period_lock_id", "16")];
+        let reply_msg = Reply {
+            id: UNLOCK_TOKEN_ID,
+            result: SubMsgResult::Ok(SubMsgResponse { events, data: None }),
+        };
+        let err = reply(deps.as_mut(), mock_env(), reply_msg).unwrap_err();
+        assert_eq!(err, ContractError::NoFoundLockEndTime {});
+    }
+
+    #[test]
+    fn execute_unlock_all() {
+        let mut deps = setup_init();
+
+        let msg = ExecuteMsg::UnlockAll {};
+
+        // unlock all: Invalid owner
+        let sender = mock_info("any", &[]);
+        let err = execute(deps.as_mut(), mock_env(), sender, msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+
+        // unlock all: Valid owner
+        let sender = mock_info("owner", &[]);
+        let res = execute(deps.as_mut(), mock_env(), sender, msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Stargate { type_url, .. } if type_url == "/osmosis.lockup.MsgBeginUnlockingAll"
+        ));
+
+        // Simulate reply with two unlocks
+        let mut events = mock_lock_events();
+        events.push(Event::new("begin_unlock").add_attributes(vec![
+            attr("period_lock_id", "17"),
+            attr("unlock_time", "2022-10-23 13:11:09 +0000 UTC"),
+        ]));
+        let reply_msg = Reply {
+            id: UNLOCK_TOKEN_ID,
+            result: SubMsgResult::Ok(SubMsgResponse { events, data: None }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        let unlock_res: UnlockResult = from_binary(&res.data.unwrap()).unwrap();
+        let ids: Vec<_> = unlock_res.unlocks.iter().map(|u| u.lock_id.u64()).collect();
+        assert_eq!(vec![16, 17], ids);
+        assert_eq!(
+            "2022-10-23 13:11:09 +0000 UTC",
+            unlock_res.unlocks[1].end_time
+        );
+    }
+
+    #[test]
+    fn execute_extend_lock() {
+        let mut deps = setup_init();
+
+        let msg = ExecuteMsg::ExtendLock {
+            id: 1u64.into(),
+            duration: 1209600u64.into(),
+        };
+
+        // extend lock: Invalid owner
+        let sender = mock_info("any", &[]);
+        let err = execute(deps.as_mut(), mock_env(), sender, msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Admin(AdminError::NotAdmin {}));
+
+        // extend lock: error funds
+        let sender = mock_info("owner", &coins(1u128, "uatom"));
+        let err = execute(deps.as_mut(), mock_env(), sender, msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Payment(NonPayable {}));
+
+        // extend lock: invalid lock id
+        let invalid = ExecuteMsg::ExtendLock {
+            id: 0u64.into(),
+            duration: 1209600u64.into(),
+        };
+        let sender = mock_info("owner", &[]);
+        let err = execute(deps.as_mut(), mock_env(), sender, invalid).unwrap_err();
+        assert_eq!(err, ContractError::InvalidLockId {});
+
+        // extend lock: the duration doesn't fit the proto duration
+        let invalid = ExecuteMsg::ExtendLock {
+            id: 1u64.into(),
+            duration: (i64::MAX as u64 + 1).into(),
+        };
+        let sender = mock_info("owner", &[]);
+        let err = execute(deps.as_mut(), mock_env(), sender, invalid).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDuration {});
+
+        // extend lock: Valid owner
+        let sender = mock_info("owner", &[]);
+        let res = execute(deps.as_mut(), mock_env(), sender, msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert!(matches!(
+            &res.messages[0].msg,
+            CosmosMsg::Stargate { type_url, .. } if type_url == "/osmosis.lockup.MsgExtendLockup"
+        ));
+
+        let result: ExtendLockResult = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(Uint64::new(1), result.lock_id);
+        assert_eq!(Uint64::new(1209600), result.duration);
     }
 
     #[test]
@@ -37,4 +37,7 @@ pub enum ContractError {
 
     #[error("Cannot get lock end time")]
     NoFoundLockEndTime {},
+
+    #[error("Invalid lock duration")]
+    InvalidDuration {},
 }
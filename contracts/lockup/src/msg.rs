@@ -1,4 +1,4 @@
-use cosmwasm_std::Uint64;
+use cosmwasm_std::{Coin, Uint64};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -13,8 +13,16 @@ pub struct InstantiateMsg {
 pub enum ExecuteMsg {
     /// Lock exactly one LP token (duration in seconds)
     Lock { duration: Uint64 },
-    /// Begin unlocking tokens
-    Unlock { id: Uint64 },
+    /// Begin unlocking tokens, only the given coins if not empty
+    Unlock {
+        id: Uint64,
+        #[serde(default)]
+        coins: Vec<Coin>,
+    },
+    /// Begin unlocking all the locks
+    UnlockAll {},
+    /// Extend the duration of a lock (in seconds), it must be longer than the current one
+    ExtendLock { id: Uint64, duration: Uint64 },
     /// Claim contract balance (only admin)
     Claim { denom: String },
     /// Change the admin (must be called by current admin)
@@ -32,3 +40,23 @@ pub enum QueryMsg {
 pub struct LockResult {
     pub lock_id: Uint64,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExtendLockResult {
+    pub lock_id: Uint64,
+    /// New lock duration in seconds
+    pub duration: Uint64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UnlockResult {
+    /// Locks that started unlocking
+    pub unlocks: Vec<UnlockingLock>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UnlockingLock {
+    pub lock_id: Uint64,
+    /// Unlock time, as reported by osmosis
+    pub end_time: String,
+}
@@ -35,3 +35,22 @@ pub mod osmosis {
         include!("paths/osmosis.lockup.rs");
     }
 }
+
+pub mod ibc {
+    pub mod applications {
+        pub mod transfer {
+            pub mod v1 {
+                include!("types/ibc.applications.transfer.v1.rs");
+                include!("paths/ibc.applications.transfer.v1.rs");
+            }
+        }
+    }
+
+    pub mod core {
+        pub mod client {
+            pub mod v1 {
+                include!("types/ibc.core.client.v1.rs");
+            }
+        }
+    }
+}
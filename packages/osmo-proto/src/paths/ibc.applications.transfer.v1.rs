@@ -0,0 +1,7 @@
+use crate::proto_ext::ProtoUrl;
+
+impl ProtoUrl for MsgTransfer {
+    fn path(&self) -> &str {
+        "/ibc.applications.transfer.v1.MsgTransfer"
+    }
+}
@@ -12,20 +12,50 @@ impl ProtoUrl for QuerySwapExactAmountInRequest {
     }
 }
 
+impl ProtoUrl for MsgJoinPool {
+    fn path(&self) -> &str {
+        "/osmosis.gamm.v1beta1.MsgJoinPool"
+    }
+}
+
+impl ProtoUrl for MsgExitPool {
+    fn path(&self) -> &str {
+        "/osmosis.gamm.v1beta1.MsgExitPool"
+    }
+}
+
 impl ProtoUrl for MsgSwapExactAmountIn {
     fn path(&self) -> &str {
         "/osmosis.gamm.v1beta1.MsgSwapExactAmountIn"
     }
 }
 
+impl ProtoUrl for MsgSwapExactAmountOut {
+    fn path(&self) -> &str {
+        "/osmosis.gamm.v1beta1.MsgSwapExactAmountOut"
+    }
+}
+
 impl ProtoUrl for MsgJoinSwapExternAmountIn {
     fn path(&self) -> &str {
         "/osmosis.gamm.v1beta1.MsgJoinSwapExternAmountIn"
     }
 }
 
+impl ProtoUrl for MsgJoinSwapShareAmountOut {
+    fn path(&self) -> &str {
+        "/osmosis.gamm.v1beta1.MsgJoinSwapShareAmountOut"
+    }
+}
+
 impl ProtoUrl for MsgExitSwapShareAmountIn {
     fn path(&self) -> &str {
         "/osmosis.gamm.v1beta1.MsgExitSwapShareAmountIn"
     }
 }
+
+impl ProtoUrl for MsgExitSwapExternAmountOut {
+    fn path(&self) -> &str {
+        "/osmosis.gamm.v1beta1.MsgExitSwapExternAmountOut"
+    }
+}
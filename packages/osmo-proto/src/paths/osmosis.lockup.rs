@@ -17,3 +17,15 @@ impl ProtoUrl for MsgBeginUnlocking {
         "/osmosis.lockup.MsgBeginUnlocking"
     }
 }
+
+impl ProtoUrl for MsgExtendLockup {
+    fn path(&self) -> &str {
+        "/osmosis.lockup.MsgExtendLockup"
+    }
+}
+
+impl ProtoUrl for MsgBeginUnlockingAll {
+    fn path(&self) -> &str {
+        "/osmosis.lockup.MsgBeginUnlockingAll"
+    }
+}
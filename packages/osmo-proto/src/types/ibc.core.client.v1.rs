@@ -0,0 +1,12 @@
+/// Height is a monotonically increasing data type
+/// that can be compared against another Height for the purposes of updating and
+/// freezing clients
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Height {
+    /// the revision that the client is currently on
+    #[prost(uint64, tag = "1")]
+    pub revision_number: u64,
+    /// the height within the given revision
+    #[prost(uint64, tag = "2")]
+    pub revision_height: u64,
+}